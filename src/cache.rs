@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::path::PathBuf;
+
+use pyramid::pon::*;
+
+use template::*;
+
+const MAGIC: &'static [u8] = b"TPMLCACHE";
+/// Bump whenever the layout below changes, so stale caches are rejected instead of misread.
+const VERSION: u32 = 20;
+
+fn io_err<E: ::std::fmt::Display>(err: E) -> TemplateError {
+    TemplateError::Io(format!("{}", err))
+}
+
+/// Running out of data while reading means the cache is truncated, which is a bad cache
+/// rather than a failed read.
+fn read_err(err: io::Error) -> TemplateError {
+    match err.kind() {
+        ErrorKind::UnexpectedEof => TemplateError::Cache("Truncated cache".to_string()),
+        _ => io_err(err)
+    }
+}
+
+/// Writes the source files the templates were loaded from, each with the types it defines,
+/// followed by the templates.
+pub fn write_cache<W: Write>(w: &mut W, sources: &Vec<(PathBuf, Vec<String>)>, templates: &HashMap<String, Template>) -> Result<(), TemplateError> {
+    try!(w.write_all(MAGIC).map_err(io_err));
+    try!(write_u32(w, VERSION));
+    try!(write_u32(w, sources.len() as u32));
+    for &(ref source, ref type_names) in sources {
+        try!(write_str(w, &source.to_string_lossy()));
+        try!(write_u32(w, type_names.len() as u32));
+        for type_name in type_names {
+            try!(write_str(w, type_name));
+        }
+    }
+    let mut type_names: Vec<&String> = templates.keys().collect();
+    type_names.sort();
+    try!(write_u32(w, type_names.len() as u32));
+    for type_name in type_names {
+        try!(write_template(w, &templates[type_name]));
+    }
+    Ok(())
+}
+
+pub fn read_cache<R: Read>(r: &mut R) -> Result<(Vec<(PathBuf, Vec<String>)>, HashMap<String, Template>), TemplateError> {
+    let mut magic = vec![0; MAGIC.len()];
+    try!(r.read_exact(&mut magic).map_err(read_err));
+    if magic.as_slice() != MAGIC {
+        return Err(TemplateError::Cache("Not a template cache".to_string()));
+    }
+    let version = try!(read_u32(r));
+    if version != VERSION {
+        return Err(TemplateError::Cache(format!("Unsupported cache version {}", version)));
+    }
+    let mut sources = vec![];
+    for _ in 0..try!(read_u32(r)) {
+        let source = PathBuf::from(try!(read_str(r)));
+        let mut type_names = vec![];
+        for _ in 0..try!(read_u32(r)) {
+            type_names.push(try!(read_str(r)));
+        }
+        sources.push((source, type_names));
+    }
+    let mut templates = HashMap::new();
+    for _ in 0..try!(read_u32(r)) {
+        let template = try!(read_template(r));
+        templates.insert(template.type_name.clone(), template);
+    }
+    Ok((sources, templates))
+}
+
+fn write_template<W: Write>(w: &mut W, template: &Template) -> Result<(), TemplateError> {
+    try!(write_str(w, &template.type_name));
+    try!(write_u8(w, match template.kind {
+        TemplateKind::Entity => 0,
+        TemplateKind::Fragment => 1
+    }));
+    try!(write_opt_str(w, &template.name));
+    try!(write_opt_str(w, &template.inherits));
+    try!(write_opt_str(w, &template.extends_file));
+    try!(write_opt_str(w, &template.when_flag));
+    try!(write_u8(w, match template.inherit_mode {
+        InheritMode::All => 0,
+        InheritMode::Properties => 1,
+        InheritMode::Children => 2
+    }));
+    try!(write_u32(w, template.aliases.len() as u32));
+    for alias in &template.aliases {
+        try!(write_str(w, alias));
+    }
+    try!(write_u32(w, template.mixins.len() as u32));
+    for mixin in &template.mixins {
+        try!(write_str(w, mixin));
+    }
+    try!(write_u8(w, match template.mixin_order {
+        MixinOrder::First => 0,
+        MixinOrder::Last => 1
+    }));
+    try!(write_u32(w, template.tags.len() as u32));
+    for tag in &template.tags {
+        try!(write_str(w, tag));
+    }
+    try!(write_opt_str(w, &template.inherits_tag));
+    match template.version {
+        Some(version) => { try!(write_u8(w, 1)); try!(write_u32(w, version)); }
+        None => try!(write_u8(w, 0))
+    }
+    match template.selector {
+        Some(ref selector) => {
+            try!(write_u8(w, 1));
+            try!(write_str(w, &selector.key));
+            try!(write_pon(w, &selector.value));
+        }
+        None => try!(write_u8(w, 0))
+    }
+    try!(write_u32(w, template.match_has.len() as u32));
+    for key in &template.match_has {
+        try!(write_str(w, key));
+    }
+    try!(write_u8(w, template.replace as u8));
+    try!(write_u8(w, template.merge as u8));
+    try!(write_u8(w, template.inline as u8));
+    try!(write_u32(w, template.required.len() as u32));
+    for key in &template.required {
+        try!(write_str(w, key));
+    }
+    try!(write_u32(w, template.abstract_properties.len() as u32));
+    for key in &template.abstract_properties {
+        try!(write_str(w, key));
+    }
+    match template.repeat {
+        Some(Repeat::Count(count)) => { try!(write_u8(w, 1)); try!(write_i64(w, count)); }
+        Some(Repeat::Property(ref key)) => { try!(write_u8(w, 2)); try!(write_str(w, key)); }
+        None => try!(write_u8(w, 0))
+    }
+    try!(write_u32(w, template.property_aliases.len() as u32));
+    for &(ref property, ref alias) in &template.property_aliases {
+        try!(write_str(w, property));
+        try!(write_str(w, alias));
+    }
+    try!(write_u32(w, template.depth_conditions.len() as u32));
+    for &(ref property, ref condition) in &template.depth_conditions {
+        try!(write_str(w, property));
+        try!(write_str(w, condition));
+    }
+    try!(write_u32(w, template.lazy.len() as u32));
+    for key in &template.lazy {
+        try!(write_str(w, key));
+    }
+    try!(write_u32(w, template.properties.len() as u32));
+    for &(ref key, ref value) in &template.properties {
+        try!(write_str(w, key));
+        try!(write_pon(w, value));
+    }
+    try!(write_pon(w, &Pon::Object(template.metadata.clone())));
+    try!(write_u32(w, template.references.len() as u32));
+    for &(ref key, ref reference) in &template.references {
+        try!(write_str(w, key));
+        match reference {
+            &Reference::Name => try!(write_u8(w, 0)),
+            &Reference::Entity(ref entity, ref property) => {
+                try!(write_u8(w, 1));
+                try!(write_str(w, entity));
+                try!(write_str(w, property));
+            }
+            &Reference::Parent(ref property) => {
+                try!(write_u8(w, 2));
+                try!(write_str(w, property));
+            }
+        }
+    }
+    try!(write_u32(w, template.children.len() as u32));
+    for child in &template.children {
+        try!(write_template(w, child));
+    }
+    match template.parent {
+        Some(ref parent) => { try!(write_u8(w, 1)); try!(write_template(w, parent)); }
+        None => try!(write_u8(w, 0))
+    }
+    try!(write_u32(w, template.switches.len() as u32));
+    for switch in &template.switches {
+        try!(write_str(w, &switch.on));
+        try!(write_u32(w, switch.cases.len() as u32));
+        for case in &switch.cases {
+            try!(write_opt_str(w, &case.value));
+            try!(write_u32(w, case.children.len() as u32));
+            for child in &case.children {
+                try!(write_template(w, child));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_template<R: Read>(r: &mut R) -> Result<Template, TemplateError> {
+    let mut template = Template::new(try!(read_str(r)));
+    template.kind = match try!(read_u8(r)) {
+        0 => TemplateKind::Entity,
+        _ => TemplateKind::Fragment
+    };
+    template.name = try!(read_opt_str(r));
+    template.inherits = try!(read_opt_str(r));
+    template.extends_file = try!(read_opt_str(r));
+    template.when_flag = try!(read_opt_str(r));
+    template.inherit_mode = match try!(read_u8(r)) {
+        0 => InheritMode::All,
+        1 => InheritMode::Properties,
+        _ => InheritMode::Children
+    };
+    for _ in 0..try!(read_u32(r)) {
+        template.aliases.push(try!(read_str(r)));
+    }
+    for _ in 0..try!(read_u32(r)) {
+        template.mixins.push(try!(read_str(r)));
+    }
+    template.mixin_order = match try!(read_u8(r)) {
+        0 => MixinOrder::First,
+        _ => MixinOrder::Last
+    };
+    for _ in 0..try!(read_u32(r)) {
+        template.tags.push(try!(read_str(r)));
+    }
+    template.inherits_tag = try!(read_opt_str(r));
+    template.version = match try!(read_u8(r)) {
+        0 => None,
+        _ => Some(try!(read_u32(r)))
+    };
+    template.selector = match try!(read_u8(r)) {
+        0 => None,
+        _ => {
+            let key = try!(read_str(r));
+            Some(Selector { key: key, value: try!(read_pon(r)) })
+        }
+    };
+    for _ in 0..try!(read_u32(r)) {
+        template.match_has.push(try!(read_str(r)));
+    }
+    template.replace = try!(read_u8(r)) != 0;
+    template.merge = try!(read_u8(r)) != 0;
+    template.inline = try!(read_u8(r)) != 0;
+    for _ in 0..try!(read_u32(r)) {
+        template.required.push(try!(read_str(r)));
+    }
+    for _ in 0..try!(read_u32(r)) {
+        template.abstract_properties.push(try!(read_str(r)));
+    }
+    template.repeat = match try!(read_u8(r)) {
+        0 => None,
+        1 => Some(Repeat::Count(try!(read_i64(r)))),
+        _ => Some(Repeat::Property(try!(read_str(r))))
+    };
+    for _ in 0..try!(read_u32(r)) {
+        let property = try!(read_str(r));
+        template.property_aliases.push((property, try!(read_str(r))));
+    }
+    for _ in 0..try!(read_u32(r)) {
+        let property = try!(read_str(r));
+        template.depth_conditions.push((property, try!(read_str(r))));
+    }
+    for _ in 0..try!(read_u32(r)) {
+        template.lazy.push(try!(read_str(r)));
+    }
+    for _ in 0..try!(read_u32(r)) {
+        let key = try!(read_str(r));
+        template.properties.push((key, try!(read_pon(r))));
+    }
+    template.metadata = match try!(read_pon(r)) {
+        Pon::Object(metadata) => metadata,
+        _ => return Err(TemplateError::Cache("Metadata isn't an object".to_string()))
+    };
+    for _ in 0..try!(read_u32(r)) {
+        let key = try!(read_str(r));
+        let reference = match try!(read_u8(r)) {
+            0 => Reference::Name,
+            1 => {
+                let entity = try!(read_str(r));
+                Reference::Entity(entity, try!(read_str(r)))
+            }
+            2 => Reference::Parent(try!(read_str(r))),
+            tag => return Err(TemplateError::Cache(format!("Unknown reference tag {}", tag)))
+        };
+        template.references.push((key, reference));
+    }
+    for _ in 0..try!(read_u32(r)) {
+        template.children.push(try!(read_template(r)));
+    }
+    template.parent = match try!(read_u8(r)) {
+        0 => None,
+        _ => Some(Box::new(try!(read_template(r))))
+    };
+    for _ in 0..try!(read_u32(r)) {
+        let mut switch = Switch { on: try!(read_str(r)), cases: vec![] };
+        for _ in 0..try!(read_u32(r)) {
+            let mut case = Case { value: try!(read_opt_str(r)), children: vec![] };
+            for _ in 0..try!(read_u32(r)) {
+                case.children.push(try!(read_template(r)));
+            }
+            switch.cases.push(case);
+        }
+        template.switches.push(switch);
+    }
+    Ok(template)
+}
+
+/// Only plain data survives the cache; references and other runtime values are rejected.
+fn write_pon<W: Write>(w: &mut W, pon: &Pon) -> Result<(), TemplateError> {
+    match pon {
+        &Pon::Nil => write_u8(w, 0),
+        &Pon::Boolean(value) => { try!(write_u8(w, 1)); write_u8(w, value as u8) }
+        &Pon::Integer(value) => { try!(write_u8(w, 2)); write_i64(w, value) }
+        &Pon::Float(value) => { try!(write_u8(w, 3)); write_u32(w, unsafe { mem::transmute::<f32, u32>(value) }) }
+        &Pon::String(ref value) => { try!(write_u8(w, 4)); write_str(w, value) }
+        &Pon::Array(ref values) => {
+            try!(write_u8(w, 5));
+            try!(write_u32(w, values.len() as u32));
+            for value in values {
+                try!(write_pon(w, value));
+            }
+            Ok(())
+        }
+        &Pon::Object(ref map) => {
+            try!(write_u8(w, 6));
+            try!(write_u32(w, map.len() as u32));
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            for key in keys {
+                try!(write_str(w, key));
+                try!(write_pon(w, &map[key]));
+            }
+            Ok(())
+        }
+        &Pon::TypedPon(ref typed) => {
+            try!(write_u8(w, 7));
+            try!(write_str(w, &typed.type_name));
+            write_pon(w, &typed.data)
+        }
+        _ => Err(TemplateError::Cache(format!("Can't cache value {:?}", pon)))
+    }
+}
+
+fn read_pon<R: Read>(r: &mut R) -> Result<Pon, TemplateError> {
+    Ok(match try!(read_u8(r)) {
+        0 => Pon::Nil,
+        1 => Pon::Boolean(try!(read_u8(r)) != 0),
+        2 => Pon::Integer(try!(read_i64(r))),
+        3 => Pon::Float(unsafe { mem::transmute::<u32, f32>(try!(read_u32(r))) }),
+        4 => Pon::String(try!(read_str(r))),
+        5 => {
+            let mut values = vec![];
+            for _ in 0..try!(read_u32(r)) {
+                values.push(try!(read_pon(r)));
+            }
+            Pon::Array(values)
+        }
+        6 => {
+            let mut map = HashMap::new();
+            for _ in 0..try!(read_u32(r)) {
+                let key = try!(read_str(r));
+                map.insert(key, try!(read_pon(r)));
+            }
+            Pon::Object(map)
+        }
+        7 => {
+            let type_name = try!(read_str(r));
+            Pon::TypedPon(Box::new(TypedPon { type_name: type_name, data: try!(read_pon(r)) }))
+        }
+        tag => return Err(TemplateError::Cache(format!("Unknown value tag {}", tag)))
+    })
+}
+
+fn write_u8<W: Write>(w: &mut W, value: u8) -> Result<(), TemplateError> {
+    w.write_all(&[value]).map_err(io_err)
+}
+
+fn write_u32<W: Write>(w: &mut W, value: u32) -> Result<(), TemplateError> {
+    let bytes = [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8];
+    w.write_all(&bytes).map_err(io_err)
+}
+
+fn write_i64<W: Write>(w: &mut W, value: i64) -> Result<(), TemplateError> {
+    try!(write_u32(w, value as u64 as u32));
+    write_u32(w, ((value as u64) >> 32) as u32)
+}
+
+fn write_str<W: Write>(w: &mut W, value: &str) -> Result<(), TemplateError> {
+    try!(write_u32(w, value.len() as u32));
+    w.write_all(value.as_bytes()).map_err(io_err)
+}
+
+fn write_opt_str<W: Write>(w: &mut W, value: &Option<String>) -> Result<(), TemplateError> {
+    match value {
+        &Some(ref value) => { try!(write_u8(w, 1)); write_str(w, value) }
+        &None => write_u8(w, 0)
+    }
+}
+
+fn read_u8<R: Read>(r: &mut R) -> Result<u8, TemplateError> {
+    let mut bytes = [0; 1];
+    try!(r.read_exact(&mut bytes).map_err(read_err));
+    Ok(bytes[0])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32, TemplateError> {
+    let mut bytes = [0; 4];
+    try!(r.read_exact(&mut bytes).map_err(read_err));
+    Ok(bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24)
+}
+
+fn read_i64<R: Read>(r: &mut R) -> Result<i64, TemplateError> {
+    let low = try!(read_u32(r)) as u64;
+    let high = try!(read_u32(r)) as u64;
+    Ok((low | high << 32) as i64)
+}
+
+/// Counts and lengths come from the file, so they're never allocated for up front: a corrupt
+/// one runs into the end of the data instead.
+fn read_str<R: Read>(r: &mut R) -> Result<String, TemplateError> {
+    let len = try!(read_u32(r)) as u64;
+    let mut bytes = vec![];
+    let read = try!(r.by_ref().take(len).read_to_end(&mut bytes).map_err(read_err));
+    if read as u64 != len {
+        return Err(TemplateError::Cache(format!("Truncated cache: a string of {} bytes has {}", len, read)));
+    }
+    String::from_utf8(bytes).map_err(|err| TemplateError::Cache(format!("{}", err)))
+}
+
+fn read_opt_str<R: Read>(r: &mut R) -> Result<Option<String>, TemplateError> {
+    match try!(read_u8(r)) {
+        0 => Ok(None),
+        _ => Ok(Some(try!(read_str(r))))
+    }
+}
+
+#[test]
+fn test_cache_round_trip() {
+    let mut templates = HashMap::new();
+    for template in Template::from_string_multi(r#"<Rock tpml:tags="mineral" tpml:match-has="rigidbody, collider" tpml:aliases="Stone, Boulder" tpml:y-when-depth=">0" tpml:transform-lazy="true" tpml:inherits-tag="heavy" x="5" y="[1, 2.5, 'three']" transform="{ a: true }" label="@name" target="@entity:camera.position" tint="@parent.color"><meta category="'props'" /></Rock><Granit inherits="Rock" tpml:inherit-mode="children" tpml:extends-file="base.tpml" tpml:mixins="Mossy" tpml:mixin-order="last" tpml:kind="fragment" tpml:required="z" tpml:abstract-property="mass, mesh"><Moss tpml:name="moss" tpml:repeat="@count" tpml:when-flag="mobile" /><parent mosses="@name" /><switch on="detail"><case value="low"><Pebble tpml:inline="true" /></case><default /></switch></Granit>"#).unwrap() {
+        templates.insert(template.type_name.clone(), template);
+    }
+    let sources = vec![(PathBuf::from("rocks.tpml"), vec!["Granit".to_string(), "Rock".to_string()])];
+
+    let mut buffer = vec![];
+    write_cache(&mut buffer, &sources, &templates).unwrap();
+    let (read_sources, read_templates) = read_cache(&mut buffer.as_slice()).unwrap();
+
+    assert_eq!(read_sources, sources);
+    assert_eq!(read_templates, templates);
+}
+
+#[test]
+fn test_cache_corrupt() {
+    let mut templates = HashMap::new();
+    templates.insert("Rock".to_string(), Template::from_string(r#"<Rock x="5" />"#).unwrap());
+    let mut buffer = vec![];
+    write_cache(&mut buffer, &vec![], &templates).unwrap();
+
+    for len in 0..buffer.len() {
+        match read_cache(&mut &buffer[..len]) {
+            Err(TemplateError::Cache(_)) => {}
+            result => panic!("Expected a cache error for {} bytes, got {:?}", len, result)
+        }
+    }
+    // A string claiming to be 4 GiB long, right after the empty source list
+    let mut corrupt = buffer[..MAGIC.len() + 8].to_vec();
+    corrupt.extend([1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF].iter().cloned());
+    match read_cache(&mut corrupt.as_slice()) {
+        Err(TemplateError::Cache(_)) => {}
+        result => panic!("Expected a cache error, got {:?}", result)
+    }
+}
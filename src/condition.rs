@@ -0,0 +1,233 @@
+use pyramid::pon::*;
+
+use template::*;
+
+/// Evaluates a condition such as `'lod > 2 && platform == "mobile"'` against properties found
+/// through `lookup`. A boolean condition is taken as is. Supported are `||`, `&&`, `!`,
+/// the comparisons `== != < <= > >=`, parentheses, numbers, quoted strings, `true` and
+/// `false`; any other word is a property name, and a missing property is nil. Values used
+/// as conditions are true unless they are nil, false, zero or an empty string.
+pub fn evaluate_condition(condition: &Pon, lookup: &Fn(&str) -> Option<Pon>) -> Result<bool, TemplateError> {
+    match condition {
+        &Pon::Boolean(value) => Ok(value),
+        &Pon::String(ref expression) => {
+            let tokens = try!(tokenize(expression));
+            let mut parser = Parser { tokens: tokens, pos: 0, lookup: lookup };
+            let value = try!(parser.or());
+            if parser.pos < parser.tokens.len() {
+                return Err(condition_err(expression, "unexpected trailing input"));
+            }
+            Ok(truthy(&value))
+        }
+        condition => Err(TemplateError::Parse(format!("Invalid condition: {:?}", condition)))
+    }
+}
+
+fn condition_err(expression: &str, message: &str) -> TemplateError {
+    TemplateError::Parse(format!("Invalid condition {}: {}", expression, message))
+}
+
+fn truthy(value: &Pon) -> bool {
+    match value {
+        &Pon::Nil => false,
+        &Pon::Boolean(value) => value,
+        &Pon::Integer(value) => value != 0,
+        &Pon::Float(value) => value != 0.0,
+        &Pon::String(ref value) => !value.is_empty(),
+        _ => true
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+enum Token {
+    Value(Pon),
+    Name(String),
+    Op(&'static str),
+    Open,
+    Close
+}
+
+const OPS: [&'static str; 9] = ["||", "&&", "==", "!=", "<=", ">=", "<", ">", "!"];
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, TemplateError> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    'outer: while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == '(' { tokens.push(Token::Open); i += 1; continue; }
+        if c == ')' { tokens.push(Token::Close); i += 1; continue; }
+        for op in OPS.iter() {
+            let op_chars: Vec<char> = op.chars().collect();
+            if chars[i..].starts_with(&op_chars) {
+                tokens.push(Token::Op(*op));
+                i += op_chars.len();
+                continue 'outer;
+            }
+        }
+        if c == '"' || c == '\'' {
+            let start = i + 1;
+            let end = match chars[start..].iter().position(|&d| d == c) {
+                Some(len) => start + len,
+                None => return Err(condition_err(expression, "unterminated string"))
+            };
+            tokens.push(Token::Value(Pon::String(chars[start..end].iter().cloned().collect())));
+            i = end + 1;
+            continue;
+        }
+        let start = i;
+        while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.' || chars[i] == '-') {
+            i += 1;
+        }
+        if start == i {
+            return Err(condition_err(expression, &format!("unexpected {}", c)));
+        }
+        let word: String = chars[start..i].iter().cloned().collect();
+        tokens.push(match word.as_str() {
+            "true" => Token::Value(Pon::Boolean(true)),
+            "false" => Token::Value(Pon::Boolean(false)),
+            word => match (word.parse::<i64>(), word.parse::<f32>()) {
+                (Ok(value), _) => Token::Value(Pon::Integer(value)),
+                (_, Ok(value)) => Token::Value(Pon::Float(value)),
+                _ => Token::Name(word.to_string())
+            }
+        });
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    lookup: &'a Fn(&str) -> Option<Pon>
+}
+
+impl<'a> Parser<'a> {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(&Token::Op(op)) => Some(op),
+            _ => None
+        }
+    }
+    fn or(&mut self) -> Result<Pon, TemplateError> {
+        let mut value = try!(self.and());
+        while self.peek_op() == Some("||") {
+            self.pos += 1;
+            let rhs = try!(self.and());
+            value = Pon::Boolean(truthy(&value) || truthy(&rhs));
+        }
+        Ok(value)
+    }
+    fn and(&mut self) -> Result<Pon, TemplateError> {
+        let mut value = try!(self.comparison());
+        while self.peek_op() == Some("&&") {
+            self.pos += 1;
+            let rhs = try!(self.comparison());
+            value = Pon::Boolean(truthy(&value) && truthy(&rhs));
+        }
+        Ok(value)
+    }
+    fn comparison(&mut self) -> Result<Pon, TemplateError> {
+        let lhs = try!(self.unary());
+        let op = match self.peek_op() {
+            Some(op) if op != "||" && op != "&&" && op != "!" => op,
+            _ => return Ok(lhs)
+        };
+        self.pos += 1;
+        let rhs = try!(self.unary());
+        let result = match op {
+            "==" => equals(&lhs, &rhs),
+            "!=" => !equals(&lhs, &rhs),
+            op => {
+                let (lhs, rhs) = match (number(&lhs), number(&rhs)) {
+                    (Some(lhs), Some(rhs)) => (lhs, rhs),
+                    _ => return Err(TemplateError::Parse(format!("Can't compare {:?} {} {:?}", lhs, op, rhs)))
+                };
+                match op {
+                    "<" => lhs < rhs,
+                    "<=" => lhs <= rhs,
+                    ">" => lhs > rhs,
+                    _ => lhs >= rhs
+                }
+            }
+        };
+        Ok(Pon::Boolean(result))
+    }
+    fn unary(&mut self) -> Result<Pon, TemplateError> {
+        if self.peek_op() == Some("!") {
+            self.pos += 1;
+            let value = try!(self.unary());
+            return Ok(Pon::Boolean(!truthy(&value)));
+        }
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Value(value)) => Ok(value),
+            Some(Token::Name(name)) => Ok((self.lookup)(&name).unwrap_or(Pon::Nil)),
+            Some(Token::Open) => {
+                let value = try!(self.or());
+                match self.tokens.get(self.pos) {
+                    Some(&Token::Close) => { self.pos += 1; Ok(value) }
+                    _ => Err(TemplateError::Parse("Missing ) in condition".to_string()))
+                }
+            }
+            token => Err(TemplateError::Parse(format!("Unexpected {:?} in condition", token)))
+        }
+    }
+}
+
+fn number(value: &Pon) -> Option<f64> {
+    match value {
+        &Pon::Integer(value) => Some(value as f64),
+        &Pon::Float(value) => Some(value as f64),
+        _ => None
+    }
+}
+
+fn equals(lhs: &Pon, rhs: &Pon) -> bool {
+    match (number(lhs), number(rhs)) {
+        (Some(lhs), Some(rhs)) => lhs == rhs,
+        _ => lhs == rhs
+    }
+}
+
+#[cfg(test)]
+fn test_lookup(key: &str) -> Option<Pon> {
+    match key {
+        "a" => Some(Pon::Integer(7)),
+        "b" => Some(Pon::Boolean(true)),
+        "c" => Some(Pon::Boolean(false)),
+        "name" => Some(Pon::String("foo".to_string())),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+fn eval(expression: &str) -> Result<bool, TemplateError> {
+    evaluate_condition(&Pon::String(expression.to_string()), &test_lookup)
+}
+
+#[test]
+fn test_condition_comparisons() {
+    assert_eq!(eval("a > 5"), Ok(true));
+    assert_eq!(eval("a <= 5"), Ok(false));
+    assert_eq!(eval("a == 7.0"), Ok(true));
+    assert_eq!(eval(r#"name == "foo""#), Ok(true));
+    assert_eq!(eval("name != 'foo'"), Ok(false));
+    assert!(eval("name > 5").is_err());
+}
+
+#[test]
+fn test_condition_boolean_ops() {
+    assert_eq!(eval("a && b"), Ok(true));
+    assert_eq!(eval("b && c"), Ok(false));
+    assert_eq!(eval("c || (a > 5 && !c)"), Ok(true));
+    assert_eq!(eval("missing"), Ok(false));
+    assert_eq!(evaluate_condition(&Pon::Boolean(true), &test_lookup), Ok(true));
+    assert!(eval("a &&").is_err());
+    assert!(eval("(a").is_err());
+}
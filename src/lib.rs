@@ -1,124 +1,1409 @@
-#![feature(convert, core)]
+#![feature(convert, core, test)]
 extern crate pyramid;
+#[cfg(feature = "xml")]
 extern crate xml;
+extern crate zip;
+#[cfg(feature = "http")]
+extern crate hyper;
+#[cfg(test)]
+extern crate test;
 
 mod template;
+mod cache;
+mod condition;
+// The only parser in builds without xml-rs
+#[cfg(any(feature = "minimal-parser", not(feature = "xml")))]
+mod minimal;
 
-use template::*;
+pub use template::*;
+pub use condition::*;
+#[cfg(any(feature = "minimal-parser", not(feature = "xml")))]
+pub use minimal::*;
 
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::mem;
 use std::path::Path;
+use std::rc::Rc;
 use std::path::PathBuf;
+use std::fs;
 use std::fs::File;
 use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::sync::mpsc::Sender;
+use std::thread;
 
 use pyramid::interface::*;
 use pyramid::pon::*;
 use pyramid::document::*;
 use pyramid::system::*;
 
-use xml::reader::EventReader;
-use xml::reader::Events;
-use xml::reader::events::*;
+use zip::ZipArchive;
+
+#[cfg(all(test, feature = "xml"))]
+use xml::escape::escape_str;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum TemplateEvent {
+    Loaded { type_name: String },
+    Applied { entity_id: EntityId, type_name: String },
+    /// A template tried to set a property outside of the allowed keys
+    Rejected { entity_id: EntityId, key: String },
+    Error { message: String }
+}
+
+/// The template applied to entities that have no template of their own type.
+pub const CATCH_ALL_TEMPLATE: &'static str = "Default";
+
+/// What happens when a template type that's already loaded is loaded again.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum LoadPolicy {
+    /// The later definition replaces the earlier one entirely
+    Replace,
+    /// The later definition is layered onto the earlier one, see `Template::layer`
+    Merge
+}
+
+/// Where an entity deviates from what its template would produce, see `diff_entity`.
+#[derive(PartialEq, Debug, Clone)]
+pub enum PropertyDiff {
+    /// The instance sets its own value instead of the template's
+    Overridden { key: String, template: Pon, instance: Pon },
+    /// The template provides the property but the entity doesn't have it
+    Missing { key: String, template: Pon }
+}
+
+/// A template that failed to apply to an entity, see `take_apply_report`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ApplyIssue {
+    pub entity_id: EntityId,
+    pub type_name: String,
+    pub error: TemplateError
+}
+
+/// The templates of a subsystem at one point, see `TemplateSubSystem::snapshot`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct TemplateSnapshot {
+    templates: HashMap<String, Template>,
+    scopes: HashMap<String, HashMap<String, Template>>,
+    base_templates: HashMap<String, Template>,
+    source_files: Vec<PathBuf>,
+    file_templates: HashMap<PathBuf, Vec<String>>
+}
+
+/// How two templates are related in `dependency_graph`.
+#[derive(PartialEq, Debug, Clone, Copy, PartialOrd, Ord, Eq)]
+pub enum EdgeKind {
+    Inherits,
+    Mixin,
+    /// The template spawns children of the other type
+    ChildType,
+    /// A file defines the template, from `templates_from_file` and the like, or the template
+    /// extends a file with `tpml:extends-file`. Files are named by their path.
+    Include
+}
+
+/// Runs after a template of the type it was registered for has been applied to an entity.
+pub type AppliedCallback = Box<FnMut(&mut System, &EntityId)>;
+
+/// Rewrites a value about to be set on an entity, or vetoes it by returning `None`.
+pub type PropertyInterceptor = Box<FnMut(&str, Pon) -> Option<Pon>>;
+
+/// Upgrades an entity from the version it was registered for to the next one. A failing
+/// migration fails the apply.
+pub type Migration = Box<Fn(&mut MigrationDocument, &EntityId) -> Result<(), TemplateError>>;
 
 pub struct TemplateSubSystem {
     root_path: PathBuf,
-    templates: HashMap<String, Template>
+    templates: HashMap<String, Template>,
+    /// Files the templates were loaded from, used to tell whether a cache is stale
+    source_files: Vec<PathBuf>,
+    /// The types each file defined when it was last loaded
+    file_templates: HashMap<PathBuf, Vec<String>>,
+    /// Named template sets consulted before the global one, for entities with a `template_scope`
+    scopes: HashMap<String, HashMap<String, Template>>,
+    /// Provided by the host and consulted after the global templates, see `set_base_templates`
+    base_templates: HashMap<String, Template>,
+    /// Active platform/feature flags, consulted by conditional load directives
+    flags: HashSet<String>,
+    type_mapper: Option<Box<Fn(&str) -> String>>,
+    /// Apply to entities in id order and to templates with match rules in type name order
+    deterministic: bool,
+    child_position: ChildPosition,
+    unit_converter: Option<Box<UnitConverter>>,
+    /// Taken out by `begin_apply` for the duration of each apply
+    interceptor: Option<PropertyInterceptor>,
+    /// Keys templates may set on entities; empty allows all
+    allowed_keys: HashSet<String>,
+    /// Refuse to apply templates whose mixins disagree on a property
+    strict_mixins: bool,
+    /// Refuse to apply templates spawning children of a type without a template
+    strict_children: bool,
+    /// Apply to the entities already in a freshly loaded document, not just to ones added later
+    retroactive: bool,
+    load_policy: LoadPolicy,
+    /// How the xml of template files and `template` directives is read
+    reader_config: ReaderConfig,
+    event_sender: Option<Sender<TemplateEvent>>,
+    migrations: HashMap<(String, u32), Migration>,
+    applied_callbacks: HashMap<String, AppliedCallback>,
+    /// Set by `freeze`: the template set can't be changed anymore
+    frozen: bool,
+    /// Global templates prepared when first applied, or all at once by `finalize`, until the
+    /// template set changes
+    prepared: HashMap<String, Rc<PreparedTemplate>>,
+    /// Set by `finalize`, cleared along with `prepared`
+    finalized: bool,
+    /// Errors loading the `templates` property of documents, plus warnings from loading
+    /// templates that still loaded, like alias conflicts and duplicate attributes
+    load_errors: Vec<TemplateError>,
+    /// Failed applies since the last `take_apply_report`
+    apply_report: Vec<ApplyIssue>,
+    stats: TemplateStats,
+    defer_children: bool,
+    transactional: bool,
+    max_spawn: Option<usize>,
+    deterministic_names: bool,
+    /// Children recorded while `defer_children` is set, waiting for `flush_deferred`
+    deferred: Vec<(EntityId, Vec<Template>)>,
+    /// Per entity, the properties last set from a template, so reloading may update them
+    provenance: HashMap<EntityId, HashSet<String>>,
+    /// Values of lazy properties held back on apply, see `lazy_property`
+    lazy: HashMap<(EntityId, String), Pon>,
+    /// `(entity, template)` pairs `on_entity_added` applied since the document loaded
+    applied: HashSet<(EntityId, String)>
+}
+
+/// What applies record while the templates they apply are borrowed from the subsystem,
+/// between `begin_apply` and `end_apply`.
+struct ApplyOutcome {
+    interceptor: Option<PropertyInterceptor>,
+    stats: TemplateStats,
+    deferred: Vec<(EntityId, Vec<Template>)>,
+    assigned: Vec<(EntityId, String)>,
+    lazy: Vec<(EntityId, String, Pon)>,
+    prepared: HashMap<String, Rc<PreparedTemplate>>,
+    applied: HashSet<(EntityId, String)>,
+    issues: Vec<ApplyIssue>
 }
 
 impl TemplateSubSystem {
     pub fn new(root_path: PathBuf) -> TemplateSubSystem {
         TemplateSubSystem {
             root_path: root_path,
-            templates: HashMap::new()
+            templates: HashMap::new(),
+            source_files: vec![],
+            file_templates: HashMap::new(),
+            scopes: HashMap::new(),
+            base_templates: HashMap::new(),
+            flags: HashSet::new(),
+            type_mapper: None,
+            deterministic: false,
+            child_position: ChildPosition::Append,
+            unit_converter: None,
+            interceptor: None,
+            allowed_keys: HashSet::new(),
+            strict_mixins: false,
+            strict_children: false,
+            retroactive: true,
+            load_policy: LoadPolicy::Replace,
+            reader_config: ReaderConfig::default(),
+            event_sender: None,
+            migrations: HashMap::new(),
+            applied_callbacks: HashMap::new(),
+            frozen: false,
+            prepared: HashMap::new(),
+            finalized: false,
+            load_errors: vec![],
+            stats: TemplateStats::default(),
+            defer_children: false,
+            transactional: false,
+            max_spawn: None,
+            deterministic_names: false,
+            apply_report: vec![],
+            deferred: vec![],
+            provenance: HashMap::new(),
+            lazy: HashMap::new(),
+            applied: HashSet::new()
         }
     }
-    fn load_templates_from_file(&mut self, path: &Path) {
-        let file = File::open(path).unwrap();
-        let file = BufReader::new(file);
-
-        let mut event_reader = EventReader::new(file);
-        let mut events = event_reader.events();
-        let mut template_stack = vec![];
-        while let Some(e) = events.next() {
-            match e.clone() {
-                XmlEvent::StartElement { name, .. } => {
-                    if name.local_name.as_str() == "Tpml" {
-                        continue;
+    pub fn set_event_sender(&mut self, tx: Sender<TemplateEvent>) {
+        self.event_sender = Some(tx);
+    }
+    pub fn set_flags(&mut self, flags: HashSet<String>) {
+        self.flags = flags;
+    }
+    /// Redirects the document type of spawned children, e.g. template `Wheel` to entity `PhysicsWheel`.
+    pub fn set_type_mapper(&mut self, f: Box<Fn(&str) -> String>) {
+        self.type_mapper = Some(f);
+    }
+    /// Controls how a later file (e.g. an override mod) treats types an earlier file already defined.
+    pub fn set_load_policy(&mut self, load_policy: LoadPolicy) {
+        self.load_policy = load_policy;
+    }
+    /// Makes application order reproducible across runs rather than following hash map iteration.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+    /// Whether spawned children go after or before the children an entity already has.
+    pub fn set_child_position(&mut self, child_position: ChildPosition) {
+        self.child_position = child_position;
+    }
+    /// Converts values written with units to each property's canonical unit while applying, see `UnitTable`.
+    pub fn set_unit_converter(&mut self, units: Box<UnitConverter>) {
+        self.unit_converter = Some(units);
+    }
+    /// Hooks into every property set by any apply, e.g. for logging or normalizing values.
+    pub fn set_property_interceptor(&mut self, f: PropertyInterceptor) {
+        self.interceptor = Some(f);
+    }
+    /// Restricts the properties templates may set, e.g. for untrusted templates. Skipped
+    /// properties are reported as `TemplateEvent::Rejected`; an empty set allows all keys.
+    pub fn set_allowed_keys(&mut self, keys: HashSet<String>) {
+        self.allowed_keys = keys;
+    }
+    /// When off, loading a document only loads its templates; they are applied to entities
+    /// added from then on but leave the entities the document came with untouched.
+    pub fn set_retroactive(&mut self, retroactive: bool) {
+        self.retroactive = retroactive;
+    }
+    /// Makes mixins that set the same property to different values an error, see `Template::check_mixins`.
+    pub fn set_strict_mixins(&mut self, strict_mixins: bool) {
+        self.strict_mixins = strict_mixins;
+    }
+    /// Makes spawning a child of a type no template is registered for an error, to catch typos;
+    /// off by default since bare children are often intended. See `Template::check_child_types`.
+    pub fn set_strict_children(&mut self, strict_children: bool) {
+        self.strict_children = strict_children;
+    }
+    /// Every `(template, child type)` where the child type has no template, by template type name.
+    pub fn validate_child_types(&self) -> Vec<TemplateError> {
+        let mut type_names: Vec<&String> = self.templates.keys().collect();
+        type_names.sort();
+        let mut errors = vec![];
+        for type_name in type_names {
+            for child in self.templates[type_name].missing_child_types(&self.global_templates()) {
+                errors.push(TemplateError::MissingChildTemplate(type_name.clone(), child));
+            }
+        }
+        errors
+    }
+    /// Every abstract property of a base that a deriving entity template doesn't override, by
+    /// template type name, see `Template::missing_overrides`.
+    pub fn validate_abstract_properties(&self) -> Vec<TemplateError> {
+        let mut type_names: Vec<&String> = self.templates.keys().collect();
+        type_names.sort();
+        let mut errors = vec![];
+        for type_name in type_names {
+            for (key, base) in self.templates[type_name].missing_overrides(&self.global_templates()) {
+                errors.push(TemplateError::MissingOverride(type_name.clone(), key, base));
+            }
+        }
+        errors
+    }
+    /// Rolls back everything an entity's template did if applying it fails part way, rather
+    /// than leaving the entity half initialized.
+    pub fn set_transactional(&mut self, transactional: bool) {
+        self.transactional = transactional;
+    }
+    /// Caps how many entities applying the template of one entity may spawn, children of
+    /// children included; going over fails the apply with `TemplateError::SpawnLimit`.
+    pub fn set_max_spawn(&mut self, max_spawn: usize) {
+        self.max_spawn = Some(max_spawn);
+    }
+    /// Base templates shared by the host, e.g. across documents: the global templates may
+    /// inherit from and mix them in, and entities of a type only they define get them, but any
+    /// template loaded into the subsystem takes precedence over a base of the same type.
+    pub fn set_base_templates(&mut self, bases: HashMap<String, Template>) -> Result<(), TemplateError> {
+        try!(self.check_not_frozen());
+        self.invalidate_prepared();
+        self.base_templates = bases;
+        Ok(())
+    }
+    /// Names every entity templates spawn after its parent and position among the parent's
+    /// children, so generated structures are the same across runs and clients.
+    pub fn set_deterministic_names(&mut self, deterministic_names: bool) {
+        self.deterministic_names = deterministic_names;
+    }
+    /// Configures the xml reader for templates loaded from now on, e.g. to trim whitespace.
+    pub fn set_reader_config(&mut self, config: ReaderConfig) {
+        self.reader_config = config;
+    }
+    /// Applies properties right away but holds back spawning children until `flush_deferred`,
+    /// for large subtrees that are rarely needed.
+    pub fn set_defer_children(&mut self, defer_children: bool) {
+        self.defer_children = defer_children;
+    }
+    /// Spawns every child held back by `set_defer_children`, including their whole subtrees.
+    pub fn flush_deferred(&mut self, system: &mut System) -> Result<(), TemplateError> {
+        let deferred = mem::replace(&mut self.deferred, vec![]);
+        let mut outcome = self.begin_apply();
+        let result = {
+            let templates = self.global_templates();
+            let mut context = self.apply_context(&templates);
+            context.stats = outcome.stats;
+            context.interceptor = outcome.interceptor.as_mut().map(|f| &mut **f);
+            context.defer_children = false;
+            let mut result = Ok(());
+            for (entity_id, children) in deferred {
+                result = Template::spawn_children(&children, &mut context, system.document_mut(), &entity_id);
+                if result.is_err() {
+                    break;
+                }
+            }
+            outcome.stats = context.stats;
+            outcome.assigned = context.assigned;
+            outcome.lazy = context.lazy;
+            result
+        };
+        self.end_apply(outcome);
+        result
+    }
+    /// Reads a property, setting it first if it's a lazy one the entity's template held back.
+    /// The document knows nothing of lazy properties, so reading them straight from it only
+    /// works once they've been asked for here or set by `flush_lazy`.
+    pub fn lazy_property(&mut self, system: &mut System, entity_id: &EntityId, key: &str) -> Result<Option<Pon>, TemplateError> {
+        let pending = self.lazy.remove(&(*entity_id, key.to_string()));
+        if let Some(value) = pending {
+            try!(system.document_mut().set_property(entity_id, key, value));
+        }
+        let document = system.document();
+        match try!(document.has_property(entity_id, key)) {
+            true => Ok(Some(try!(document.get_property(entity_id, key)).clone())),
+            false => Ok(None)
+        }
+    }
+    /// Sets every lazy property still held back.
+    pub fn flush_lazy(&mut self, system: &mut System) -> Result<(), TemplateError> {
+        let mut pending: Vec<((EntityId, String), Pon)> = mem::replace(&mut self.lazy, HashMap::new()).into_iter().collect();
+        pending.sort_by(|a, b| a.0.cmp(&b.0));
+        for ((entity_id, key), value) in pending {
+            try!(system.document_mut().set_property(&entity_id, &key, value));
+        }
+        Ok(())
+    }
+    /// Every failure applying a template since the last call, in the order they happened.
+    /// A failing entity never stops the others from being processed.
+    pub fn take_apply_report(&mut self) -> Vec<ApplyIssue> {
+        mem::replace(&mut self.apply_report, vec![])
+    }
+    /// Applies templates to every entity in the document, as if each had just been added.
+    /// Like `on_entity_added`, a template is applied to an entity at most once: firing it again,
+    /// for an entity templated before or for a child a template spawned and already templated
+    /// by its own type, leaves the entity alone. Applying a template sets it up once even if it
+    /// fails part way.
+    pub fn apply_to_all(&mut self, system: &mut System) {
+        let mut entities: Vec<EntityId> = { system.document().entities_iter().map(|x| x.clone()).collect() };
+        if self.deterministic {
+            entities.sort();
+        }
+        for entity in entities {
+            self.on_entity_added(system, &entity);
+        }
+    }
+    /// Whether entities of the type get a template when added, by type, alias or the catch-all.
+    /// Scoped templates and match rules depend on the entity and aren't considered.
+    pub fn has_template_for(&self, type_name: &str) -> bool {
+        type_template(&self.global_templates(), type_name).is_some()
+    }
+    /// The global templates the predicate accepts, in no particular order.
+    pub fn templates_matching<'a, F: Fn(&Template) -> bool + 'a>(&'a self, f: F) -> Box<Iterator<Item = &'a Template> + 'a> {
+        Box::new(self.templates.values().filter(move |template| f(template)))
+    }
+    pub fn load_errors(&self) -> &Vec<TemplateError> {
+        &self.load_errors
+    }
+    pub fn stats(&self) -> TemplateStats {
+        self.stats
+    }
+    pub fn resolve_template(&self, type_name: &str) -> Option<Template> {
+        self.templates.get(type_name).map(|template| template.resolve(&self.global_templates()))
+    }
+    /// The properties the entity's template contributes, with the value that wins for each:
+    /// the instance's own unless the template replaces it. Properties only the instance sets
+    /// aren't part of this view.
+    pub fn effective_properties(&self, system: &System, entity_id: &EntityId) -> Vec<(String, Pon)> {
+        let document = system.document();
+        let type_name = match document.get_entity_type_name(entity_id) {
+            Ok(type_name) => type_name.clone(),
+            Err(_) => return vec![]
+        };
+        let templates = self.templates_for(document, entity_id);
+        let template = match templates.get_template(&type_name) {
+            Some(template) => template,
+            None => return vec![]
+        };
+        template.flatten(&templates).into_iter().map(|property| {
+            let instance = if property.replace { None } else { document.get_property(entity_id, &property.key).ok() };
+            match instance {
+                Some(value) => (property.key, value.clone()),
+                None => (property.key, property.value)
+            }
+        }).collect()
+    }
+    /// Compares the entity's current properties against its resolved template. Properties the
+    /// template doesn't know about aren't reported.
+    pub fn diff_entity(&self, system: &System, entity_id: &EntityId) -> Vec<PropertyDiff> {
+        let document = system.document();
+        let type_name = match document.get_entity_type_name(entity_id) {
+            Ok(type_name) => type_name.clone(),
+            Err(_) => return vec![]
+        };
+        let templates = self.templates_for(document, entity_id);
+        let template = match templates.get_template(&type_name) {
+            Some(template) => template,
+            None => return vec![]
+        };
+        let mut diffs = vec![];
+        for property in template.flatten(&templates) {
+            match document.get_property(entity_id, &property.key) {
+                Ok(value) => {
+                    if value.concretize().ok() != property.value.concretize().ok() {
+                        diffs.push(PropertyDiff::Overridden { key: property.key, template: property.value, instance: value.clone() });
                     }
                 }
-                XmlEvent::EndElement { name, .. } => {
-                    if name.local_name.as_str() == "Tpml" {
-                        continue;
+                Err(_) => diffs.push(PropertyDiff::Missing { key: property.key, template: property.value })
+            }
+        }
+        diffs
+    }
+    /// The value a template declares itself for a property, ignoring its bases.
+    pub fn template_property(&self, type_name: &str, key: &str) -> Option<&Pon> {
+        self.templates.get(type_name)
+            .and_then(|template| template.properties.iter().find(|p| p.0 == key))
+            .map(|p| &p.1)
+    }
+    /// The value a template ends up with for a property once its inheritance chain is resolved,
+    /// following the same precedence as applying it.
+    pub fn inherited_template_property(&self, type_name: &str, key: &str) -> Option<Pon> {
+        self.templates.get(type_name)
+            .and_then(|template| template.flatten(&self.global_templates()).into_iter().find(|p| p.key == key))
+            .map(|p| p.value)
+    }
+    /// The type followed by each of its bases, up to the root of the hierarchy.
+    pub fn inheritance_chain(&self, type_name: &str) -> Vec<String> {
+        match self.templates.get(type_name) {
+            Some(template) => template.chain(&self.global_templates()).iter().rev().map(|t| t.type_name.clone()).collect(),
+            None => vec![]
+        }
+    }
+    /// Renders the inheritance chain of a type as an indented tree, the type itself first and
+    /// each base (or mixin) one level deeper, with the properties each template declares. A
+    /// property that loses to another template's value is marked with the template it loses to.
+    pub fn print_hierarchy(&self, type_name: &str) -> String {
+        let template = match self.templates.get(type_name) {
+            Some(template) => template,
+            None => return format!("{} (no template)\n", type_name)
+        };
+        let templates = self.global_templates();
+        let resolved = template.flatten(&templates);
+        let mut out = String::new();
+        for (depth, t) in template.chain(&templates).iter().rev().enumerate() {
+            let indent: String = (0..depth).map(|_| "  ").collect();
+            out.push_str(&format!("{}{}\n", indent, t.type_name));
+            for &(ref key, ref value) in &t.properties {
+                out.push_str(&format!("{}  {} = {:?}", indent, key, value));
+                if let Some(winner) = resolved.iter().find(|p| &p.key == key) {
+                    if winner.source != t.type_name {
+                        out.push_str(&format!(" (shadowed by {})", winner.source));
                     }
                 }
-                _ => {}
+                out.push_str("\n");
             }
-            match Template::parse_event(&mut template_stack, e) {
-                Some(template) => { self.templates.insert(template.type_name.clone(), template); }
-                _ => {}
+        }
+        out
+    }
+    /// Runs the same template matching as `on_entity_added` for an entity and all its descendants,
+    /// e.g. after attaching a pre-built subtree. Descendants are collected up front, so children
+    /// spawned by the templates themselves aren't matched again.
+    pub fn apply_to_subtree(&mut self, system: &mut System, root_entity: &EntityId) {
+        let mut entities = vec![];
+        let mut pending = vec![*root_entity];
+        while let Some(entity_id) = pending.pop() {
+            entities.push(entity_id);
+            if let Ok(children) = system.document().get_children(&entity_id) {
+                pending.extend(children.iter().cloned());
             }
         }
+        for entity_id in entities {
+            self.on_entity_added(system, &entity_id);
+        }
     }
-    fn load_templates(&mut self, node: &Pon, context: &mut TranslateContext) -> Result<(), PonTranslateErr> {
-        node.as_array(|templates| {
-            for pn in templates {
-                try!(pn.as_typed(|p| {
-                    match p.type_name.as_str() {
-                        "template" => {
-                            let s = try!(p.data.translate::<String>(context));
-                            let template = Template::from_string(&s).unwrap();
-                            self.templates.insert(template.type_name.clone(), template);
-                        }
-                        "templates_from_file" => {
-                            let filename = try!(p.data.translate::<String>(context));
-                            let path = self.root_path.join(Path::new(&filename));
-                            self.load_templates_from_file(&path);
+    /// Every `(from, to, kind)` relation between the loaded global templates and the files they
+    /// come from, sorted. Targets that aren't loaded are included too, so dangling references show up.
+    pub fn dependency_graph(&self) -> Vec<(String, String, EdgeKind)> {
+        let mut edges = vec![];
+        for (type_name, template) in &self.templates {
+            if let Some(ref inherits) = template.inherits {
+                edges.push((type_name.clone(), inherits.clone(), EdgeKind::Inherits));
+            }
+            for mixin in &template.mixins {
+                edges.push((type_name.clone(), mixin.clone(), EdgeKind::Mixin));
+            }
+            let mut children: Vec<&Template> = template.children.iter().collect();
+            for switch in &template.switches {
+                for case in &switch.cases {
+                    children.extend(case.children.iter());
+                }
+            }
+            while let Some(child) = children.pop() {
+                edges.push((type_name.clone(), child.type_name.clone(), EdgeKind::ChildType));
+                children.extend(child.children.iter());
+            }
+            if let Some(ref file) = template.extends_file {
+                edges.push((type_name.clone(), file.clone(), EdgeKind::Include));
+            }
+        }
+        for (path, type_names) in &self.file_templates {
+            for type_name in type_names {
+                edges.push((path.display().to_string(), type_name.clone(), EdgeKind::Include));
+            }
+        }
+        edges.sort();
+        edges.dedup();
+        edges
+    }
+    /// Every property declaration of the global templates that is shadowed by a base or mixin
+    /// and so never takes effect, as `(template, key, template whose value wins)`, sorted.
+    pub fn dead_properties(&self) -> Vec<(String, String, String)> {
+        let mut dead = vec![];
+        for (type_name, template) in &self.templates {
+            for (key, source) in template.dead_properties(&self.global_templates()) {
+                dead.push((type_name.clone(), key, source));
+            }
+        }
+        dead.sort();
+        dead
+    }
+    /// Templates no entity in the document uses, directly, as a base or through spawned children.
+    pub fn unused_templates(&self, system: &System) -> Vec<String> {
+        let document = system.document();
+        let mut pending: Vec<String> = document.entities_iter()
+            .filter_map(|entity_id| document.get_entity_type_name(entity_id).ok().map(|t| t.clone()))
+            .collect();
+        let mut used = HashSet::new();
+        while let Some(type_name) = pending.pop() {
+            if !used.insert(type_name.clone()) {
+                continue;
+            }
+            if let Some(template) = self.templates.get(&type_name) {
+                for t in template.chain(&self.templates) {
+                    pending.push(t.type_name.clone());
+                    let mut children: Vec<&Template> = t.children.iter().collect();
+                    while let Some(child) = children.pop() {
+                        pending.push(child.type_name.clone());
+                        children.extend(child.children.iter());
+                    }
+                }
+            }
+        }
+        let mut unused: Vec<String> = self.templates.keys().filter(|t| !used.contains(*t)).cloned().collect();
+        unused.sort();
+        unused
+    }
+    pub fn on_applied(&mut self, type_name: &str, callback: AppliedCallback) {
+        self.applied_callbacks.insert(type_name.to_string(), callback);
+    }
+    pub fn add_migration(&mut self, type_name: &str, version: u32, migration: Migration) {
+        self.migrations.insert((type_name.to_string(), version), migration);
+    }
+    /// Brings the entity up to the template's `version`. Part of `apply_and_report`, so it runs
+    /// for type, component and match rule templates alike, and for `apply_template`, inside the
+    /// apply's transaction.
+    fn migrate(&self, document: &mut MigrationDocument, entity_id: &EntityId, template: &Template) -> Result<(), TemplateError> {
+        let target = match template.version {
+            Some(version) => version,
+            None => return Ok(())
+        };
+        // Instances without a version are assumed to be up to date
+        let mut version = match document.get_property(entity_id, "version").map(|p| p.concretize()) {
+            Ok(Ok(Pon::Integer(version))) if version >= 0 => version as u32,
+            _ => return Ok(())
+        };
+        while version < target {
+            if let Some(migration) = self.migrations.get(&(template.type_name.clone(), version)) {
+                try!(migration(document, entity_id));
+            }
+            version += 1;
+        }
+        document.set_property(entity_id, "version", Pon::Integer(target as i64))
+    }
+    /// Applies the named template to the entity regardless of the entity's own type.
+    pub fn apply_template(&mut self, system: &mut System, entity_id: &EntityId, type_name: &str) -> Result<(), TemplateError> {
+        let mut outcome = self.begin_apply();
+        let result = match self.templates.get(type_name) {
+            Some(template) => {
+                let prepared = self.prepared_for(&mut outcome, &template.type_name);
+                self.apply_and_report(&mut outcome, template, prepared, &self.global_templates(), system, entity_id)
+            }
+            None => Err(TemplateError::UnknownTemplate(type_name.to_string()))
+        };
+        self.end_apply(outcome);
+        result
+    }
+    fn begin_apply(&mut self) -> ApplyOutcome {
+        ApplyOutcome {
+            interceptor: self.interceptor.take(),
+            stats: self.stats,
+            deferred: vec![],
+            assigned: vec![],
+            lazy: vec![],
+            prepared: HashMap::new(),
+            applied: HashSet::new(),
+            issues: vec![]
+        }
+    }
+    /// Keeps what the applies since `begin_apply` recorded.
+    fn end_apply(&mut self, outcome: ApplyOutcome) {
+        self.interceptor = outcome.interceptor;
+        self.stats = outcome.stats;
+        self.deferred.extend(outcome.deferred.into_iter());
+        for (entity_id, key) in outcome.assigned {
+            self.provenance.entry(entity_id).or_insert_with(HashSet::new).insert(key);
+        }
+        for (entity_id, key, value) in outcome.lazy {
+            self.lazy.insert((entity_id, key), value);
+        }
+        self.prepared.extend(outcome.prepared.into_iter());
+        self.applied.extend(outcome.applied.into_iter());
+        self.apply_report.extend(outcome.issues.into_iter());
+    }
+    fn apply_context<'a>(&'a self, templates: &'a TemplateSource) -> ApplyContext<'a> {
+        let mut context = ApplyContext::new(templates);
+        context.type_mapper = self.type_mapper.as_ref().map(|f| &**f);
+        context.defer_children = self.defer_children;
+        context.transactional = self.transactional;
+        context.max_spawn = self.max_spawn;
+        context.deterministic_names = self.deterministic_names;
+        context.child_position = self.child_position;
+        context.units = self.unit_converter.as_ref().map(|units| &**units);
+        if !self.allowed_keys.is_empty() {
+            context.allowed_keys = Some(&self.allowed_keys);
+        }
+        context.flags = Some(&self.flags);
+        context
+    }
+    fn apply_and_report(&self, outcome: &mut ApplyOutcome, template: &Template, prepared: Option<Rc<PreparedTemplate>>, templates: &TemplateSource, system: &mut System, entity_id: &EntityId) -> Result<(), TemplateError> {
+        let mut context = self.apply_context(templates);
+        context.stats = outcome.stats;
+        context.interceptor = outcome.interceptor.as_mut().map(|f| &mut **f);
+        let result = match self.strict_mixins {
+            true => template.check_mixins(templates),
+            false => Ok(())
+        };
+        let result = result.and_then(|_| match self.strict_children {
+            true => template.check_child_types(templates),
+            false => Ok(())
+        });
+        let result = result.and_then(|_| ApplyContext::transaction(&mut context, system.document_mut(), &mut |context: &mut ApplyContext, document: &mut Document| {
+            try!(self.migrate(&mut MigrationDocument::new(document, &mut context.undo), entity_id, template));
+            match prepared {
+                Some(ref prepared) => template.apply_prepared(prepared, context, document, entity_id),
+                None => template.apply_in(context, document, entity_id)
+            }
+        }));
+        outcome.stats = context.stats;
+        outcome.deferred.extend(context.deferred.into_iter());
+        outcome.assigned.extend(context.assigned.into_iter());
+        outcome.lazy.extend(context.lazy.into_iter());
+        for (entity_id, key) in context.rejected {
+            self.emit(TemplateEvent::Rejected { entity_id: entity_id, key: key });
+        }
+        match result {
+            Ok(()) => self.emit(TemplateEvent::Applied { entity_id: *entity_id, type_name: template.type_name.clone() }),
+            Err(ref err) => {
+                self.emit(TemplateEvent::Error { message: format!("{:?}", err) });
+                outcome.issues.push(ApplyIssue { entity_id: *entity_id, type_name: template.type_name.clone(), error: err.clone() });
+            }
+        }
+        result
+    }
+    /// Marks the template as applied to the entity, returning whether it wasn't before. It is
+    /// marked up front, so a type and a component naming the same template apply it once.
+    fn first_application(&self, outcome: &mut ApplyOutcome, entity_id: &EntityId, type_name: &str) -> bool {
+        let key = (*entity_id, type_name.to_string());
+        !self.applied.contains(&key) && outcome.applied.insert(key)
+    }
+    fn alias_conflicts(&self, template: &Template) -> Vec<TemplateError> {
+        let mut conflicts = vec![];
+        for alias in &template.aliases {
+            let taken = self.templates.contains_key(alias) || self.templates.values()
+                .any(|t| t.type_name != template.type_name && t.aliases.contains(alias));
+            if taken {
+                conflicts.push(TemplateError::AliasConflict(alias.clone(), template.type_name.clone()));
+            }
+        }
+        for t in self.templates.values() {
+            if t.type_name != template.type_name && t.aliases.contains(&template.type_name) {
+                conflicts.push(TemplateError::AliasConflict(template.type_name.clone(), t.type_name.clone()));
+            }
+        }
+        conflicts
+    }
+    fn emit(&self, event: TemplateEvent) {
+        if let Some(ref tx) = self.event_sender {
+            // A dropped receiver just means nobody is listening anymore
+            tx.send(event).ok();
+        }
+    }
+    /// What parsing reported but didn't stop the templates from loading is recorded like a
+    /// load error.
+    fn record_warnings(&mut self, warnings: Vec<TemplateError>) {
+        for warning in warnings {
+            self.emit(TemplateEvent::Error { message: format!("{:?}", warning) });
+            self.load_errors.push(warning);
+        }
+    }
+    fn insert_template(&mut self, template: Template) {
+        self.invalidate_prepared();
+        self.emit(TemplateEvent::Loaded { type_name: template.type_name.clone() });
+        // Conflicts are reported but the template still loads; a real type always wins over an alias
+        for err in self.alias_conflicts(&template) {
+            self.emit(TemplateEvent::Error { message: format!("{:?}", err) });
+            self.load_errors.push(err);
+        }
+        if self.load_policy == LoadPolicy::Merge {
+            if let Some(existing) = self.templates.get_mut(&template.type_name) {
+                existing.layer(template);
+                return;
+            }
+        }
+        self.templates.insert(template.type_name.clone(), template);
+    }
+    /// Re-parses the files templates were loaded from and reapplies only the entities whose
+    /// template, or one of its bases or mixins, changed; the reapplied entities are returned.
+    /// Properties a template set on an entity, and that still have the old template's value,
+    /// are moved to the new value, while values the instance set itself are kept even if they
+    /// equal the template's. Children aren't spawned again, and
+    /// templates that weren't loaded from a file are left as they are.
+    pub fn reload_incremental(&mut self, system: &mut System) -> Result<Vec<EntityId>, TemplateError> {
+        let sources = self.source_files.clone();
+        self.reload_sources(system, &sources)
+    }
+    /// Like `reload_incremental` for a single file: types the file no longer defines are
+    /// removed, and only the entities depending on the file's templates are reapplied.
+    pub fn reload_file(&mut self, system: &mut System, path: &Path) -> Result<Vec<EntityId>, TemplateError> {
+        self.reload_sources(system, &[path.to_path_buf()])
+    }
+    /// Every type the files defined before or define now is rebuilt from all source files in
+    /// load order through `insert_template`, so layers under `LoadPolicy::Merge` and load events
+    /// are the same as on the first load, and a type no file defines anymore is dropped. Other
+    /// files defining one of those types are read again for it. Nothing changes unless every
+    /// file parses.
+    fn reload_sources(&mut self, system: &mut System, paths: &[PathBuf]) -> Result<Vec<EntityId>, TemplateError> {
+        try!(self.check_not_frozen());
+        let mut sources = self.source_files.clone();
+        for path in paths {
+            if !sources.contains(path) {
+                sources.push(path.clone());
+            }
+        }
+        let mut warnings = vec![];
+        let mut parsed = HashMap::new();
+        let mut affected = HashSet::new();
+        for path in paths {
+            let templates = try!(parse_tpml_file_with_warnings(path, &self.reader_config, &mut warnings));
+            if let Some(type_names) = self.file_templates.get(path) {
+                affected.extend(type_names.iter().cloned());
+            }
+            affected.extend(templates.iter().map(|t| t.type_name.clone()));
+            parsed.insert(path.clone(), templates);
+        }
+        for source in &sources {
+            let defines_affected = self.file_templates.get(source).map(|type_names| type_names.iter().any(|t| affected.contains(t))) == Some(true);
+            if defines_affected && !parsed.contains_key(source) {
+                let templates = try!(parse_tpml_file_with_warnings(source, &self.reader_config, &mut warnings));
+                parsed.insert(source.clone(), templates);
+            }
+        }
+        let previous = self.templates.clone();
+        for type_name in &affected {
+            self.templates.remove(type_name);
+        }
+        self.record_warnings(warnings);
+        for source in &sources {
+            let templates = match parsed.remove(source) {
+                Some(templates) => templates,
+                None => continue
+            };
+            if paths.contains(source) {
+                self.file_templates.insert(source.clone(), templates.iter().map(|t| t.type_name.clone()).collect());
+            }
+            for template in templates {
+                if affected.contains(&template.type_name) {
+                    self.insert_template(template);
+                }
+            }
+        }
+        self.source_files = sources;
+        self.reapply_changed(system, previous)
+    }
+    fn reapply_changed(&mut self, system: &mut System, previous: HashMap<String, Template>) -> Result<Vec<EntityId>, TemplateError> {
+        self.invalidate_prepared();
+        let mut changed = HashSet::new();
+        for (type_name, template) in &self.templates {
+            if previous.get(type_name) != Some(template) {
+                changed.insert(type_name.clone());
+            }
+        }
+        for type_name in previous.keys() {
+            if !self.templates.contains_key(type_name) {
+                changed.insert(type_name.clone());
+            }
+        }
+        let mut entities: Vec<EntityId> = system.document().entities_iter().map(|x| x.clone()).collect();
+        entities.sort();
+        let mut outcome = self.begin_apply();
+        let result = self.reapply_entities(&mut outcome, system, &previous, &changed, entities);
+        self.end_apply(outcome);
+        result
+    }
+    fn reapply_entities(&self, outcome: &mut ApplyOutcome, system: &mut System, previous: &HashMap<String, Template>, changed: &HashSet<String>, entities: Vec<EntityId>) -> Result<Vec<EntityId>, TemplateError> {
+        let mut reapplied = vec![];
+        for entity_id in entities {
+            let type_name = match system.document().get_entity_type_name(&entity_id) {
+                Ok(type_name) => type_name.clone(),
+                Err(_) => continue
+            };
+            // Scope and base layers are unchanged by the reload, so only the global layer differs
+            let scope = self.template_scope(system.document(), &entity_id);
+            let old_templates = self.layered_templates(scope, previous);
+            let new_templates = self.layered_templates(scope, &self.templates);
+            let old = type_template(&old_templates, &type_name);
+            let new = type_template(&new_templates, &type_name);
+            let affected = |template: Option<&Template>, templates: &TemplateSource| match template {
+                Some(template) => template.chain(templates).iter().any(|t| changed.contains(&t.type_name)),
+                None => false
+            };
+            if !affected(old, &old_templates) && !affected(new, &new_templates) {
+                continue;
+            }
+            if let (Some(old), Some(new)) = (old, new) {
+                let new_properties = new.flatten(&new_templates);
+                for property in old.flatten(&old_templates) {
+                    // Only values a template set, and nobody changed since, follow the template
+                    let from_template = self.provenance.get(&entity_id).map(|keys| keys.contains(&property.key)) == Some(true);
+                    let stale = from_template && match system.document().get_property(&entity_id, &property.key) {
+                        Ok(value) => value.concretize().ok() == property.value.concretize().ok(),
+                        Err(_) => false
+                    };
+                    if let Some(new_property) = new_properties.iter().find(|p| p.key == property.key) {
+                        if stale && new_property.value != property.value {
+                            try!(system.document_mut().set_property(&entity_id, &property.key, new_property.value.clone()));
                         }
-                        _ => return Err(PonTranslateErr::UnrecognizedType(p.type_name.clone()))
                     }
-                    Ok(())
-                }))
+                }
+            }
+            if let Some(template) = new {
+                let result = {
+                    let mut context = self.apply_context(&new_templates);
+                    context.stats = outcome.stats;
+                    context.interceptor = outcome.interceptor.as_mut().map(|f| &mut **f);
+                    // The entity already has its children from the first apply
+                    context.defer_children = true;
+                    let result = template.apply_in(&mut context, system.document_mut(), &entity_id);
+                    outcome.stats = context.stats;
+                    outcome.assigned.extend(context.assigned.into_iter());
+                    outcome.lazy.extend(context.lazy.into_iter());
+                    result
+                };
+                match result {
+                    Ok(()) => self.emit(TemplateEvent::Applied { entity_id: entity_id, type_name: template.type_name.clone() }),
+                    Err(ref err) => {
+                        self.emit(TemplateEvent::Error { message: format!("{:?}", err) });
+                        outcome.issues.push(ApplyIssue { entity_id: entity_id, type_name: template.type_name.clone(), error: err.clone() });
+                    }
+                }
+            }
+            reapplied.push(entity_id);
+        }
+        Ok(reapplied)
+    }
+    /// Locks the template set: loading, adding, removing and reloading templates, and setting
+    /// base templates, fail with `TemplateError::Frozen` from then on, while applying and
+    /// queries keep working.
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+    /// Moves resolving inheritance to load time: every global template is checked for missing
+    /// bases and mixins and for inheritance cycles, and its flattened properties and children
+    /// are kept for applying to entities without a `template_scope`. Loading, adding, removing
+    /// or reloading templates drops what was prepared until `finalize` is called again.
+    pub fn finalize(&mut self) -> Result<(), TemplateError> {
+        let prepared = {
+            let templates = self.global_templates();
+            let mut type_names: Vec<&String> = self.templates.keys().collect();
+            type_names.sort();
+            let mut prepared = HashMap::new();
+            for type_name in type_names {
+                let template = &self.templates[type_name];
+                try!(template.check_bases(&templates));
+                prepared.insert(type_name.clone(), Rc::new(template.prepare(&templates)));
+            }
+            prepared
+        };
+        self.prepared = prepared;
+        self.finalized = true;
+        Ok(())
+    }
+    pub fn is_finalized(&self) -> bool {
+        self.finalized
+    }
+    /// The flattened properties and children of a global template, prepared the first time
+    /// it's asked for so applying it to further entities doesn't walk the chain again.
+    fn prepared_for(&self, outcome: &mut ApplyOutcome, type_name: &str) -> Option<Rc<PreparedTemplate>> {
+        if let Some(prepared) = self.prepared.get(type_name).or(outcome.prepared.get(type_name)) {
+            return Some(prepared.clone());
+        }
+        let templates = self.global_templates();
+        let prepared = match templates.get_template(type_name) {
+            Some(template) => Rc::new(template.prepare(&templates)),
+            None => return None
+        };
+        outcome.prepared.insert(type_name.to_string(), prepared.clone());
+        Some(prepared)
+    }
+    fn invalidate_prepared(&mut self) {
+        self.prepared.clear();
+        self.finalized = false;
+    }
+    fn check_not_frozen(&self) -> Result<(), TemplateError> {
+        match self.frozen {
+            true => Err(TemplateError::Frozen),
+            false => Ok(())
+        }
+    }
+    /// Copies the global, scoped and base templates along with the files they came from, for
+    /// `restore` to go back to, e.g. to undo template edits in an editor.
+    pub fn snapshot(&self) -> TemplateSnapshot {
+        TemplateSnapshot {
+            templates: self.templates.clone(),
+            scopes: self.scopes.clone(),
+            base_templates: self.base_templates.clone(),
+            source_files: self.source_files.clone(),
+            file_templates: self.file_templates.clone()
+        }
+    }
+    /// Replaces the template set with a snapshot in one step. Entities already templated keep
+    /// what was applied to them.
+    pub fn restore(&mut self, snapshot: TemplateSnapshot) -> Result<(), TemplateError> {
+        try!(self.check_not_frozen());
+        self.invalidate_prepared();
+        self.templates = snapshot.templates;
+        self.scopes = snapshot.scopes;
+        self.base_templates = snapshot.base_templates;
+        self.source_files = snapshot.source_files;
+        self.file_templates = snapshot.file_templates;
+        Ok(())
+    }
+    /// Adds a template to the global set, following the load policy like loaded templates.
+    pub fn add_template(&mut self, template: Template) -> Result<(), TemplateError> {
+        try!(self.check_not_frozen());
+        self.insert_template(template);
+        Ok(())
+    }
+    pub fn remove_template(&mut self, type_name: &str) -> Result<Option<Template>, TemplateError> {
+        try!(self.check_not_frozen());
+        self.invalidate_prepared();
+        Ok(self.templates.remove(type_name))
+    }
+    /// Writes the loaded global templates to a compact binary cache.
+    pub fn save_cache(&self, path: &Path) -> Result<(), TemplateError> {
+        let mut file = try!(File::create(path).map_err(|err| TemplateError::Io(format!("{}", err))));
+        let sources: Vec<(PathBuf, Vec<String>)> = self.source_files.iter()
+            .map(|source| (source.clone(), self.file_templates.get(source).cloned().unwrap_or(vec![])))
+            .collect();
+        cache::write_cache(&mut file, &sources, &self.templates)
+    }
+    /// Loads templates from a cache written by `save_cache`, unless it's missing, unreadable or
+    /// older than any of the files it was built from. Returns whether the cache was used.
+    pub fn load_cache(&mut self, path: &Path) -> Result<bool, TemplateError> {
+        try!(self.check_not_frozen());
+        // An archive entry is as new as the archive it's in
+        let modified = |path: &Path| {
+            let path = split_archive_path(path).map(|(archive, _)| archive).unwrap_or(path.to_path_buf());
+            fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()
+        };
+        let cache_modified = match modified(path) {
+            Some(cache_modified) => cache_modified,
+            None => return Ok(false)
+        };
+        let file = try!(File::open(path).map_err(|err| TemplateError::Io(format!("{}", err))));
+        // A bad cache is just one to parse the sources again for
+        let (sources, templates) = match cache::read_cache(&mut BufReader::new(file)) {
+            Ok(cache) => cache,
+            Err(TemplateError::Cache(_)) | Err(TemplateError::Io(_)) => return Ok(false),
+            Err(err) => return Err(err)
+        };
+        for &(ref source, _) in &sources {
+            match modified(source) {
+                Some(source_modified) if source_modified <= cache_modified => {}
+                _ => return Ok(false)
+            }
+        }
+        for (_, template) in templates {
+            self.insert_template(template);
+        }
+        // Like loading the files themselves, so `reload_file` knows what each of them defined
+        for (source, type_names) in sources {
+            self.source_files.push(source.clone());
+            self.file_templates.insert(source, type_names);
+        }
+        Ok(true)
+    }
+    fn load_templates_from_file(&mut self, path: &Path) -> Result<(), TemplateError> {
+        try!(self.check_not_frozen());
+        self.source_files.push(path.to_path_buf());
+        let mut warnings = vec![];
+        let parsed = try!(parse_tpml_file_with_warnings(path, &self.reader_config, &mut warnings));
+        self.record_warnings(warnings);
+        self.file_templates.insert(path.to_path_buf(), parsed.iter().map(|t| t.type_name.clone()).collect());
+        for template in parsed {
+            self.insert_template(template);
+        }
+        Ok(())
+    }
+    /// Parses the files on separate threads, then loads them in the order given, so the result
+    /// doesn't depend on which file finishes first. Types defined by more than one of the files
+    /// are returned as `(type, first file, later file)`; the load policy decides which wins.
+    pub fn load_templates_from_files(&mut self, paths: &[PathBuf]) -> Result<Vec<(String, PathBuf, PathBuf)>, TemplateError> {
+        try!(self.check_not_frozen());
+        let config = self.reader_config;
+        let handles: Vec<_> = paths.iter().cloned().map(|path| thread::spawn(move || {
+            let mut warnings = vec![];
+            let result = parse_tpml_file_with_warnings(&path, &config, &mut warnings);
+            (result, warnings)
+        })).collect();
+        let mut parsed = vec![];
+        for handle in handles {
+            match handle.join() {
+                Ok((result, warnings)) => {
+                    parsed.push(try!(result));
+                    self.record_warnings(warnings);
+                }
+                Err(_) => return Err(TemplateError::Parse("Parser thread panicked".to_string()))
+            }
+        }
+        let mut defined_in: HashMap<String, PathBuf> = HashMap::new();
+        let mut conflicts = vec![];
+        for (path, templates) in paths.iter().zip(parsed.into_iter()) {
+            self.source_files.push(path.clone());
+            self.file_templates.insert(path.clone(), templates.iter().map(|t| t.type_name.clone()).collect());
+            for template in templates {
+                match defined_in.get(&template.type_name) {
+                    Some(first) if first != path => conflicts.push((template.type_name.clone(), first.clone(), path.clone())),
+                    _ => {}
+                }
+                if !defined_in.contains_key(&template.type_name) {
+                    defined_in.insert(template.type_name.clone(), path.clone());
+                }
+                self.insert_template(template);
+            }
+        }
+        Ok(conflicts)
+    }
+    /// Loads every `.tpml` entry of a zip archive like a file at `archive/entry`, which is
+    /// what `source_files` and `reload_file` know it by. Entries can `extends-file` each other.
+    pub fn load_templates_from_archive(&mut self, archive: &Path) -> Result<(), TemplateError> {
+        try!(self.check_not_frozen());
+        let names = {
+            let file = try!(File::open(archive).map_err(|err| TemplateError::Io(format!("{}", err))));
+            let mut zip = try!(ZipArchive::new(BufReader::new(file)).map_err(|err| TemplateError::Archive(format!("{:?}", err))));
+            let mut names = vec![];
+            for i in 0..zip.len() {
+                let entry = try!(zip.by_index(i).map_err(|err| TemplateError::Archive(format!("{:?}", err))));
+                if entry.name().ends_with(".tpml") {
+                    names.push(entry.name().to_string());
+                }
+            }
+            names
+        };
+        for name in names {
+            try!(self.load_templates_from_file(&archive.join(&name)));
+        }
+        Ok(())
+    }
+    /// Like `load_templates_from_archive` for an archive that isn't a file. Entries can still
+    /// `extends-file` each other, but with nothing on disk to re-read they aren't added to
+    /// `source_files` for reloading or the cache.
+    pub fn load_templates_from_archive_reader<R: Read + Seek>(&mut self, reader: R) -> Result<(), TemplateError> {
+        try!(self.check_not_frozen());
+        let mut zip = try!(ZipArchive::new(reader).map_err(|err| TemplateError::Archive(format!("{:?}", err))));
+        let mut names = vec![];
+        let mut entries = HashMap::new();
+        for i in 0..zip.len() {
+            let mut entry = try!(zip.by_index(i).map_err(|err| TemplateError::Archive(format!("{:?}", err))));
+            let mut bytes = vec![];
+            try!(entry.read_to_end(&mut bytes).map_err(|err| TemplateError::Io(format!("{}", err))));
+            let name = archive_entry_name(Path::new(entry.name()));
+            if name.ends_with(".tpml") {
+                names.push(name.clone());
+            }
+            entries.insert(name, bytes);
+        }
+        let read = |path: &Path| match entries.get(&archive_entry_name(path)) {
+            Some(bytes) => Ok(bytes.clone()),
+            None => Err(TemplateError::Archive(format!("No entry {} in the archive", path.display())))
+        };
+        for name in names {
+            let mut warnings = vec![];
+            let parsed = try!(parse_tpml_source(Path::new(&name), &self.reader_config, &read, &mut warnings));
+            self.record_warnings(warnings);
+            for template in parsed {
+                self.insert_template(template);
+            }
+        }
+        Ok(())
+    }
+    /// Fetches a `.tpml` file over HTTP and loads its templates.
+    #[cfg(feature = "http")]
+    pub fn load_templates_from_url(&mut self, url: &str) -> Result<(), TemplateError> {
+        try!(self.check_not_frozen());
+        let client = hyper::Client::new();
+        let response = try!(client.get(url).send().map_err(|err| TemplateError::Http(format!("{}", err))));
+        if !response.status.is_success() {
+            return Err(TemplateError::HttpStatus(url.to_string(), response.status.to_u16()));
+        }
+        self.load_templates_from_reader(BufReader::new(response))
+    }
+    #[cfg(feature = "http")]
+    fn load_templates_from_reader<R: Read>(&mut self, mut reader: R) -> Result<(), TemplateError> {
+        let mut source = String::new();
+        try!(reader.read_to_string(&mut source).map_err(|err| TemplateError::Io(format!("{}", err))));
+        let mut warnings = vec![];
+        let parsed = try!(Template::from_string_multi_with_warnings(&source, &self.reader_config, &mut warnings));
+        self.record_warnings(warnings);
+        for template in parsed {
+            self.insert_template(template);
+        }
+        Ok(())
+    }
+    /// Loads templates into a named scope, so they don't collide with templates of the same type elsewhere.
+    pub fn load_templates_scoped(&mut self, scope: &str, node: &Pon) -> Result<(), TemplateError> {
+        try!(self.check_not_frozen());
+        let scoped = self.scopes.remove(scope).unwrap_or(HashMap::new());
+        let global = mem::replace(&mut self.templates, scoped);
+        let result = self.load_templates(node, &mut TranslateContext::empty());
+        let scoped = mem::replace(&mut self.templates, global);
+        self.scopes.insert(scope.to_string(), scoped);
+        result
+    }
+    /// The templates visible to an entity: its `template_scope` first, falling back to the global set.
+    /// Inheritance is resolved through the same layers, so scopes stay self-consistent.
+    fn templates_for(&self, document: &Document, entity_id: &EntityId) -> LayeredTemplates {
+        self.layered_templates(self.template_scope(document, entity_id), &self.templates)
+    }
+    /// An optional scope over `global`, over the base templates.
+    fn layered_templates<'a>(&'a self, scope: Option<&'a HashMap<String, Template>>, global: &'a HashMap<String, Template>) -> LayeredTemplates<'a> {
+        let mut layers = vec![];
+        if let Some(templates) = scope {
+            layers.push(templates);
+        }
+        layers.push(global);
+        layers.push(&self.base_templates);
+        LayeredTemplates { layers: layers }
+    }
+    fn template_scope(&self, document: &Document, entity_id: &EntityId) -> Option<&HashMap<String, Template>> {
+        match document.get_property(entity_id, "template_scope").map(|p| p.concretize()) {
+            Ok(Ok(Pon::String(scope))) => self.scopes.get(&scope),
+            _ => None
+        }
+    }
+    /// The global templates over the host's base templates.
+    fn global_templates(&self) -> LayeredTemplates {
+        LayeredTemplates { layers: vec![&self.templates, &self.base_templates] }
+    }
+    fn load_templates(&mut self, node: &Pon, context: &mut TranslateContext) -> Result<(), TemplateError> {
+        try!(self.check_not_frozen());
+        let directives = try!(node.as_array(|templates| Ok(templates.clone())));
+        for pn in &directives {
+            let (type_name, data) = try!(pn.as_typed(|p| Ok((p.type_name.clone(), p.data.clone()))));
+            match type_name.as_str() {
+                // template '<Rock x="5"/><Granit inherits="Rock"/>', any number of templates
+                "template" => {
+                    let s = try!(data.translate::<String>(context));
+                    let mut warnings = vec![];
+                    let templates = try!(Template::from_string_multi_with_warnings(&s, &self.reader_config, &mut warnings));
+                    self.record_warnings(warnings);
+                    if templates.is_empty() {
+                        return Err(TemplateError::Empty);
+                    }
+                    for template in templates {
+                        self.insert_template(template);
+                    }
+                }
+                "templates_from_file" => {
+                    let filename = try!(data.translate::<String>(context));
+                    let path = self.root_path.join(Path::new(&filename));
+                    try!(self.load_templates_from_file(&path));
+                }
+                // templates_from_files ['rocks.tpml', 'trees.tpml'], parsed in parallel
+                "templates_from_files" => {
+                    let files = try!(data.as_array(|files| Ok(files.clone())));
+                    let mut paths = vec![];
+                    for file in &files {
+                        paths.push(self.root_path.join(Path::new(&try!(file.translate::<String>(context)))));
+                    }
+                    for (type_name, first, later) in try!(self.load_templates_from_files(&paths)) {
+                        self.emit(TemplateEvent::Error { message: format!("{} is defined in both {:?} and {:?}", type_name, first, later) });
+                    }
+                }
+                // templates_inline [Rock { x: 5 }, Granit { inherits: 'Rock', y: 2 }]
+                "templates_inline" => {
+                    let templates = try!(data.as_array(|templates| Ok(templates.clone())));
+                    for pon in &templates {
+                        let template = try!(Template::from_pon(pon));
+                        self.insert_template(template);
+                    }
+                }
+                // templates_from_file_when { file: 'mobile.tpml', flag: 'mobile' }
+                "templates_from_file_when" => {
+                    let (file, flag) = match data {
+                        Pon::Object(ref map) => match (map.get("file"), map.get("flag")) {
+                            (Some(file), Some(flag)) => (try!(file.translate::<String>(context)), try!(flag.translate::<String>(context))),
+                            _ => return Err(TemplateError::Parse("templates_from_file_when needs a file and a flag".to_string()))
+                        },
+                        _ => return Err(TemplateError::Parse("templates_from_file_when expects an object".to_string()))
+                    };
+                    if self.flags.contains(&flag) {
+                        let path = self.root_path.join(Path::new(&file));
+                        try!(self.load_templates_from_file(&path));
+                    }
+                }
+                _ => return Err(From::from(PonTranslateErr::UnrecognizedType(type_name.clone())))
             }
-            Ok(())
-        })
+        }
+        Ok(())
     }
 }
 
+/// The template applied to entities of a type: the type's own, one aliasing the type, or else
+/// the catch-all. A fragment found this way applies nothing, without falling back further.
+fn type_template<'a>(templates: &'a TemplateSource, type_name: &str) -> Option<&'a Template> {
+    let template = match templates.get_template(type_name) {
+        Some(template) => Some(template),
+        None => templates.aliased_template(type_name).or(templates.get_template(CATCH_ALL_TEMPLATE))
+    };
+    template.and_then(|template| match template.kind {
+        TemplateKind::Entity => Some(template),
+        TemplateKind::Fragment => None
+    })
+}
+
+/// The templates an entity lists in its `templates` property, e.g. `templates="['Damageable', 'Renderable']"`,
+/// applied in order after the template of its type; as nothing is overwritten, the first to set a
+/// property wins. The root's `templates` property holds the load directives instead.
+fn component_templates(document: &Document, entity_id: &EntityId) -> Vec<String> {
+    if document.get_root().ok().map(|root| root == entity_id) == Some(true) {
+        return vec![];
+    }
+    match document.get_property(entity_id, "templates").map(|p| p.concretize()) {
+        Ok(Ok(Pon::Array(names))) => names.iter().filter_map(|name| match name {
+            &Pon::String(ref name) => Some(name.clone()),
+            _ => None
+        }).collect(),
+        Ok(Ok(Pon::String(names))) => names.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect(),
+        _ => vec![]
+    }
+}
 
 impl ISubSystem for TemplateSubSystem {
     fn on_document_loaded(&mut self, system: &mut System) {
+        self.applied.clear();
         {
             let doc = system.document_mut();
-            let root = doc.get_root().unwrap().clone();
-            match doc.get_property(&root, "templates") {
-                Ok(templates) => {
-                    self.load_templates(&templates, &mut TranslateContext::empty());
+            // A document without templates is fine; anything going wrong beyond that is recorded
+            let result = match doc.get_root() {
+                Ok(root) => match doc.has_property(root, "templates") {
+                    Ok(false) => Ok(()),
+                    Ok(true) => match doc.get_property(root, "templates") {
+                        Ok(templates) => self.load_templates(&templates.clone(), &mut TranslateContext::empty()),
+                        Err(err) => Err(From::from(err))
+                    },
+                    Err(err) => Err(From::from(err))
                 },
-                _ => {}
+                Err(err) => Err(From::from(err))
+            };
+            if let Err(err) = result {
+                self.emit(TemplateEvent::Error { message: format!("{:?}", err) });
+                self.load_errors.push(err);
             }
         }
-        let entities: Vec<EntityId> = { system.document().entities_iter().map(|x| x.clone()).collect() };
-        for entity in entities {
-            self.on_entity_added(system, &entity);
+        if self.retroactive {
+            self.apply_to_all(system);
         }
-        println!("TEMPLATES {:?}", self.templates);
     }
     fn on_entity_added(&mut self, system: &mut System, entity_id: &EntityId) {
-        let type_name = system.document().get_entity_type_name(entity_id).unwrap().clone();
-        match self.templates.get(&type_name) {
-            Some(template) => {
-                template.apply(&self.templates, system.document_mut(), entity_id);
-            },
-            None => {}
+        let type_name = match system.document().get_entity_type_name(entity_id) {
+            Ok(type_name) => type_name.clone(),
+            Err(err) => {
+                let err: TemplateError = From::from(err);
+                self.emit(TemplateEvent::Error { message: format!("{:?}", err) });
+                self.load_errors.push(err);
+                return;
+            }
+        };
+        let mut applied = vec![];
+        let mut outcome = self.begin_apply();
+        {
+            let templates = self.templates_for(system.document(), entity_id);
+            // What was prepared was resolved against the global templates only
+            let scoped = self.template_scope(system.document(), entity_id).is_some();
+            if let Some(template) = type_template(&templates, &type_name) {
+                if self.first_application(&mut outcome, entity_id, &template.type_name) {
+                    let prepared = if scoped { None } else { self.prepared_for(&mut outcome, &template.type_name) };
+                    if self.apply_and_report(&mut outcome, template, prepared, &templates, system, entity_id).is_ok() {
+                        applied.push(template.type_name.clone());
+                    }
+                }
+            }
+            for name in component_templates(system.document(), entity_id) {
+                match templates.get_template(&name) {
+                    Some(template) => if self.first_application(&mut outcome, entity_id, &template.type_name) {
+                        let prepared = if scoped { None } else { self.prepared_for(&mut outcome, &template.type_name) };
+                        if self.apply_and_report(&mut outcome, template, prepared, &templates, system, entity_id).is_ok() {
+                            applied.push(template.type_name.clone());
+                        }
+                    },
+                    None => self.emit(TemplateEvent::Error { message: format!("{:?}", TemplateError::UnknownTemplate(name)) })
+                }
+            }
+        }
+        {
+            let global = self.global_templates();
+            let mut selected: Vec<&Template> = self.templates.values().filter(|t| t.has_match_rules()).collect();
+            if self.deterministic {
+                selected.sort_by(|a, b| a.type_name.cmp(&b.type_name));
+            }
+            for template in selected {
+                if template.type_name == type_name || template.kind != TemplateKind::Entity { continue; }
+                if !template.matches_entity(system.document(), entity_id) || !self.first_application(&mut outcome, entity_id, &template.type_name) { continue; }
+                let prepared = self.prepared_for(&mut outcome, &template.type_name);
+                if self.apply_and_report(&mut outcome, template, prepared, &global, system, entity_id).is_ok() {
+                    applied.push(template.type_name.clone());
+                }
+            }
+        }
+        self.end_apply(outcome);
+        for type_name in applied {
+            if let Some(callback) = self.applied_callbacks.get_mut(&type_name) {
+                callback(system, entity_id);
+            }
         }
     }
 }
 
+/// What xml-rs's `escape_str` does, for tests of builds without it.
+#[cfg(all(test, not(feature = "xml")))]
+fn escape_str(s: &str) -> String {
+    s.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;").replace("\"", "&quot;").replace("'", "&apos;")
+}
+
 #[test]
 fn test_template() {
     let template = r#"<Rock x="5"/>"#;
-    let doc_src = format!(r#"<Root templates="[template '{}']"><Rock name="tmp" /></Root>"#, xml::escape::escape_str(template));
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Rock name="tmp" /></Root>"#, escape_str(template));
     let doc = Document::from_string(doc_src.as_str()).unwrap();
     let ent = doc.get_entity_by_name("tmp").unwrap();
 
@@ -133,7 +1418,7 @@ fn test_template() {
 fn test_template_inherits() {
     let template1 = r#"<Rock x="5"/>"#;
     let template2 = r#"<Granit inherits="Rock" y="2"/>"#;
-    let doc_src = format!(r#"<Root templates="[template '{}', template '{}']"><Granit name="tmp" /></Root>"#, xml::escape::escape_str(template1), xml::escape::escape_str(template2));
+    let doc_src = format!(r#"<Root templates="[template '{}', template '{}']"><Granit name="tmp" /></Root>"#, escape_str(template1), escape_str(template2));
     let doc = Document::from_string(doc_src.as_str()).unwrap();
     let ent = doc.get_entity_by_name("tmp").unwrap();
 
@@ -144,3 +1429,1505 @@ fn test_template_inherits() {
     assert_eq!(system.document().get_property(&ent, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
     assert_eq!(system.document().get_property(&ent, "y").unwrap().concretize(), Ok(Pon::Integer(2)));
 }
+
+#[test]
+fn test_template_string_with_several_templates() {
+    let templates = r#"<Rock x="5"/><Granit inherits="Rock" y="2"/>"#;
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Granit name="granit" /><Rock name="rock" /></Root>"#, escape_str(templates));
+    let doc = Document::from_string(doc_src.as_str()).unwrap();
+    let granit = doc.get_entity_by_name("granit").unwrap();
+    let rock = doc.get_entity_by_name("rock").unwrap();
+
+    let mut system = pyramid::system::System::new();
+    system.add_subsystem(Box::new(TemplateSubSystem::new(PathBuf::new())));
+    system.set_document(doc);
+
+    assert_eq!(system.document().get_property(&rock, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(system.document().get_property(&granit, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(system.document().get_property(&granit, "y").unwrap().concretize(), Ok(Pon::Integer(2)));
+}
+
+#[test]
+fn test_template_events() {
+    let template = r#"<Rock x="5"/>"#;
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Rock name="tmp" /></Root>"#, escape_str(template));
+    let doc = Document::from_string(doc_src.as_str()).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_event_sender(tx);
+    let mut system = pyramid::system::System::new();
+    system.add_subsystem(Box::new(subsystem));
+    system.set_document(doc);
+
+    let mut events = vec![];
+    while let Ok(event) = rx.try_recv() {
+        events.push(event);
+    }
+    assert_eq!(events, vec![
+        TemplateEvent::Loaded { type_name: "Rock".to_string() },
+        TemplateEvent::Applied { entity_id: ent, type_name: "Rock".to_string() }
+    ]);
+}
+
+#[test]
+fn test_template_migration() {
+    let template = r#"<Creature tpml:version="2" health="10"/>"#;
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Creature name="tmp" version="1" hp="3" /></Root>"#, escape_str(template));
+    let doc = Document::from_string(doc_src.as_str()).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.add_migration("Creature", 1, Box::new(|document: &mut MigrationDocument, entity_id: &EntityId| {
+        let hp = try!(document.get_property(entity_id, "hp")).clone();
+        document.set_property(entity_id, "health", hp)
+    }));
+    let mut system = pyramid::system::System::new();
+    system.add_subsystem(Box::new(subsystem));
+    system.set_document(doc);
+
+    assert_eq!(system.document().get_property(&ent, "health").unwrap().concretize(), Ok(Pon::Integer(3)));
+    assert_eq!(system.document().get_property(&ent, "version").unwrap().concretize(), Ok(Pon::Integer(2)));
+}
+
+#[test]
+fn test_template_migration_on_apply_template() {
+    let doc = Document::from_string(r#"<Root><Beast name="tmp" version="1" hp="3" /></Root>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Creature tpml:version="2" health="10"/>"#).unwrap());
+    subsystem.add_migration("Creature", 1, Box::new(|document: &mut MigrationDocument, entity_id: &EntityId| {
+        let hp = try!(document.get_property(entity_id, "hp")).clone();
+        document.set_property(entity_id, "health", hp)
+    }));
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.apply_template(&mut system, &ent, "Creature").unwrap();
+
+    assert_eq!(system.document().get_property(&ent, "health").unwrap().concretize(), Ok(Pon::Integer(3)));
+    assert_eq!(system.document().get_property(&ent, "version").unwrap().concretize(), Ok(Pon::Integer(2)));
+}
+
+#[test]
+fn test_transactional_apply_rolls_back_migration() {
+    let doc = Document::from_string(r#"<Root><Creature name="tmp" version="1" hp="3" /></Root>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_transactional(true);
+    subsystem.insert_template(Template::from_string(r#"<Creature tpml:version="2" tpml:required="armor" health="10"/>"#).unwrap());
+    subsystem.add_migration("Creature", 1, Box::new(|document: &mut MigrationDocument, entity_id: &EntityId| {
+        let hp = try!(document.get_property(entity_id, "hp")).clone();
+        try!(document.remove_property(entity_id, "hp"));
+        document.set_property(entity_id, "health", hp)
+    }));
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    assert_eq!(subsystem.apply_template(&mut system, &ent, "Creature"),
+        Err(TemplateError::MissingProperties("Creature".to_string(), vec!["armor".to_string()])));
+    // The migration is undone with the rest of the apply
+    assert!(!system.document().has_property(&ent, "health").unwrap());
+    assert_eq!(system.document().get_property(&ent, "hp").unwrap().concretize(), Ok(Pon::Integer(3)));
+    assert_eq!(system.document().get_property(&ent, "version").unwrap().concretize(), Ok(Pon::Integer(1)));
+}
+
+#[test]
+fn test_template_selector() {
+    let template = r#"<Physical tpml:selector="physical=true" mass="1"/>"#;
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Rock name="a" physical="true" /><Rock name="b" /></Root>"#, escape_str(template));
+    let doc = Document::from_string(doc_src.as_str()).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+
+    let mut system = pyramid::system::System::new();
+    system.add_subsystem(Box::new(TemplateSubSystem::new(PathBuf::new())));
+    system.set_document(doc);
+
+    assert_eq!(system.document().get_property(&a, "mass").unwrap().concretize(), Ok(Pon::Integer(1)));
+    assert_eq!(system.document().has_property(&b, "mass"), Ok(false));
+}
+
+#[test]
+fn test_template_match_has() {
+    let template = r#"<Body tpml:match-has="rigidbody, collider" simulated="true"/>"#;
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Crate name="a" rigidbody="1.5" collider="'box'" /><Barrel name="b" rigidbody="2.0" /><Crate name="c" /></Root>"#, escape_str(template));
+    let doc = Document::from_string(doc_src.as_str()).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let c = doc.get_entity_by_name("c").unwrap();
+
+    let mut system = pyramid::system::System::new();
+    system.add_subsystem(Box::new(TemplateSubSystem::new(PathBuf::new())));
+    system.set_document(doc);
+
+    assert_eq!(system.document().get_property(&a, "simulated").unwrap().concretize(), Ok(Pon::Boolean(true)));
+    assert_eq!(system.document().has_property(&b, "simulated"), Ok(false));
+    assert_eq!(system.document().has_property(&c, "simulated"), Ok(false));
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn test_load_templates_from_url() {
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        for (status, body) in vec![("200 OK", r#"<Tpml><Rock x="5"/></Tpml>"#), ("404 Not Found", "")] {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            stream.read(&mut request).unwrap();
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body).unwrap();
+        }
+    });
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.load_templates_from_url(&format!("http://{}/rocks.tpml", address)).unwrap();
+    assert!(subsystem.templates.contains_key("Rock"));
+    let missing = format!("http://{}/missing.tpml", address);
+    assert_eq!(subsystem.load_templates_from_url(&missing), Err(TemplateError::HttpStatus(missing.clone(), 404)));
+    server.join().unwrap();
+    match subsystem.load_templates_from_url(&format!("http://{}/rocks.tpml", address)) {
+        Err(TemplateError::Http(_)) => {}
+        result => panic!("Expected a network error, got {:?}", result)
+    }
+}
+
+#[test]
+fn test_load_templates_from_archive() {
+    use std::io::Write;
+
+    let mut buffer = std::io::Cursor::new(Vec::new());
+    {
+        let mut writer = zip::ZipWriter::new(&mut buffer);
+        writer.start_file("rocks.tpml", zip::CompressionMethod::Stored).unwrap();
+        writer.write_all(br#"<Tpml><Rock x="5"/><Oak x="1" y="1"/></Tpml>"#).unwrap();
+        writer.start_file("trees/oak.tpml", zip::CompressionMethod::Stored).unwrap();
+        writer.write_all(br#"<Tpml><Oak tpml:extends-file="../rocks.tpml" y="2"/></Tpml>"#).unwrap();
+        writer.start_file("readme.txt", zip::CompressionMethod::Stored).unwrap();
+        writer.write_all(b"not a template").unwrap();
+        writer.finish().unwrap();
+    }
+    buffer.set_position(0);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.load_templates_from_archive_reader(buffer).unwrap();
+
+    assert_eq!(subsystem.templates.len(), 2);
+    assert!(subsystem.templates.contains_key("Rock"));
+    assert_eq!(subsystem.template_property("Oak", "x"), Some(&Pon::Integer(1)));
+    assert_eq!(subsystem.template_property("Oak", "y"), Some(&Pon::Integer(2)));
+}
+
+#[test]
+fn test_load_templates_from_archive_file() {
+    use std::io::Write;
+
+    let dir = test_dir("archive_file");
+    let path = dir.join("assets.zip");
+    {
+        let mut writer = zip::ZipWriter::new(File::create(&path).unwrap());
+        writer.start_file("rocks.tpml", zip::CompressionMethod::Stored).unwrap();
+        writer.write_all(br#"<Tpml><Oak x="1" y="1"/></Tpml>"#).unwrap();
+        writer.start_file("trees/oak.tpml", zip::CompressionMethod::Stored).unwrap();
+        writer.write_all(br#"<Tpml><Oak tpml:extends-file="../rocks.tpml" y="2"/></Tpml>"#).unwrap();
+        writer.finish().unwrap();
+    }
+
+    // A path through the archive works wherever a file does, here as an include
+    let mut subsystem = TemplateSubSystem::new(dir.clone());
+    subsystem.load_templates(&Pon::from_string("[templates_from_file 'assets.zip/trees/oak.tpml']").unwrap(), &mut TranslateContext::empty()).unwrap();
+    assert_eq!(subsystem.template_property("Oak", "x"), Some(&Pon::Integer(1)));
+    assert_eq!(subsystem.template_property("Oak", "y"), Some(&Pon::Integer(2)));
+    assert_eq!(subsystem.file_templates[&path.join("trees/oak.tpml")], vec!["Oak".to_string()]);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.load_templates_from_archive(&path).unwrap();
+    assert_eq!(subsystem.source_files, vec![path.join("rocks.tpml"), path.join("trees/oak.tpml")]);
+    assert_eq!(subsystem.template_property("Oak", "y"), Some(&Pon::Integer(2)));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_resolve_template() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5"><Moss /></Rock>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Granit inherits="Rock" y="2"/>"#).unwrap());
+
+    let resolved = subsystem.resolve_template("Granit").unwrap();
+
+    assert_eq!(resolved.type_name, "Granit".to_string());
+    assert_eq!(resolved.inherits, None);
+    assert_eq!(resolved.properties, vec![("x".to_string(), Pon::Integer(5)), ("y".to_string(), Pon::Integer(2))]);
+    assert_eq!(resolved.children.len(), 1);
+    assert_eq!(subsystem.resolve_template("Marble"), None);
+}
+
+#[test]
+fn test_template_fragment() {
+    let template1 = r#"<Heavy tpml:kind="fragment" mass="10"/>"#;
+    let template2 = r#"<Rock inherits="Heavy" x="5"/>"#;
+    let doc_src = format!(r#"<Root templates="[template '{}', template '{}']"><Heavy name="heavy" /><Rock name="rock" /></Root>"#, escape_str(template1), escape_str(template2));
+    let doc = Document::from_string(doc_src.as_str()).unwrap();
+    let heavy = doc.get_entity_by_name("heavy").unwrap();
+    let rock = doc.get_entity_by_name("rock").unwrap();
+
+    let mut system = pyramid::system::System::new();
+    system.add_subsystem(Box::new(TemplateSubSystem::new(PathBuf::new())));
+    system.set_document(doc);
+
+    assert_eq!(system.document().has_property(&heavy, "mass"), Ok(false));
+    assert_eq!(system.document().get_property(&rock, "mass").unwrap().concretize(), Ok(Pon::Integer(10)));
+}
+
+#[test]
+fn test_template_stats() {
+    let template = r#"<Rock x="5" y="1"><Moss /></Rock>"#;
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Rock name="a" y="2" /><Rock name="b" /></Root>"#, escape_str(template));
+    let mut system = pyramid::system::System::new();
+    system.set_document(Document::from_string(doc_src.as_str()).unwrap());
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.on_document_loaded(&mut system);
+
+    assert_eq!(subsystem.stats(), TemplateStats {
+        applies: 4,
+        properties_set: 3,
+        properties_skipped: 1,
+        children_spawned: 2
+    });
+}
+
+#[test]
+fn test_template_scopes() {
+    let doc = Document::from_string(r#"<Root><Rock name="a" template_scope="'modA'" /><Rock name="b" template_scope="'modB'" /><Rock name="c" /></Root>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let c = doc.get_entity_by_name("c").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.load_templates_scoped("modA", &Pon::from_string(r#"[template '<Rock x="1"/>']"#).unwrap()).unwrap();
+    subsystem.load_templates_scoped("modB", &Pon::from_string(r#"[template '<Rock x="2"/>']"#).unwrap()).unwrap();
+    subsystem.on_document_loaded(&mut system);
+
+    assert_eq!(system.document().get_property(&a, "x").unwrap().concretize(), Ok(Pon::Integer(1)));
+    assert_eq!(system.document().get_property(&b, "x").unwrap().concretize(), Ok(Pon::Integer(2)));
+    assert_eq!(system.document().has_property(&c, "x"), Ok(false));
+}
+
+#[test]
+fn test_malformed_inline_template_does_not_panic() {
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Rock name="tmp" /></Root>"#, escape_str(r#"<Rock x="{ a: "/>"#));
+    let mut system = pyramid::system::System::new();
+    system.set_document(Document::from_string(doc_src.as_str()).unwrap());
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_event_sender(tx);
+    subsystem.on_document_loaded(&mut system);
+
+    match rx.try_recv() {
+        Ok(TemplateEvent::Error { .. }) => {}
+        event => panic!("Expected an error event, got {:?}", event)
+    }
+}
+
+#[test]
+fn test_template_on_applied() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let template = r#"<Rigidbody mass="1"/>"#;
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Rigidbody name="a" /><Rock name="b" /></Root>"#, escape_str(template));
+    let doc = Document::from_string(doc_src.as_str()).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let registered = Rc::new(RefCell::new(vec![]));
+    let callback_registered = registered.clone();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.on_applied("Rigidbody", Box::new(move |_: &mut System, entity_id: &EntityId| {
+        callback_registered.borrow_mut().push(*entity_id);
+    }));
+    subsystem.on_document_loaded(&mut system);
+
+    assert_eq!(*registered.borrow(), vec![a]);
+}
+
+#[test]
+fn test_apply_template_explicitly() {
+    let doc = Document::from_string(r#"<Root><Stone name="tmp" /></Root>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Glowing light="3"/>"#).unwrap());
+
+    subsystem.apply_template(&mut system, &ent, "Glowing").unwrap();
+    assert_eq!(system.document().get_property(&ent, "light").unwrap().concretize(), Ok(Pon::Integer(3)));
+    assert_eq!(subsystem.apply_template(&mut system, &ent, "Missing"), Err(TemplateError::UnknownTemplate("Missing".to_string())));
+}
+
+#[test]
+fn test_template_scoped_inherits() {
+    let doc = Document::from_string(r#"<Root><Granit name="a" template_scope="'modA'" /><Granit name="b" template_scope="'modB'" /></Root>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.load_templates(&Pon::from_string(r#"[template '<Rock x="0" y="0"/>', template '<Granit inherits="Rock"/>']"#).unwrap(), &mut TranslateContext::empty()).unwrap();
+    subsystem.load_templates_scoped("modA", &Pon::from_string(r#"[template '<Rock x="1"/>', template '<Granit inherits="Rock"/>']"#).unwrap()).unwrap();
+    subsystem.load_templates_scoped("modB", &Pon::from_string(r#"[template '<Rock x="2"/>']"#).unwrap()).unwrap();
+    subsystem.on_document_loaded(&mut system);
+
+    assert_eq!(system.document().get_property(&a, "x").unwrap().concretize(), Ok(Pon::Integer(1)));
+    assert_eq!(system.document().has_property(&a, "y"), Ok(false));
+    // modB has no Granit, so the global one applies, but its base still resolves within modB first
+    assert_eq!(system.document().get_property(&b, "x").unwrap().concretize(), Ok(Pon::Integer(2)));
+}
+
+#[test]
+fn test_conditional_templates_from_file() {
+    use std::io::Write;
+
+    let root_path = test_dir("conditional");
+    File::create(root_path.join("mobile.tpml")).unwrap().write_all(br#"<Tpml><Button size="2"/></Tpml>"#).unwrap();
+    let directives = Pon::from_string("[templates_from_file_when { file: 'mobile.tpml', flag: 'mobile' }]").unwrap();
+
+    let mut desktop = TemplateSubSystem::new(root_path.clone());
+    desktop.load_templates(&directives, &mut TranslateContext::empty()).unwrap();
+    assert!(!desktop.templates.contains_key("Button"));
+
+    let mut mobile = TemplateSubSystem::new(root_path.clone());
+    let mut flags = HashSet::new();
+    flags.insert("mobile".to_string());
+    mobile.set_flags(flags);
+    mobile.load_templates(&directives, &mut TranslateContext::empty()).unwrap();
+    assert!(mobile.templates.contains_key("Button"));
+    fs::remove_dir_all(&root_path).unwrap();
+}
+
+#[test]
+fn test_effective_properties() {
+    let doc = Document::from_string(r#"<Root><Granit name="tmp" x="7" /></Root>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5" z="1"/>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Granit inherits="Rock" y="2"/>"#).unwrap());
+
+    let properties = subsystem.effective_properties(&system, &ent);
+
+    assert_eq!(properties.len(), 3);
+    assert_eq!(properties[0].0, "x".to_string());
+    assert_eq!(properties[0].1.concretize(), Ok(Pon::Integer(7)));
+    assert_eq!(properties[1], ("z".to_string(), Pon::Integer(1)));
+    assert_eq!(properties[2], ("y".to_string(), Pon::Integer(2)));
+}
+
+#[test]
+fn test_inheritance_chain() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5"/>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Granit inherits="Rock"/>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<PolishedGranit inherits="Granit"/>"#).unwrap());
+
+    assert_eq!(subsystem.inheritance_chain("PolishedGranit"), vec!["PolishedGranit".to_string(), "Granit".to_string(), "Rock".to_string()]);
+    assert_eq!(subsystem.inheritance_chain("Marble"), Vec::<String>::new());
+}
+
+#[test]
+fn test_set_type_mapper() {
+    let template = r#"<Car><Wheel /></Car>"#;
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Car name="tmp" /></Root>"#, escape_str(template));
+    let doc = Document::from_string(doc_src.as_str()).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_type_mapper(Box::new(|type_name: &str| format!("Physics{}", type_name)));
+    let mut system = pyramid::system::System::new();
+    system.add_subsystem(Box::new(subsystem));
+    system.set_document(doc);
+
+    let children = system.document().get_children(&ent).unwrap().clone();
+    assert_eq!(system.document().get_entity_type_name(&children[0]).unwrap().clone(), "PhysicsWheel".to_string());
+}
+
+#[test]
+fn test_deterministic_application_order() {
+    let template = r#"<Rock x="5"/>"#;
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Rock name="a" /><Rock name="b" /><Rock name="c" /><Rock name="d" /></Root>"#, escape_str(template));
+    let run = || {
+        let mut system = pyramid::system::System::new();
+        system.set_document(Document::from_string(doc_src.as_str()).unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+        subsystem.set_deterministic(true);
+        subsystem.set_event_sender(tx);
+        subsystem.on_document_loaded(&mut system);
+        let mut applied = vec![];
+        while let Ok(event) = rx.try_recv() {
+            if let TemplateEvent::Applied { entity_id, .. } = event {
+                applied.push(entity_id);
+            }
+        }
+        applied
+    };
+
+    let first = run();
+    let mut sorted = first.clone();
+    sorted.sort();
+    assert_eq!(first.len(), 4);
+    assert_eq!(first, sorted);
+    for _ in 0..5 {
+        assert_eq!(run(), first);
+    }
+}
+
+#[test]
+fn test_template_cache() {
+    use std::io::Write;
+
+    let root_path = test_dir("cache");
+    File::create(root_path.join("rocks.tpml")).unwrap().write_all(br#"<Tpml><Rock x="5"><Moss /></Rock><Granit inherits="Rock" y="2"/></Tpml>"#).unwrap();
+    let cache_path = root_path.join("templates.cache");
+
+    let mut subsystem = TemplateSubSystem::new(root_path.clone());
+    subsystem.load_templates(&Pon::from_string("[templates_from_file 'rocks.tpml']").unwrap(), &mut TranslateContext::empty()).unwrap();
+    subsystem.save_cache(&cache_path).unwrap();
+
+    let mut cached = TemplateSubSystem::new(root_path.clone());
+    assert_eq!(cached.load_cache(&cache_path), Ok(true));
+    assert_eq!(cached.templates, subsystem.templates);
+    assert_eq!(cached.source_files, subsystem.source_files);
+    assert_eq!(cached.file_templates, subsystem.file_templates);
+    assert_eq!(TemplateSubSystem::new(root_path.clone()).load_cache(&root_path.join("missing.cache")), Ok(false));
+    // A truncated cache is ignored, not an error
+    let truncated_path = root_path.join("truncated.cache");
+    let mut bytes = vec![];
+    File::open(&cache_path).unwrap().read_to_end(&mut bytes).unwrap();
+    File::create(&truncated_path).unwrap().write_all(&bytes[..bytes.len() / 2]).unwrap();
+    let mut truncated = TemplateSubSystem::new(root_path.clone());
+    assert_eq!(truncated.load_cache(&truncated_path), Ok(false));
+    assert!(truncated.templates.is_empty());
+
+    // Rewritten until the file system can tell it's newer than the cache
+    let cache_modified = fs::metadata(&cache_path).unwrap().modified().unwrap();
+    loop {
+        File::create(root_path.join("rocks.tpml")).unwrap().write_all(br#"<Tpml><Rock x="6" /></Tpml>"#).unwrap();
+        if fs::metadata(root_path.join("rocks.tpml")).unwrap().modified().unwrap() > cache_modified {
+            break;
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_eq!(TemplateSubSystem::new(root_path.clone()).load_cache(&cache_path), Ok(false));
+    fs::remove_dir_all(&root_path).unwrap();
+}
+
+#[test]
+fn test_templates_inline() {
+    let doc = Document::from_string(r#"<Root templates="[templates_inline [Rock { x: 5 }, Granit { inherits: 'Rock', y: 2 }]]"><Granit name="tmp" /></Root>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    let mut system = pyramid::system::System::new();
+    system.add_subsystem(Box::new(TemplateSubSystem::new(PathBuf::new())));
+    system.set_document(doc);
+
+    assert_eq!(system.document().get_property(&ent, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(system.document().get_property(&ent, "y").unwrap().concretize(), Ok(Pon::Integer(2)));
+}
+
+#[test]
+fn test_base_templates() {
+    let template = r#"<Granit inherits="Rock" y="2"/>"#;
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Granit name="a" /><Rock name="b" /></Root>"#, escape_str(template));
+    let doc = Document::from_string(doc_src.as_str()).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let mut bases = HashMap::new();
+    bases.insert("Rock".to_string(), Template::from_string(r#"<Rock x="5" y="1"/>"#).unwrap());
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_base_templates(bases).unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+
+    assert_eq!(system.document().get_property(&a, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(system.document().get_property(&a, "y").unwrap().concretize(), Ok(Pon::Integer(1)));
+    assert_eq!(system.document().get_property(&b, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(subsystem.inheritance_chain("Granit"), vec!["Granit".to_string(), "Rock".to_string()]);
+}
+
+#[test]
+fn test_abstract_properties() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    for template in Template::from_string_multi(r#"<Vehicle tpml:kind="fragment" tpml:abstract-property="wheels, mesh" speed="1" /><Land tpml:kind="fragment" inherits="Vehicle" wheels="4" /><Car inherits="Land" mesh="'car'" /><Cart inherits="Land" /><Boat inherits="Vehicle" mesh="@name" />"#).unwrap() {
+        subsystem.insert_template(template);
+    }
+    assert_eq!(subsystem.validate_abstract_properties(), vec![
+        TemplateError::MissingOverride("Boat".to_string(), "wheels".to_string(), "Vehicle".to_string()),
+        TemplateError::MissingOverride("Cart".to_string(), "mesh".to_string(), "Vehicle".to_string())
+    ]);
+}
+
+#[test]
+fn test_snapshot_restore() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.add_template(Template::from_string(r#"<Rock x="5"/>"#).unwrap()).unwrap();
+    subsystem.load_templates_scoped("mod", &Pon::from_string(r#"[template '<Tree y="1"/>']"#).unwrap()).unwrap();
+    let snapshot = subsystem.snapshot();
+
+    subsystem.add_template(Template::from_string(r#"<Rock x="7"/>"#).unwrap()).unwrap();
+    subsystem.add_template(Template::from_string(r#"<Granit inherits="Rock"/>"#).unwrap()).unwrap();
+    subsystem.load_templates_scoped("mod", &Pon::from_string(r#"[template '<Bush/>']"#).unwrap()).unwrap();
+    subsystem.restore(snapshot.clone()).unwrap();
+
+    assert_eq!(subsystem.template_property("Rock", "x"), Some(&Pon::Integer(5)));
+    assert!(!subsystem.has_template_for("Granit"));
+    assert_eq!(subsystem.snapshot(), snapshot);
+    subsystem.freeze();
+    assert_eq!(subsystem.restore(snapshot), Err(TemplateError::Frozen));
+}
+
+#[test]
+fn test_templates_matching() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    for template in Template::from_string_multi(r#"<Rock x="5"/><Granit inherits="Rock" y="2"/><Marble x="1" tpml:tags="shiny"/>"#).unwrap() {
+        subsystem.insert_template(template);
+    }
+    let mut with_x: Vec<&str> = subsystem.templates_matching(|t| t.properties.iter().any(|p| p.0 == "x")).map(|t| t.type_name.as_str()).collect();
+    with_x.sort();
+    assert_eq!(with_x, vec!["Marble", "Rock"]);
+    assert_eq!(subsystem.templates_matching(|t| t.tags.contains(&"shiny".to_string())).count(), 1);
+}
+
+#[test]
+fn test_dead_properties() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5" y="1"/>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Granit inherits="Rock" x="7" z="2"/>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Marble inherits="Rock" tpml:replace="true" x="9"/>"#).unwrap());
+    assert_eq!(subsystem.dead_properties(), vec![("Granit".to_string(), "x".to_string(), "Rock".to_string())]);
+}
+
+#[test]
+fn test_unused_templates() {
+    let doc = Document::from_string(r#"<Root><Granit name="tmp" /></Root>"#).unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5"><Moss /></Rock>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Granit inherits="Rock"/>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Moss y="1"/>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Marble z="2"/>"#).unwrap());
+
+    assert_eq!(subsystem.unused_templates(&system), vec!["Marble".to_string()]);
+}
+
+#[test]
+fn test_refired_entity_added() {
+    let doc = Document::from_string(r#"<Root><Tank name="tank" /></Root>"#).unwrap();
+    let tank = doc.get_entity_by_name("tank").unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Tank><Turret mounted="true" /></Tank>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Turret armor="1"><Barrel /></Turret>"#).unwrap());
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+    let turret = system.document().get_children(&tank).unwrap()[0];
+
+    // The host fires for the spawned child, then again for both
+    subsystem.on_entity_added(&mut system, &turret);
+    subsystem.on_entity_added(&mut system, &turret);
+    subsystem.on_entity_added(&mut system, &tank);
+
+    assert_eq!(system.document().get_children(&tank).unwrap().len(), 1);
+    assert_eq!(system.document().get_children(&turret).unwrap().len(), 1);
+    assert_eq!(system.document().get_property(&turret, "mounted").unwrap().concretize(), Ok(Pon::Boolean(true)));
+    assert_eq!(system.document().get_property(&turret, "armor").unwrap().concretize(), Ok(Pon::Integer(1)));
+}
+
+#[test]
+fn test_apply_to_subtree() {
+    let doc = Document::from_string(r#"<Root><Other name="other" /><Rock name="rock"><Moss name="moss" /></Rock></Root>"#).unwrap();
+    let other = doc.get_entity_by_name("other").unwrap();
+    let rock = doc.get_entity_by_name("rock").unwrap();
+    let moss = doc.get_entity_by_name("moss").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5"/>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Moss y="1"/>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Other z="2"/>"#).unwrap());
+    subsystem.apply_to_subtree(&mut system, &rock);
+
+    assert_eq!(system.document().get_property(&rock, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(system.document().get_property(&moss, "y").unwrap().concretize(), Ok(Pon::Integer(1)));
+    assert_eq!(system.document().has_property(&other, "z"), Ok(false));
+}
+
+#[test]
+fn test_load_policy_replace() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.load_templates(&Pon::from_string(r#"[template '<Rock x="5" y="1"/>', template '<Rock x="7"/>']"#).unwrap(), &mut TranslateContext::empty()).unwrap();
+
+    assert_eq!(subsystem.templates["Rock"].properties, vec![("x".to_string(), Pon::Integer(7))]);
+}
+
+#[test]
+fn test_load_policy_merge() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_load_policy(LoadPolicy::Merge);
+    subsystem.load_templates(&Pon::from_string(r#"[template '<Rock x="5" y="1"/>', template '<Rock x="7" z="2"/>']"#).unwrap(), &mut TranslateContext::empty()).unwrap();
+
+    assert_eq!(subsystem.templates["Rock"].properties, vec![
+        ("x".to_string(), Pon::Integer(7)),
+        ("y".to_string(), Pon::Integer(1)),
+        ("z".to_string(), Pon::Integer(2))
+    ]);
+}
+
+#[test]
+fn test_defer_children() {
+    let template = r#"<Forest><Tree><Leaf /></Tree></Forest>"#;
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Forest name="tmp" /></Root>"#, escape_str(template));
+    let doc = Document::from_string(doc_src.as_str()).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_defer_children(true);
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+    assert_eq!(system.document().get_children(&ent).unwrap().len(), 0);
+
+    subsystem.flush_deferred(&mut system).unwrap();
+    let children = system.document().get_children(&ent).unwrap().clone();
+    assert_eq!(children.len(), 1);
+    assert_eq!(system.document().get_children(&children[0]).unwrap().len(), 1);
+}
+
+#[test]
+fn test_diff_entity() {
+    let doc = Document::from_string(r#"<Root><Granit name="tmp" x="7" y="2" /></Root>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5" z="1"/>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Granit inherits="Rock" y="2"/>"#).unwrap());
+
+    let diffs = subsystem.diff_entity(&system, &ent);
+
+    assert_eq!(diffs.len(), 2);
+    match diffs[0] {
+        PropertyDiff::Overridden { ref key, ref template, ref instance } => {
+            assert_eq!(key, "x");
+            assert_eq!(template, &Pon::Integer(5));
+            assert_eq!(instance.concretize(), Ok(Pon::Integer(7)));
+        }
+        ref diff => panic!("Unexpected diff {:?}", diff)
+    }
+    assert_eq!(diffs[1], PropertyDiff::Missing { key: "z".to_string(), template: Pon::Integer(1) });
+}
+
+#[test]
+fn test_strict_mixins() {
+    let doc = Document::from_string(r#"<Root><Lamp name="tmp" /></Root>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_strict_mixins(true);
+    subsystem.insert_template(Template::from_string(r#"<Glow intensity="1" />"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Flicker intensity="2" />"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Lamp tpml:mixins="Glow, Flicker" />"#).unwrap());
+
+    assert_eq!(subsystem.apply_template(&mut system, &ent, "Lamp"),
+        Err(TemplateError::MixinConflict("intensity".to_string(), "Glow".to_string(), "Flicker".to_string())));
+    assert!(!system.document().has_property(&ent, "intensity").unwrap());
+}
+
+#[test]
+fn test_reload_incremental() {
+    use std::io::Write;
+
+    let dir = test_dir("reload_incremental");
+    let path = dir.join("templates.tpml");
+    File::create(&path).unwrap().write_all(br#"<Tpml><Rock x="5" /><Granit inherits="Rock" /><Tree y="1" /></Tpml>"#).unwrap();
+    let doc = Document::from_string(r#"<Root><Granit name="granit" /><Rock name="rock" x="7" /><Tree name="tree" /></Root>"#).unwrap();
+    let granit = doc.get_entity_by_name("granit").unwrap();
+    let rock = doc.get_entity_by_name("rock").unwrap();
+    let tree = doc.get_entity_by_name("tree").unwrap();
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.load_templates_from_file(&path).unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+
+    File::create(&path).unwrap().write_all(br#"<Tpml><Rock x="6" /><Granit inherits="Rock" /><Tree y="1" /></Tpml>"#).unwrap();
+    let reapplied = subsystem.reload_incremental(&mut system).unwrap();
+
+    let mut expected = vec![granit, rock];
+    expected.sort();
+    assert_eq!(reapplied, expected);
+    assert_eq!(system.document().get_property(&granit, "x").unwrap().concretize(), Ok(Pon::Integer(6)));
+    // The instance's own value is kept
+    assert_eq!(system.document().get_property(&rock, "x").unwrap().concretize(), Ok(Pon::Integer(7)));
+    assert_eq!(system.document().get_property(&tree, "y").unwrap().concretize(), Ok(Pon::Integer(1)));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_reload_incremental_removes_types() {
+    use std::io::Write;
+
+    let dir = test_dir("reload_incremental_removes");
+    let path = dir.join("templates.tpml");
+    File::create(&path).unwrap().write_all(br#"<Tpml><Rock x="5" /><Tree y="1" /></Tpml>"#).unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.load_templates_from_file(&path).unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(Document::from_string(r#"<Root />"#).unwrap());
+    subsystem.on_document_loaded(&mut system);
+
+    File::create(&path).unwrap().write_all(br#"<Tpml><Rock x="6" /></Tpml>"#).unwrap();
+    subsystem.reload_incremental(&mut system).unwrap();
+    assert_eq!(subsystem.template_property("Rock", "x"), Some(&Pon::Integer(6)));
+    assert!(!subsystem.templates.contains_key("Tree"));
+
+    // A file that doesn't parse leaves everything as it was
+    File::create(&path).unwrap().write_all(br#"<Tpml><Rock x="7" </Tpml>"#).unwrap();
+    assert!(subsystem.reload_incremental(&mut system).is_err());
+    assert_eq!(subsystem.template_property("Rock", "x"), Some(&Pon::Integer(6)));
+    assert_eq!(subsystem.file_templates[&path], vec!["Rock".to_string()]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_non_retroactive() {
+    let template = r#"<Rock x="5"/>"#;
+    let doc_src = format!(r#"<Root templates="[template '{}']"><Rock name="existing" /></Root>"#, escape_str(template));
+    let doc = Document::from_string(doc_src.as_str()).unwrap();
+    let existing = doc.get_entity_by_name("existing").unwrap();
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_retroactive(false);
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+    assert!(!system.document().has_property(&existing, "x").unwrap());
+
+    let root = system.document().get_root().unwrap().clone();
+    let added = system.document_mut().append_entity(Some(root), "Rock", None).unwrap();
+    subsystem.on_entity_added(&mut system, &added);
+    assert_eq!(system.document().get_property(&added, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+}
+
+#[test]
+fn test_dependency_graph() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5"/>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Mossy tpml:kind="fragment"><Moss /></Mossy>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Granit inherits="Rock" tpml:mixins="Mossy"/>"#).unwrap());
+
+    assert_eq!(subsystem.dependency_graph(), vec![
+        ("Granit".to_string(), "Mossy".to_string(), EdgeKind::Mixin),
+        ("Granit".to_string(), "Rock".to_string(), EdgeKind::Inherits),
+        ("Mossy".to_string(), "Moss".to_string(), EdgeKind::ChildType)
+    ]);
+}
+
+#[test]
+fn test_dependency_graph_includes() {
+    use std::io::Write;
+
+    let root_path = test_dir("dependency_graph_includes");
+    let base = root_path.join("base.tpml");
+    let rocks = root_path.join("rocks.tpml");
+    File::create(&base).unwrap().write_all(br#"<Tpml><Rock x="5" /></Tpml>"#).unwrap();
+    File::create(&rocks).unwrap().write_all(br#"<Tpml><Rock tpml:extends-file="base.tpml" y="1" /><Granit inherits="Rock" /></Tpml>"#).unwrap();
+    let mut subsystem = TemplateSubSystem::new(root_path.clone());
+    subsystem.load_templates_from_file(&rocks).unwrap();
+
+    let rocks_name = rocks.display().to_string();
+    assert_eq!(subsystem.dependency_graph(), vec![
+        (rocks_name.clone(), "Granit".to_string(), EdgeKind::Include),
+        (rocks_name, "Rock".to_string(), EdgeKind::Include),
+        ("Granit".to_string(), "Rock".to_string(), EdgeKind::Inherits),
+        ("Rock".to_string(), base.display().to_string(), EdgeKind::Include)
+    ]);
+    fs::remove_dir_all(&root_path).unwrap();
+}
+
+#[test]
+fn test_document_without_templates() {
+    let doc = Document::from_string(r#"<Root><Rock name="tmp" /></Root>"#).unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    subsystem.on_document_loaded(&mut system);
+
+    assert_eq!(subsystem.load_errors().len(), 0);
+}
+
+#[test]
+fn test_duplicate_attribute_warning() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.load_templates(&Pon::from_string(r#"[template '<Rock x="5" x="7"/>']"#).unwrap(), &mut TranslateContext::empty()).unwrap();
+
+    assert_eq!(subsystem.template_property("Rock", "x"), Some(&Pon::Integer(7)));
+    assert_eq!(subsystem.load_errors(), &vec![TemplateError::DuplicateAttribute("x".to_string(), "Rock".to_string())]);
+}
+
+#[test]
+fn test_document_with_malformed_templates() {
+    let doc = Document::from_string(r#"<Root templates="5"><Rock name="tmp" /></Root>"#).unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_event_sender(tx);
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    subsystem.on_document_loaded(&mut system);
+
+    assert_eq!(subsystem.load_errors().len(), 1);
+    match rx.try_recv() {
+        Ok(TemplateEvent::Error { .. }) => {}
+        event => panic!("Expected an error event, got {:?}", event)
+    }
+}
+
+#[test]
+fn test_allowed_keys() {
+    let doc = Document::from_string(r#"<Root><User name="tmp" /></Root>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_event_sender(tx);
+    let mut allowed_keys = HashSet::new();
+    allowed_keys.insert("x".to_string());
+    subsystem.set_allowed_keys(allowed_keys);
+    subsystem.insert_template(Template::from_string(r#"<User x="5" admin="true" />"#).unwrap());
+    subsystem.on_entity_added(&mut system, &ent);
+
+    assert!(!system.document().has_property(&ent, "admin").unwrap());
+    let mut events = vec![];
+    while let Ok(event) = rx.try_recv() {
+        events.push(event);
+    }
+    assert!(events.contains(&TemplateEvent::Rejected { entity_id: ent, key: "admin".to_string() }));
+}
+
+#[test]
+fn test_template_property() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5"/>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Granit inherits="Rock" y="2"/>"#).unwrap());
+
+    assert_eq!(subsystem.template_property("Granit", "y"), Some(&Pon::Integer(2)));
+    assert_eq!(subsystem.template_property("Granit", "x"), None);
+    assert_eq!(subsystem.inherited_template_property("Granit", "x"), Some(Pon::Integer(5)));
+    assert_eq!(subsystem.inherited_template_property("Granit", "z"), None);
+    assert_eq!(subsystem.template_property("Marble", "x"), None);
+}
+
+#[test]
+fn test_load_templates_from_files() {
+    use std::io::Write;
+
+    let root_path = test_dir("parallel");
+    let mut paths = vec![];
+    for (i, content) in [r#"<Tpml><Rock x="1"/></Tpml>"#, r#"<Tpml><Tree y="2"/><Bush /></Tpml>"#, r#"<Tpml><Rock x="3"/></Tpml>"#].iter().enumerate() {
+        let path = root_path.join(format!("file{}.tpml", i));
+        File::create(&path).unwrap().write_all(content.as_bytes()).unwrap();
+        paths.push(path);
+    }
+
+    for _ in 0..5 {
+        let mut subsystem = TemplateSubSystem::new(root_path.clone());
+        let conflicts = subsystem.load_templates_from_files(&paths).unwrap();
+
+        assert_eq!(conflicts, vec![("Rock".to_string(), paths[0].clone(), paths[2].clone())]);
+        assert!(subsystem.templates.contains_key("Tree"));
+        assert!(subsystem.templates.contains_key("Bush"));
+        assert_eq!(subsystem.template_property("Rock", "x"), Some(&Pon::Integer(3)));
+    }
+    fs::remove_dir_all(&root_path).unwrap();
+}
+
+#[test]
+fn test_property_interceptor() {
+    let doc = Document::from_string(r#"<Root><Player name="tmp" /></Root>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_property_interceptor(Box::new(|key: &str, value: Pon| match key {
+        "speed" => Some(Pon::Integer(10)),
+        "secret" => None,
+        _ => Some(value)
+    }));
+    subsystem.insert_template(Template::from_string(r#"<Player speed="5" secret="1" />"#).unwrap());
+    subsystem.on_entity_added(&mut system, &ent);
+
+    assert_eq!(system.document().get_property(&ent, "speed").unwrap().concretize(), Ok(Pon::Integer(10)));
+    assert!(!system.document().has_property(&ent, "secret").unwrap());
+}
+
+#[test]
+fn test_catch_all_template() {
+    let doc = Document::from_string(r#"<Root><Rock name="rock" /><Tree name="tree" /></Root>"#).unwrap();
+    let rock = doc.get_entity_by_name("rock").unwrap();
+    let tree = doc.get_entity_by_name("tree").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Default visible="true" />"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5" />"#).unwrap());
+    subsystem.on_entity_added(&mut system, &rock);
+    subsystem.on_entity_added(&mut system, &tree);
+
+    assert_eq!(system.document().get_property(&tree, "visible").unwrap().concretize(), Ok(Pon::Boolean(true)));
+    assert!(!system.document().has_property(&rock, "visible").unwrap());
+}
+
+#[test]
+fn test_reload_file() {
+    use std::io::Write;
+
+    let root_path = test_dir("reload_file");
+    let rocks = root_path.join("rocks.tpml");
+    let trees = root_path.join("trees.tpml");
+    File::create(&rocks).unwrap().write_all(br#"<Tpml><Rock x="5" /></Tpml>"#).unwrap();
+    File::create(&trees).unwrap().write_all(br#"<Tpml><Tree y="1" /></Tpml>"#).unwrap();
+    let doc = Document::from_string(r#"<Root><Rock name="rock" /><Tree name="tree" /></Root>"#).unwrap();
+    let rock = doc.get_entity_by_name("rock").unwrap();
+
+    let mut subsystem = TemplateSubSystem::new(root_path.clone());
+    subsystem.load_templates_from_file(&rocks).unwrap();
+    subsystem.load_templates_from_file(&trees).unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+
+    File::create(&rocks).unwrap().write_all(br#"<Tpml><Rock x="6" /></Tpml>"#).unwrap();
+    // Edited too, but not reloaded
+    File::create(&trees).unwrap().write_all(br#"<Tpml><Tree y="2" /></Tpml>"#).unwrap();
+    let reapplied = subsystem.reload_file(&mut system, &rocks).unwrap();
+
+    assert_eq!(reapplied, vec![rock]);
+    assert_eq!(system.document().get_property(&rock, "x").unwrap().concretize(), Ok(Pon::Integer(6)));
+    assert_eq!(subsystem.template_property("Tree", "y"), Some(&Pon::Integer(1)));
+    fs::remove_dir_all(&root_path).unwrap();
+}
+
+#[test]
+fn test_reload_file_reports_and_uses_base_templates() {
+    use std::io::Write;
+
+    let root_path = test_dir("reload_file_report");
+    let granit = root_path.join("granit.tpml");
+    File::create(&granit).unwrap().write_all(br#"<Tpml><Granit inherits="Rock" y="1" /></Tpml>"#).unwrap();
+    let doc = Document::from_string(r#"<Root><Granit name="a" key="'red'" /><Granit name="b" /></Root>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let mut bases = HashMap::new();
+    bases.insert("Rock".to_string(), Template::from_string(r#"<Rock x="5"/>"#).unwrap());
+
+    let mut subsystem = TemplateSubSystem::new(root_path.clone());
+    subsystem.set_base_templates(bases).unwrap();
+    subsystem.load_templates_from_file(&granit).unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+    assert!(subsystem.take_apply_report().is_empty());
+
+    File::create(&granit).unwrap().write_all(br#"<Tpml><Granit inherits="Rock" y="2" tpml:required="key" /></Tpml>"#).unwrap();
+    let reapplied = subsystem.reload_file(&mut system, &granit).unwrap();
+
+    assert_eq!(reapplied, vec![a, b]);
+    assert_eq!(system.document().get_property(&a, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(system.document().get_property(&a, "y").unwrap().concretize(), Ok(Pon::Integer(2)));
+    assert_eq!(subsystem.take_apply_report(), vec![ApplyIssue {
+        entity_id: b,
+        type_name: "Granit".to_string(),
+        error: TemplateError::MissingProperties("Granit".to_string(), vec!["key".to_string()])
+    }]);
+    fs::remove_dir_all(&root_path).unwrap();
+}
+
+#[test]
+fn test_reload_file_merge_layers() {
+    use std::io::Write;
+    use std::sync::mpsc::channel;
+
+    let root_path = test_dir("reload_file_merge");
+    let base = root_path.join("base.tpml");
+    let overlay = root_path.join("overlay.tpml");
+    File::create(&base).unwrap().write_all(br#"<Tpml><Rock x="5" y="1" /></Tpml>"#).unwrap();
+    File::create(&overlay).unwrap().write_all(br#"<Tpml><Rock y="2" /></Tpml>"#).unwrap();
+    let doc = Document::from_string(r#"<Root><Rock name="rock" /></Root>"#).unwrap();
+    let rock = doc.get_entity_by_name("rock").unwrap();
+
+    let mut subsystem = TemplateSubSystem::new(root_path.clone());
+    subsystem.set_load_policy(LoadPolicy::Merge);
+    subsystem.load_templates_from_file(&base).unwrap();
+    subsystem.load_templates_from_file(&overlay).unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+    let (tx, rx) = channel();
+    subsystem.set_event_sender(tx);
+
+    File::create(&overlay).unwrap().write_all(br#"<Tpml><Rock y="3" /></Tpml>"#).unwrap();
+    subsystem.reload_file(&mut system, &overlay).unwrap();
+
+    // The base file's layer is still there under the reloaded one
+    assert_eq!(subsystem.template_property("Rock", "x"), Some(&Pon::Integer(5)));
+    assert_eq!(subsystem.template_property("Rock", "y"), Some(&Pon::Integer(3)));
+    assert_eq!(system.document().get_property(&rock, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(system.document().get_property(&rock, "y").unwrap().concretize(), Ok(Pon::Integer(3)));
+    let mut loaded = vec![];
+    while let Ok(event) = rx.try_recv() {
+        if let TemplateEvent::Loaded { .. } = event {
+            loaded.push(event);
+        }
+    }
+    assert_eq!(loaded, vec![TemplateEvent::Loaded { type_name: "Rock".to_string() }, TemplateEvent::Loaded { type_name: "Rock".to_string() }]);
+    fs::remove_dir_all(&root_path).unwrap();
+}
+
+#[test]
+fn test_component_templates() {
+    let doc = Document::from_string(r#"<Root><Crate name="tmp" templates="['Damageable', 'Renderable']" /></Root>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Damageable health="100" layer="1" />"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Renderable mesh="'crate'" layer="2" />"#).unwrap());
+    subsystem.on_entity_added(&mut system, &ent);
+
+    assert_eq!(system.document().get_property(&ent, "health").unwrap().concretize(), Ok(Pon::Integer(100)));
+    assert_eq!(system.document().get_property(&ent, "mesh").unwrap().concretize(), Ok(Pon::String("crate".to_string())));
+    assert_eq!(system.document().get_property(&ent, "layer").unwrap().concretize(), Ok(Pon::Integer(1)));
+}
+
+#[test]
+fn test_freeze() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.add_template(Template::from_string(r#"<Rock x="5"/>"#).unwrap()).unwrap();
+    subsystem.freeze();
+
+    assert_eq!(subsystem.add_template(Template::from_string(r#"<Tree y="1"/>"#).unwrap()), Err(TemplateError::Frozen));
+    assert_eq!(subsystem.remove_template("Rock"), Err(TemplateError::Frozen));
+    assert_eq!(subsystem.load_templates_scoped("mod", &Pon::from_string("[]").unwrap()), Err(TemplateError::Frozen));
+    assert_eq!(subsystem.set_base_templates(HashMap::new()), Err(TemplateError::Frozen));
+    let mut system = pyramid::system::System::new();
+    system.set_document(Document::from_string(r#"<Root><Rock name="tmp" /></Root>"#).unwrap());
+    assert_eq!(subsystem.reload_incremental(&mut system), Err(TemplateError::Frozen));
+
+    let ent = system.document().get_entity_by_name("tmp").unwrap();
+    subsystem.on_entity_added(&mut system, &ent);
+    assert_eq!(system.document().get_property(&ent, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(subsystem.inheritance_chain("Rock"), vec!["Rock".to_string()]);
+}
+
+#[cfg(test)]
+fn load_mixed_document(root_path: &Path, templates: &str) -> (pyramid::system::System, EntityId) {
+    let doc_src = format!(r#"<Root templates="{}"><Granit name="tmp" /></Root>"#, escape_str(templates));
+    let doc = Document::from_string(doc_src.as_str()).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.add_subsystem(Box::new(TemplateSubSystem::new(root_path.to_path_buf())));
+    system.set_document(doc);
+    (system, ent)
+}
+
+#[test]
+fn test_inline_template_inherits_file_template() {
+    use std::io::Write;
+
+    let root_path = test_dir("inline_inherits_file");
+    File::create(root_path.join("rock.tpml")).unwrap().write_all(br#"<Tpml><Rock x="5"/></Tpml>"#).unwrap();
+    let inline = r#"<Granit inherits="Rock" y="2"/>"#;
+
+    for templates in &[format!("[templates_from_file 'rock.tpml', template '{}']", inline), format!("[template '{}', templates_from_file 'rock.tpml']", inline)] {
+        let (system, ent) = load_mixed_document(&root_path, templates);
+        assert_eq!(system.document().get_property(&ent, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+        assert_eq!(system.document().get_property(&ent, "y").unwrap().concretize(), Ok(Pon::Integer(2)));
+    }
+    fs::remove_dir_all(&root_path).unwrap();
+}
+
+#[test]
+fn test_file_template_inherits_inline_template() {
+    use std::io::Write;
+
+    let root_path = test_dir("file_inherits_inline");
+    File::create(root_path.join("granit.tpml")).unwrap().write_all(br#"<Tpml><Granit inherits="Rock" y="2"/></Tpml>"#).unwrap();
+    let inline = r#"<Rock x="5"/>"#;
+
+    for templates in &[format!("[templates_from_file 'granit.tpml', template '{}']", inline), format!("[template '{}', templates_from_file 'granit.tpml']", inline)] {
+        let (system, ent) = load_mixed_document(&root_path, templates);
+        assert_eq!(system.document().get_property(&ent, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+        assert_eq!(system.document().get_property(&ent, "y").unwrap().concretize(), Ok(Pon::Integer(2)));
+    }
+    fs::remove_dir_all(&root_path).unwrap();
+}
+
+#[test]
+fn test_print_hierarchy() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5" z="1"/>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Granit inherits="Rock" x="7" y="2"/>"#).unwrap());
+
+    let hierarchy = subsystem.print_hierarchy("Granit");
+    let lines: Vec<&str> = hierarchy.lines().collect();
+
+    assert_eq!(lines[0], "Granit");
+    assert!(lines[1].starts_with("  x = ") && lines[1].ends_with("(shadowed by Rock)"));
+    assert!(lines[2].starts_with("  y = ") && !lines[2].contains("shadowed"));
+    assert_eq!(lines[3], "  Rock");
+    assert!(lines[4].starts_with("    x = ") && !lines[4].contains("shadowed"));
+    assert!(lines[5].starts_with("    z = "));
+}
+
+#[test]
+fn test_reload_preserves_instance_overrides() {
+    use std::io::Write;
+
+    let dir = test_dir("reload_preserves_instance_overrides");
+    let path = dir.join("templates.tpml");
+    File::create(&path).unwrap().write_all(br#"<Tpml><Rock x="5" y="1" /></Tpml>"#).unwrap();
+    // `authored` sets y to the template's value itself
+    let doc = Document::from_string(r#"<Root><Rock name="plain" /><Rock name="authored" y="1" /></Root>"#).unwrap();
+    let plain = doc.get_entity_by_name("plain").unwrap();
+    let authored = doc.get_entity_by_name("authored").unwrap();
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.load_templates_from_file(&path).unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+
+    File::create(&path).unwrap().write_all(br#"<Tpml><Rock x="6" y="2" /></Tpml>"#).unwrap();
+    subsystem.reload_incremental(&mut system).unwrap();
+
+    assert_eq!(system.document().get_property(&plain, "x").unwrap().concretize(), Ok(Pon::Integer(6)));
+    assert_eq!(system.document().get_property(&plain, "y").unwrap().concretize(), Ok(Pon::Integer(2)));
+    assert_eq!(system.document().get_property(&authored, "x").unwrap().concretize(), Ok(Pon::Integer(6)));
+    assert_eq!(system.document().get_property(&authored, "y").unwrap().concretize(), Ok(Pon::Integer(1)));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_tpml_version_check() {
+    use std::io::Write;
+
+    let dir = test_dir("tpml_version_check");
+    let path = dir.join("templates.tpml");
+    File::create(&path).unwrap().write_all(br#"<Tpml version="3"><Rock x="5" /></Tpml>"#).unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    assert_eq!(subsystem.load_templates_from_file(&path), Err(TemplateError::UnsupportedVersion("3".to_string())));
+    assert!(subsystem.template_property("Rock", "x").is_none());
+
+    File::create(&path).unwrap().write_all(br#"<Tpml version="2"><Rock x="5" /></Tpml>"#).unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.load_templates_from_file(&path).unwrap();
+    assert_eq!(subsystem.template_property("Rock", "x"), Some(&Pon::Integer(5)));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_lazy_property() {
+    let doc = Document::from_string(r#"<Root><Rock name="tmp" /></Root>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5" mesh="'rock.mesh'" tpml:mesh-lazy="true" />"#).unwrap());
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+
+    assert_eq!(system.document().get_property(&ent, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert!(!system.document().has_property(&ent, "mesh").unwrap());
+    assert_eq!(subsystem.lazy_property(&mut system, &ent, "mesh"), Ok(Some(Pon::String("rock.mesh".to_string()))));
+    assert_eq!(system.document().get_property(&ent, "mesh").unwrap().concretize(), Ok(Pon::String("rock.mesh".to_string())));
+    assert_eq!(subsystem.lazy_property(&mut system, &ent, "missing"), Ok(None));
+}
+
+#[test]
+fn test_type_aliases() {
+    let doc = Document::from_string(r#"<Root><Stone name="stone" /><Boulder name="boulder" /><Pebble name="pebble" /></Root>"#).unwrap();
+    let stone = doc.get_entity_by_name("stone").unwrap();
+    let boulder = doc.get_entity_by_name("boulder").unwrap();
+    let pebble = doc.get_entity_by_name("pebble").unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock tpml:aliases="Stone, Boulder" x="5" />"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Pebble x="1" />"#).unwrap());
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+
+    assert_eq!(system.document().get_property(&stone, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(system.document().get_property(&boulder, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(system.document().get_property(&pebble, "x").unwrap().concretize(), Ok(Pon::Integer(1)));
+    assert!(subsystem.load_errors().is_empty());
+
+    subsystem.insert_template(Template::from_string(r#"<Gravel tpml:aliases="Pebble, Stone" />"#).unwrap());
+    assert_eq!(subsystem.load_errors(), &vec![
+        TemplateError::AliasConflict("Pebble".to_string(), "Gravel".to_string()),
+        TemplateError::AliasConflict("Stone".to_string(), "Gravel".to_string())
+    ]);
+}
+
+#[test]
+fn test_strict_children() {
+    let doc = Document::from_string(r#"<Root><Tank name="tmp" /></Root>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Tank><Turet /><Track /></Tank>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Track width="2" />"#).unwrap());
+    assert_eq!(subsystem.validate_child_types(), vec![TemplateError::MissingChildTemplate("Tank".to_string(), "Turet".to_string())]);
+
+    subsystem.set_strict_children(true);
+    assert_eq!(subsystem.apply_template(&mut system, &ent, "Tank"),
+        Err(TemplateError::MissingChildTemplate("Tank".to_string(), "Turet".to_string())));
+    assert_eq!(system.document().get_children(&ent).unwrap().len(), 0);
+}
+
+#[test]
+fn test_max_spawn() {
+    let doc = Document::from_string(r#"<Root><Forest name="forest" /></Root>"#).unwrap();
+    let forest = doc.get_entity_by_name("forest").unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_retroactive(false);
+    subsystem.insert_template(Template::from_string(r#"<Forest><Tree tpml:repeat="10"><Leaf /></Tree></Forest>"#).unwrap());
+    subsystem.set_max_spawn(3);
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+
+    assert_eq!(subsystem.apply_template(&mut system, &forest, "Forest"), Err(TemplateError::SpawnLimit(3)));
+    // Tree, Leaf, then a second Tree before the limit is hit
+    assert_eq!(system.document().get_children(&forest).unwrap().len(), 2);
+}
+
+#[test]
+fn test_when_flag_children() {
+    let doc = Document::from_string(r#"<Root><Screen name="desktop" /><Screen name="phone" /></Root>"#).unwrap();
+    let desktop = doc.get_entity_by_name("desktop").unwrap();
+    let phone = doc.get_entity_by_name("phone").unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_retroactive(false);
+    subsystem.insert_template(Template::from_string(r#"<Screen><MobileUI tpml:when-flag="mobile" /><Menu /></Screen>"#).unwrap());
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+
+    subsystem.apply_template(&mut system, &desktop, "Screen").unwrap();
+    let mut flags = HashSet::new();
+    flags.insert("mobile".to_string());
+    subsystem.set_flags(flags);
+    subsystem.apply_template(&mut system, &phone, "Screen").unwrap();
+
+    let types = |entity_id: &EntityId| -> Vec<String> {
+        system.document().get_children(entity_id).unwrap().iter().map(|c| system.document().get_entity_type_name(c).unwrap().clone()).collect()
+    };
+    assert_eq!(types(&desktop), vec!["Menu".to_string()]);
+    assert_eq!(types(&phone), vec!["MobileUI".to_string(), "Menu".to_string()]);
+}
+
+#[test]
+fn test_deterministic_names() {
+    let spawned_names = || {
+        let doc = Document::from_string(r#"<Root><Tank name="tank" /></Root>"#).unwrap();
+        let tank = doc.get_entity_by_name("tank").unwrap();
+        let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+        subsystem.set_deterministic_names(true);
+        subsystem.insert_template(Template::from_string(r#"<Tank><Turret tpml:repeat="2"><Barrel /></Turret></Tank>"#).unwrap());
+        let mut system = pyramid::system::System::new();
+        system.set_document(doc);
+        subsystem.on_document_loaded(&mut system);
+        let document = system.document();
+        let mut names = vec![];
+        let mut pending = document.get_children(&tank).unwrap().clone();
+        while let Some(entity_id) = pending.pop() {
+            names.push(document.get_entity_name(&entity_id).unwrap());
+            pending.extend(document.get_children(&entity_id).unwrap().iter().cloned());
+        }
+        names.sort();
+        names
+    };
+    let names = spawned_names();
+    assert_eq!(names, vec![
+        Some("tank.Turret0".to_string()),
+        Some("tank.Turret0.Barrel0".to_string()),
+        Some("tank.Turret1".to_string()),
+        Some("tank.Turret1.Barrel0".to_string())
+    ]);
+    assert_eq!(spawned_names(), names);
+}
+
+#[test]
+fn test_deterministic_names_unnamed_parents() {
+    let doc = Document::from_string(r#"<Root><Tank /><Tank /></Root>"#).unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.set_deterministic_names(true);
+    subsystem.insert_template(Template::from_string(r#"<Tank><Turret /></Tank>"#).unwrap());
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+
+    let document = system.document();
+    let root = document.get_root().unwrap().clone();
+    let tanks = document.get_children(&root).unwrap().clone();
+    let turret_names: Vec<Option<String>> = tanks.iter().map(|tank| document.get_entity_name(&document.get_children(tank).unwrap()[0]).unwrap()).collect();
+    assert_eq!(turret_names, vec![Some("Root.Tank0.Turret0".to_string()), Some("Root.Tank1.Turret0".to_string())]);
+    assert_eq!(document.get_entity_by_name("Root.Tank1.Turret0").unwrap(), document.get_children(&tanks[1]).unwrap()[0]);
+}
+
+#[test]
+fn test_apply_report() {
+    let doc = Document::from_string(r#"<Root><Door name="good" key="'red'" /><Door name="bad" /><Door name="also_good" key="'blue'" /></Root>"#).unwrap();
+    let good = doc.get_entity_by_name("good").unwrap();
+    let bad = doc.get_entity_by_name("bad").unwrap();
+    let also_good = doc.get_entity_by_name("also_good").unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Door locked="true" tpml:required="key" />"#).unwrap());
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+
+    assert_eq!(subsystem.take_apply_report(), vec![ApplyIssue {
+        entity_id: bad,
+        type_name: "Door".to_string(),
+        error: TemplateError::MissingProperties("Door".to_string(), vec!["key".to_string()])
+    }]);
+    assert!(subsystem.take_apply_report().is_empty());
+    assert_eq!(system.document().get_property(&good, "locked").unwrap().concretize(), Ok(Pon::Boolean(true)));
+    assert_eq!(system.document().get_property(&also_good, "locked").unwrap().concretize(), Ok(Pon::Boolean(true)));
+}
+
+#[test]
+fn test_has_template_for() {
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock tpml:aliases="Stone" x="5" />"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Shiny tpml:kind="fragment" />"#).unwrap());
+
+    assert!(subsystem.has_template_for("Rock"));
+    assert!(subsystem.has_template_for("Stone"));
+    assert!(!subsystem.has_template_for("Tree"));
+    assert!(!subsystem.has_template_for("Shiny"));
+
+    subsystem.insert_template(Template::from_string(r#"<Default visible="true" />"#).unwrap());
+    assert!(subsystem.has_template_for("Tree"));
+}
+
+#[test]
+fn test_prepared_cache() {
+    let doc = Document::from_string(r#"<Root><Granit name="a" /><Granit name="b" /></Root>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5" />"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Granit inherits="Rock" y="2" />"#).unwrap());
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.apply_template(&mut system, &a, "Granit").unwrap();
+    assert_eq!(subsystem.prepared.keys().cloned().collect::<Vec<String>>(), vec!["Granit".to_string()]);
+    assert!(!subsystem.is_finalized());
+
+    // The second apply uses what the first prepared, even with the template changed behind
+    // the subsystem's back
+    subsystem.templates.get_mut("Rock").unwrap().properties = vec![("x".to_string(), Pon::Integer(9))];
+    subsystem.apply_template(&mut system, &b, "Granit").unwrap();
+    assert_eq!(system.document().get_property(&b, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+
+    subsystem.add_template(Template::from_string(r#"<Moss />"#).unwrap()).unwrap();
+    assert!(subsystem.prepared.is_empty());
+    subsystem.apply_template(&mut system, &b, "Granit").unwrap();
+    assert_eq!(system.document().get_property(&b, "x").unwrap().concretize(), Ok(Pon::Integer(9)));
+}
+
+#[test]
+fn test_finalize() {
+    let doc = Document::from_string(r#"<Root><Granit name="a" /></Root>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5"><Moss /></Rock>"#).unwrap());
+    subsystem.insert_template(Template::from_string(r#"<Granit inherits="Rock" y="2" />"#).unwrap());
+    assert!(!subsystem.is_finalized());
+    subsystem.finalize().unwrap();
+    assert!(subsystem.is_finalized());
+    {
+        let prepared = &subsystem.prepared["Granit"];
+        assert_eq!(prepared.properties.iter().map(|p| p.key.as_str()).collect::<Vec<_>>(), vec!["x", "y"]);
+        assert_eq!(prepared.children.len(), 1);
+    }
+    // Changed behind the subsystem's back, so only an apply not using the cache would see it
+    subsystem.templates.get_mut("Rock").unwrap().properties = vec![("x".to_string(), Pon::Integer(9))];
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+    assert_eq!(system.document().get_property(&a, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(system.document().get_children(&a).unwrap().len(), 1);
+
+    subsystem.add_template(Template::from_string(r#"<Pebble inherits="Gravel" />"#).unwrap()).unwrap();
+    assert!(!subsystem.is_finalized());
+    assert_eq!(subsystem.finalize(), Err(TemplateError::UnknownTemplate("Pebble inherits Gravel".to_string())));
+    subsystem.add_template(Template::from_string(r#"<Gravel inherits="Pebble" />"#).unwrap()).unwrap();
+    assert_eq!(subsystem.finalize(), Err(TemplateError::InheritanceCycle(vec!["Gravel".to_string(), "Pebble".to_string(), "Gravel".to_string()])));
+    assert!(!subsystem.is_finalized());
+}
+
+#[test]
+fn test_entity_added_missing_entity_is_recorded() {
+    let doc = Document::from_string(r#"<Root><Rock name="a" /></Root>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let mut subsystem = TemplateSubSystem::new(PathBuf::new());
+    subsystem.insert_template(Template::from_string(r#"<Rock x="5" />"#).unwrap());
+    let mut system = pyramid::system::System::new();
+    system.set_document(doc);
+    subsystem.on_document_loaded(&mut system);
+    system.document_mut().remove_entity(&a).unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    subsystem.set_event_sender(tx);
+
+    subsystem.on_entity_added(&mut system, &a);
+    assert_eq!(subsystem.load_errors().len(), 1);
+    let mut errors = 0;
+    while let Ok(event) = rx.try_recv() {
+        if let TemplateEvent::Error { .. } = event {
+            errors += 1;
+        }
+    }
+    assert_eq!(errors, 1);
+}
@@ -0,0 +1,198 @@
+use template::*;
+
+/// Parses Tpml without going through xml-rs. Only the subset templates use is understood:
+/// elements, quoted attributes, nesting, comments, `<?...?>` declarations and pragmas, plus the five
+/// predefined entities and character references. Text between elements is ignored, like it
+/// is by `parse_tpml`; anything else, such as CDATA or a DOCTYPE, is an error.
+///
+/// With the `minimal-parser` feature this is an alternative to `parse_tpml` for callers that
+/// parse template sources themselves. In builds without the `xml` feature it is the parser
+/// every loader and `Template::from_string` go through.
+pub fn parse_tpml_minimal(source: &str) -> Result<Vec<Template>, TemplateError> {
+    parse_tpml_minimal_with_warnings(source, &ReaderConfig::default(), &mut vec![])
+}
+
+/// Like `parse_tpml_minimal`, see `parse_tpml_with_warnings`. Only `trim_values` of the
+/// config applies; the other options are about how xml-rs reports what this parser ignores.
+pub fn parse_tpml_minimal_with_warnings(source: &str, config: &ReaderConfig, warnings: &mut Vec<TemplateError>) -> Result<Vec<Template>, TemplateError> {
+    let source = source.trim_left_matches('\u{feff}');
+    let mut template_stack = vec![];
+    let mut pragmas = config.pragmas();
+    let mut templates = vec![];
+    let mut rest = source;
+    while let Some(open) = rest.find('<') {
+        rest = &rest[open..];
+        if rest.starts_with("<?tpml-pragma") {
+            let end = match rest.find("?>") {
+                Some(end) => end,
+                None => return Err(unexpected_end())
+            };
+            pragmas.apply(&rest["<?tpml-pragma".len()..end], warnings);
+            rest = &rest[end + 2..];
+        } else if rest.starts_with("<?") {
+            rest = try!(skip_past(rest, "?>"));
+        } else if rest.starts_with("<!--") {
+            rest = try!(skip_past(rest, "-->"));
+        } else if rest.starts_with("<!") {
+            return Err(TemplateError::Parse("Minimal parser doesn't support <! declarations".to_string()));
+        } else if rest.starts_with("</") {
+            let close = match rest.find('>') {
+                Some(close) => close,
+                None => return Err(unexpected_end())
+            };
+            let name = rest[2..close].trim();
+            rest = &rest[close + 1..];
+            if name == "Tpml" {
+                continue;
+            }
+            if let Some(template) = try!(Template::end_element(&mut template_stack, name)) {
+                templates.push(template);
+            }
+        } else {
+            let (name, attributes, self_closing, remaining) = try!(parse_start_tag(&rest[1..]));
+            rest = remaining;
+            if name == "Tpml" {
+                try!(check_tpml_version(attributes.iter().find(|a| a.1 == "version").map(|a| a.2.as_str())));
+                continue;
+            }
+            try!(Template::start_element(&mut template_stack, &pragmas, name.clone(), attributes, warnings));
+            if self_closing {
+                if let Some(template) = try!(Template::end_element(&mut template_stack, &name)) {
+                    templates.push(template);
+                }
+            }
+        }
+    }
+    if template_stack.len() > 0 {
+        return Err(unexpected_end());
+    }
+    Ok(templates)
+}
+
+fn unexpected_end() -> TemplateError {
+    TemplateError::Parse("Unexpected end of input".to_string())
+}
+
+fn skip_past<'a>(rest: &'a str, end: &str) -> Result<&'a str, TemplateError> {
+    match rest.find(end) {
+        Some(i) => Ok(&rest[i + end.len()..]),
+        None => Err(unexpected_end())
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '.' || c == ':'
+}
+
+/// Parses what follows the `<` of a start tag, returning the element name, its
+/// `(prefix, name, value)` attributes, whether it closes itself and the input after it.
+fn parse_start_tag(tag: &str) -> Result<(String, Vec<(Option<String>, String, String)>, bool, &str), TemplateError> {
+    let name_end = tag.find(|c: char| !is_name_char(c)).unwrap_or(tag.len());
+    if name_end == 0 {
+        return Err(TemplateError::Parse(format!("Expected an element name at: {}", tag.chars().take(20).collect::<String>())));
+    }
+    let name = tag[..name_end].to_string();
+    let mut rest = &tag[name_end..];
+    let mut attributes = vec![];
+    loop {
+        rest = rest.trim_left();
+        if rest.starts_with("/>") {
+            return Ok((name, attributes, true, &rest[2..]));
+        }
+        if rest.starts_with(">") {
+            return Ok((name, attributes, false, &rest[1..]));
+        }
+        if rest.is_empty() {
+            return Err(unexpected_end());
+        }
+        let key_end = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+        if key_end == 0 {
+            return Err(TemplateError::Parse(format!("Unexpected character in <{}>", name)));
+        }
+        let key = &rest[..key_end];
+        rest = rest[key_end..].trim_left();
+        if !rest.starts_with("=") {
+            return Err(TemplateError::Parse(format!("Attribute {} of <{}> has no value", key, name)));
+        }
+        rest = rest[1..].trim_left();
+        let quote = match rest.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => quote,
+            _ => return Err(TemplateError::Parse(format!("Attribute {} of <{}> isn't quoted", key, name)))
+        };
+        let value_end = match rest[1..].find(quote) {
+            Some(len) => 1 + len,
+            None => return Err(unexpected_end())
+        };
+        let value = try!(unescape(&rest[1..value_end]));
+        rest = &rest[value_end + 1..];
+        let (prefix, local_name) = match key.find(':') {
+            Some(i) => (Some(key[..i].to_string()), key[i + 1..].to_string()),
+            None => (None, key.to_string())
+        };
+        attributes.push((prefix, local_name, value));
+    }
+}
+
+fn unescape(value: &str) -> Result<String, TemplateError> {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let semi = match rest.find(';') {
+            Some(semi) => semi,
+            None => return Err(TemplateError::Parse(format!("Unterminated entity in {}", value)))
+        };
+        let entity = &rest[1..semi];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok().and_then(::std::char::from_u32),
+            entity if entity.starts_with("#") => entity[1..].parse::<u32>().ok().and_then(::std::char::from_u32),
+            _ => None
+        };
+        match c {
+            Some(c) => out.push(c),
+            None => return Err(TemplateError::Parse(format!("Unknown entity &{}; in {}", entity, value)))
+        }
+        rest = &rest[semi + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(all(feature = "xml", feature = "minimal-parser"))]
+#[test]
+fn test_minimal_parser_parity() {
+    let sources = [
+        r#"<Tpml><Rock x="5" y='[1, 2]' /></Tpml>"#,
+        r#"<?xml version="1.0"?><Tpml><!-- rocks --><Granit inherits="Rock" label="@name"><Moss tpml:name="moss" tpml:repeat="3" /></Granit></Tpml>"#,
+        r#"<Tpml><Sign text="'a &lt; b &amp; &#x63;'" meta:note="'editor'"><meta category="'props'" /></Sign></Tpml>"#,
+        r#"<Tpml><Lamp><switch on="detail"><case value="low"><Bulb /></case><default /></switch><parent lights="@name" /></Lamp></Tpml>"#
+    ];
+    for source in sources.iter() {
+        assert_eq!(parse_tpml_minimal(source), parse_tpml(source.as_bytes()));
+    }
+}
+
+#[test]
+fn test_minimal_parser_errors() {
+    assert!(parse_tpml_minimal(r#"<Tpml><Rock x="5"></Stone></Tpml>"#).is_err());
+    assert!(parse_tpml_minimal(r#"<Tpml><Rock x="5">"#).is_err());
+    assert!(parse_tpml_minimal(r#"<Tpml><Rock x=5 /></Tpml>"#).is_err());
+    assert!(parse_tpml_minimal(r#"<Tpml><Rock x="&nope;" /></Tpml>"#).is_err());
+    assert!(parse_tpml_minimal(r#"<Tpml><![CDATA[x]]></Tpml>"#).is_err());
+}
+
+#[test]
+fn test_minimal_parser_reader_config() {
+    let source = r#"<Tpml><Granit inherits=" Rock " /></Tpml>"#;
+    let config = ReaderConfig { trim_values: true, ..ReaderConfig::default() };
+    let mut warnings = vec![];
+    assert_eq!(parse_tpml_minimal_with_warnings(source, &config, &mut warnings).unwrap()[0].inherits, Some("Rock".to_string()));
+    assert_eq!(parse_tpml_minimal(source).unwrap()[0].inherits, Some(" Rock ".to_string()));
+    assert!(warnings.is_empty());
+}
@@ -1,138 +1,3412 @@
 extern crate pyramid;
 
+use std::cmp;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Read;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
 
 use pyramid::pon::*;
 use pyramid::interface::*;
 use pyramid::document::*;
 
+#[cfg(feature = "xml")]
+use xml::attribute::OwnedAttribute;
+#[cfg(feature = "xml")]
 use xml::reader::EventReader;
+#[cfg(feature = "xml")]
+use xml::reader::ParserConfig;
+#[cfg(feature = "xml")]
 use xml::reader::Events;
+#[cfg(feature = "xml")]
 use xml::reader::events::*;
 
+use zip::ZipArchive;
+
+use condition::*;
+
 #[derive(PartialEq, Debug, Clone)]
-pub struct Template {
-    pub type_name: String,
-    pub inherits: Option<String>,
-    pub properties: Vec<(String, Pon)>,
-    pub children: Vec<Template>
+pub enum TemplateError {
+    Io(String),
+    Archive(String),
+    /// The request for a template file failed before a response came back
+    Http(String),
+    /// `(url, status)` of a template file the server didn't return successfully
+    HttpStatus(String, u16),
+    Document(String),
+    Translate(String),
+    Parse(String),
+    UnknownTemplate(String),
+    Cache(String),
+    /// The entity of the given type ended up without these required properties
+    MissingProperties(String, Vec<String>),
+    /// Two mixins set the property to different values: `(property, first mixin, second mixin)`
+    MixinConflict(String, String, String),
+    /// A value with a unit that can't be converted
+    Unit(String),
+    /// The template set was frozen and can't be changed anymore
+    Frozen,
+    /// A reference like `@entity:camera.position` whose entity or property doesn't exist
+    UnresolvedReference(String),
+    /// `(alias, template declaring it)` where the alias is already a template's type or alias
+    AliasConflict(String, String),
+    /// `(template, child type)` of a child no template is registered for
+    MissingChildTemplate(String, String),
+    /// A single template was expected but the input is empty or only whitespace
+    Empty,
+    /// The `<Tpml version="...">` of a file this crate can't read
+    UnsupportedVersion(String),
+    /// Types inheriting from each other in a loop, starting and ending with the same type
+    InheritanceCycle(Vec<String>),
+    /// A single apply tried to spawn more entities than allowed, see `ApplyContext::max_spawn`
+    SpawnLimit(usize),
+    /// `(template, property, base)` where the template doesn't override an abstract property of the base
+    MissingOverride(String, String, String),
+    /// `(attribute, template)` of an attribute given more than once, a warning outside of the
+    /// `strict` pragma where the last one wins
+    DuplicateAttribute(String, String),
+    /// The template has a child of its own type, but the entity it's applied to has no integer
+    /// `max_depth` to stop the recursion at
+    MissingMaxDepth(String),
+    /// A template file that is empty or only whitespace, a warning since that's not an error
+    NoTemplates(String),
+    /// A word of a `tpml-pragma` instruction that isn't a known pragma, a warning since it's ignored
+    UnknownPragma(String)
 }
 
-impl Template {
-    pub fn from_string(string: &str) -> Result<Template, String> {
-        let mut parser = EventReader::from_str(string);
-        let mut event = parser.events();
-        let mut template_stack = vec![];
-        while let Some(e) = event.next() {
-            match Template::parse_event(&mut template_stack, e) {
-                Some(template) => return Ok(template),
-                None => {}
+impl From<DocError> for TemplateError {
+    fn from(err: DocError) -> TemplateError {
+        TemplateError::Document(format!("{:?}", err))
+    }
+}
+
+impl From<PonTranslateErr> for TemplateError {
+    fn from(err: PonTranslateErr) -> TemplateError {
+        TemplateError::Translate(format!("{:?}", err))
+    }
+}
+
+/// Matches entities whose `key` property concretizes to `value`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Selector {
+    pub key: String,
+    pub value: Pon
+}
+
+impl Selector {
+    pub fn from_string(string: &str) -> Result<Selector, String> {
+        let mut parts = string.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim().to_string();
+        let value = match parts.next() {
+            Some(value) => match Pon::from_string(value.trim()) {
+                Ok(value) => value,
+                Err(err) => return Err(format!("Error parsing selector: {} error: {:?}", string, err))
+            },
+            None => Pon::Boolean(true)
+        };
+        Ok(Selector { key: key, value: value })
+    }
+    pub fn matches(&self, document: &Document, entity_id: &EntityId) -> bool {
+        match document.get_property(entity_id, &self.key) {
+            Ok(value) => value.concretize() == Ok(self.value.clone()),
+            Err(_) => false
+        }
+    }
+}
+
+/// What a template takes from the templates it inherits, from `tpml:inherit-mode="children"`.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum InheritMode {
+    All,
+    Properties,
+    Children
+}
+
+impl InheritMode {
+    fn includes(&self, part: InheritMode) -> bool {
+        *self == InheritMode::All || *self == part
+    }
+}
+
+/// The attribute prefix of directives, `<Moss tpml:name="moss" tpml:repeat="3" />`, keeping
+/// them apart from properties. Reserving names instead would take `kind`, `version`, `tags`
+/// and every `-alias` or `-lazy` suffix away from the properties templates can set.
+pub const DIRECTIVE_PREFIX: &'static str = "tpml";
+
+/// What `xmlns:tpml` and `xmlns:meta` are bound to where a file declares them, as in
+/// `<Tpml xmlns:tpml="urn:pyramid-template:tpml" xmlns:meta="urn:pyramid-template:meta">`.
+/// Directives and metadata are told apart by prefix alone, so declaring them is optional.
+pub const DIRECTIVE_NAMESPACE: &'static str = "urn:pyramid-template:tpml";
+pub const META_NAMESPACE: &'static str = "urn:pyramid-template:meta";
+
+/// Per file parsing switches from `<?tpml-pragma strict?>` processing instructions.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct Pragmas {
+    /// Duplicated attributes are an error rather than resolved last-wins
+    pub strict: bool,
+    /// Whitespace around attribute values is dropped, so `inherits=" Rock "` names `Rock`
+    pub trim_values: bool
+}
+
+impl Pragmas {
+    /// Takes in the words of a `tpml-pragma` instruction; unknown ones are ignored, with a
+    /// `TemplateError::UnknownPragma` added to `warnings`.
+    pub fn apply(&mut self, data: &str, warnings: &mut Vec<TemplateError>) {
+        for pragma in data.split_whitespace() {
+            match pragma {
+                "strict" => self.strict = true,
+                "trim-values" => self.trim_values = true,
+                pragma => warnings.push(TemplateError::UnknownPragma(pragma.to_string()))
             }
         }
-        Err("No template parsed".to_string())
     }
-    pub fn parse_event(mut template_stack: &mut Vec<Template>, event: XmlEvent) -> Option<Template> {
-        match event {
-            XmlEvent::StartElement { name: type_name, attributes, .. } => {
-                let inherits = match attributes.iter().find(|x| x.name.local_name == "inherits") {
-                    Some(attr) => Some(attr.value.to_string()),
-                    None => None
-                };
-                let mut template = Template {
-                    type_name: type_name.to_string(),
-                    inherits: inherits,
-                    properties: vec![],
-                    children: vec![]
+}
+
+/// Options for the xml reader templates are parsed with. The first five are passed on to
+/// xml-rs and default to what it does, so they do nothing in builds without the `xml`
+/// feature; `trim_values` starts every file as if it had the `trim-values` pragma.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct ReaderConfig {
+    pub trim_whitespace: bool,
+    pub whitespace_to_characters: bool,
+    pub cdata_to_characters: bool,
+    pub ignore_comments: bool,
+    pub coalesce_characters: bool,
+    pub trim_values: bool
+}
+
+impl Default for ReaderConfig {
+    fn default() -> ReaderConfig {
+        ReaderConfig {
+            trim_whitespace: false,
+            whitespace_to_characters: false,
+            cdata_to_characters: false,
+            ignore_comments: true,
+            coalesce_characters: true,
+            trim_values: false
+        }
+    }
+}
+
+impl ReaderConfig {
+    #[cfg(feature = "xml")]
+    pub fn event_reader<R: Read>(&self, reader: R) -> EventReader<R> {
+        let config = ParserConfig::new()
+            .trim_whitespace(self.trim_whitespace)
+            .whitespace_to_characters(self.whitespace_to_characters)
+            .cdata_to_characters(self.cdata_to_characters)
+            .ignore_comments(self.ignore_comments)
+            .coalesce_characters(self.coalesce_characters);
+        EventReader::new_with_config(reader, config)
+    }
+    /// The pragmas a file starts out with.
+    pub fn pragmas(&self) -> Pragmas {
+        Pragmas { strict: false, trim_values: self.trim_values }
+    }
+}
+
+/// Where a template's mixins go in its chain, from `tpml:mixin-order="last"`. As earlier templates
+/// in the chain take precedence, `First` lets the mixins win over the template's own properties.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum MixinOrder {
+    First,
+    Last
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TemplateKind {
+    /// Applied to entities of its type
+    Entity,
+    /// Only contributes to other templates through inheritance
+    Fragment
+}
+
+/// How many copies of a child template to spawn.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Repeat {
+    Count(i64),
+    /// Read from a property of the entity the child is spawned on, e.g. `tpml:repeat="@post_count"`
+    Property(String)
+}
+
+impl Repeat {
+    pub fn from_string(string: &str) -> Result<Repeat, String> {
+        if string.starts_with("@") {
+            return Ok(Repeat::Property(string[1..].to_string()));
+        }
+        match string.trim().parse::<i64>() {
+            Ok(count) => Ok(Repeat::Count(count)),
+            Err(_) => Err(format!("Invalid repeat: {}", string))
+        }
+    }
+    /// Defaults to 1 when the property is missing or not an integer, and never goes below 0.
+    pub fn count(&self, document: &Document, entity_id: &EntityId) -> i64 {
+        let count = match self {
+            &Repeat::Count(count) => count,
+            &Repeat::Property(ref key) => match document.get_property(entity_id, key).map(|p| p.concretize()) {
+                Ok(Ok(Pon::Integer(count))) => count,
+                _ => 1
+            }
+        };
+        cmp::max(count, 0)
+    }
+}
+
+/// `<switch on="detail">`: spawns the children of the case matching the entity's `detail`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Switch {
+    pub on: String,
+    pub cases: Vec<Case>
+}
+
+/// `<case value="low">`, or the default case (`<default>`) when `value` is `None`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Case {
+    pub value: Option<String>,
+    pub children: Vec<Template>
+}
+
+impl Switch {
+    /// The first case whose value matches, else the default case, else nothing. A case value
+    /// matches a string property by its text and any other property by its PON value.
+    pub fn select(&self, document: &Document, entity_id: &EntityId) -> Option<&Case> {
+        let value = match document.get_property(entity_id, &self.on).map(|p| p.concretize()) {
+            Ok(Ok(value)) => Some(value),
+            _ => None
+        };
+        if let Some(value) = value {
+            for case in &self.cases {
+                let matches = match (&case.value, &value) {
+                    (&Some(ref case_value), &Pon::String(ref string)) => case_value == string,
+                    (&Some(ref case_value), value) => Pon::from_string(case_value).ok().as_ref() == Some(value),
+                    (&None, _) => false
                 };
-                for attribute in attributes {
-                    if (attribute.name.local_name == "inherits") { continue; }
-                    match Pon::from_string(&attribute.value) {
-                        Ok(node) => template.properties.push((attribute.name.local_name.to_string(), node)),
-                        Err(err) => panic!("Error parsing: {} error: {:?}", attribute.value, err)
-                    };
+                if matches {
+                    return Some(case);
                 }
-                template_stack.push(template);
             }
-            XmlEvent::EndElement { name: type_name } => {
-                match template_stack.pop() {
-                    Some(template) => {
-                        match template_stack.last_mut() {
-                            Some(ref mut parent) => {
-                                parent.children.push(template);
-                            }
-                            None => return Some(template)
-                        };
-                    }
-                    None => return None
+        }
+        self.cases.iter().find(|case| case.value.is_none())
+    }
+}
+
+/// A property value computed from the document while applying, instead of a literal.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Reference {
+    /// `@name`: the name of the entity the template is applied to
+    Name,
+    /// `@entity:camera.position`: the `position` property of the entity named `camera`
+    Entity(String, String),
+    /// `@parent.color`: the `color` property of the entity's parent
+    Parent(String)
+}
+
+impl Reference {
+    /// Recognizes the reference syntaxes; anything else is left to the PON parser.
+    pub fn from_string(string: &str) -> Option<Reference> {
+        let string = string.trim();
+        if string == "@name" {
+            return Some(Reference::Name);
+        }
+        if string.starts_with("@entity:") {
+            let mut parts = string["@entity:".len()..].splitn(2, '.');
+            return match (parts.next(), parts.next()) {
+                (Some(entity), Some(property)) if !entity.is_empty() && !property.is_empty() =>
+                    Some(Reference::Entity(entity.to_string(), property.to_string())),
+                _ => None
+            };
+        }
+        if string.starts_with("@parent.") && string.len() > "@parent.".len() {
+            return Some(Reference::Parent(string["@parent.".len()..].to_string()));
+        }
+        None
+    }
+    /// `None` when there is nothing to resolve to, e.g. `@name` on an anonymous entity or
+    /// `@parent.color` on the root, in which case the property is left unset. A referenced
+    /// entity or property that doesn't exist is an error rather than silently leaving the wiring out.
+    pub fn resolve(&self, document: &Document, entity_id: &EntityId) -> Result<Option<Pon>, TemplateError> {
+        match self {
+            &Reference::Name => Ok(try!(document.get_entity_name(entity_id)).map(|name| Pon::String(name))),
+            &Reference::Entity(ref name, ref property) => {
+                let target = match document.get_entity_by_name(name) {
+                    Some(target) => target,
+                    None => return Err(TemplateError::UnresolvedReference(format!("No entity named {}", name)))
+                };
+                match document.get_property(&target, property) {
+                    Ok(value) => Ok(Some(value.clone())),
+                    Err(_) => Err(TemplateError::UnresolvedReference(format!("{} has no property {}", name, property)))
                 }
             }
-            XmlEvent::Error(e) => {
-                panic!("Xml error: {}", e);
+            &Reference::Parent(ref property) => {
+                let parent = match try!(document.get_parent(entity_id)) {
+                    Some(parent) => parent,
+                    None => return Ok(None)
+                };
+                match document.get_property(&parent, property) {
+                    Ok(value) => Ok(Some(value.clone())),
+                    Err(_) => Err(TemplateError::UnresolvedReference(format!("The parent has no property {}", property)))
+                }
             }
-            _ => {}
         }
-        None
     }
-    pub fn apply(&self, templates: &HashMap<String, Template>, document: &mut Document, entity_id: &EntityId) {
-        if let &Some(ref inherits) = &self.inherits {
-            if let Some(inherits_template) = templates.get(inherits) {
-                inherits_template.apply(templates, document, entity_id);
+}
+
+/// Cumulative counters of what applying templates did.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct TemplateStats {
+    pub applies: usize,
+    pub properties_set: usize,
+    /// Properties the entity already had
+    pub properties_skipped: usize,
+    pub children_spawned: usize
+}
+
+/// Where templates referenced by name, e.g. through `inherits`, are looked up.
+pub trait TemplateSource {
+    fn get_template(&self, type_name: &str) -> Option<&Template>;
+    /// Every template with the tag, in type name order.
+    fn tagged_templates(&self, tag: &str) -> Vec<&Template>;
+    /// The template declaring the type name in its `aliases`, the first by type name if several do.
+    fn aliased_template(&self, alias: &str) -> Option<&Template>;
+}
+
+impl TemplateSource for HashMap<String, Template> {
+    fn get_template(&self, type_name: &str) -> Option<&Template> {
+        self.get(type_name)
+    }
+    fn tagged_templates(&self, tag: &str) -> Vec<&Template> {
+        let mut tagged: Vec<&Template> = self.values().filter(|t| t.tags.iter().any(|t| t == tag)).collect();
+        tagged.sort_by(|a, b| a.type_name.cmp(&b.type_name));
+        tagged
+    }
+    fn aliased_template(&self, alias: &str) -> Option<&Template> {
+        self.values()
+            .filter(|t| t.aliases.iter().any(|a| a == alias))
+            .min_by_key(|t| t.type_name.clone())
+    }
+}
+
+/// Consults each template set in turn, so earlier layers shadow later ones.
+pub struct LayeredTemplates<'a> {
+    pub layers: Vec<&'a HashMap<String, Template>>
+}
+
+impl<'a> TemplateSource for LayeredTemplates<'a> {
+    fn get_template(&self, type_name: &str) -> Option<&Template> {
+        for layer in &self.layers {
+            if let Some(template) = layer.get(type_name) {
+                return Some(template);
             }
         }
-        for &(ref k, ref v) in &self.properties {
-            if let Ok(has) = document.has_property(entity_id, &k.as_str()) {
-                if !has {
-                    document.set_property(entity_id, k, v.clone());
+        None
+    }
+    fn tagged_templates(&self, tag: &str) -> Vec<&Template> {
+        let mut type_names: Vec<&String> = vec![];
+        for layer in &self.layers {
+            for template in layer.tagged_templates(tag) {
+                if !type_names.contains(&&template.type_name) {
+                    type_names.push(&template.type_name);
                 }
             }
         }
-        for ref template in &self.children {
-            let e = document.append_entity(Some(*entity_id), &template.type_name, None).unwrap();
-            template.apply(templates, document, &e);
+        // Shadowed templates are looked up again, so a layer can't add a tag to a type it shadows away
+        let mut tagged: Vec<&Template> = type_names.into_iter()
+            .filter_map(|type_name| self.get_template(type_name))
+            .filter(|t| t.tags.iter().any(|t| t == tag))
+            .collect();
+        tagged.sort_by(|a, b| a.type_name.cmp(&b.type_name));
+        tagged
+    }
+    fn aliased_template(&self, alias: &str) -> Option<&Template> {
+        for layer in &self.layers {
+            if let Some(template) = layer.aliased_template(alias) {
+                return Some(template);
+            }
         }
+        None
     }
 }
 
-#[test]
-fn test_template_from_string() {
-    let str = r#"<Stone x="5"><Candle /></Stone>"#;
-    let template = Template::from_string(str).unwrap();
-    assert_eq!(template, Template {
-        type_name: "Stone".to_string(),
-        inherits: None,
-        properties: vec![("x".to_string(), Pon::Integer(5))],
-        children: vec![
-            Template {
-                type_name: "Candle".to_string(),
-                inherits: None,
-                properties: vec![],
-                children: vec![]
-            }
-        ]
-    })
+/// Where spawned children go relative to children the entity already has.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ChildPosition {
+    Append,
+    /// Before the existing children, keeping the template's own order
+    Prepend
 }
 
-#[test]
-fn test_template_apply() {
-    let str = r#"<Stone x="5"><Candle /></Stone>"#;
-    let template = Template::from_string(str).unwrap();
-    let mut doc = Document::from_string(r#"<Stone name="tmp" />"#).unwrap();
-    let ent = doc.get_entity_by_name("tmp").unwrap();
+/// A document mutation made while applying transactionally, kept to undo it.
+#[derive(PartialEq, Debug, Clone)]
+pub enum DocumentChange {
+    /// The property and the value it had before, if any
+    Property(EntityId, String, Option<Pon>),
+    Spawned(EntityId)
+}
 
-    template.apply(&HashMap::new(), &mut doc, &ent);
+/// Adds the value the property has before it's changed to `undo`, while a transaction is open.
+fn record_change(undo: &mut Option<Vec<DocumentChange>>, document: &Document, entity_id: &EntityId, key: &str) -> Result<(), TemplateError> {
+    if let Some(ref mut undo) = *undo {
+        let previous = match try!(document.has_property(entity_id, key)) {
+            true => Some(try!(document.get_property(entity_id, key)).clone()),
+            false => None
+        };
+        undo.push(DocumentChange::Property(*entity_id, key.to_string(), previous));
+    }
+    Ok(())
+}
 
-    assert_eq!(doc.get_property(&ent, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
-    assert_eq!(doc.get_children(&ent).unwrap().len(), 1);
+/// The document as a migration sees it. Changes go through the transaction of the apply the
+/// migration runs for, so a transactional apply that fails undoes them too.
+pub struct MigrationDocument<'a> {
+    document: &'a mut Document,
+    undo: &'a mut Option<Vec<DocumentChange>>
 }
 
-#[test]
-fn test_template_apply_dont_overwrite() {
-    let str = r#"<Stone x="5" />"#;
-    let template = Template::from_string(str).unwrap();
-    let mut doc = Document::from_string(r#"<Stone x="7" name="tmp" />"#).unwrap();
-    let ent = doc.get_entity_by_name("tmp").unwrap();
+impl<'a> MigrationDocument<'a> {
+    pub fn new(document: &'a mut Document, undo: &'a mut Option<Vec<DocumentChange>>) -> MigrationDocument<'a> {
+        MigrationDocument { document: document, undo: undo }
+    }
+    pub fn document(&self) -> &Document {
+        &*self.document
+    }
+    pub fn has_property(&self, entity_id: &EntityId, key: &str) -> Result<bool, TemplateError> {
+        Ok(try!(self.document.has_property(entity_id, key)))
+    }
+    pub fn get_property(&self, entity_id: &EntityId, key: &str) -> Result<&Pon, TemplateError> {
+        Ok(try!(self.document.get_property(entity_id, key)))
+    }
+    pub fn set_property(&mut self, entity_id: &EntityId, key: &str, value: Pon) -> Result<(), TemplateError> {
+        try!(record_change(self.undo, self.document, entity_id, key));
+        Ok(try!(self.document.set_property(entity_id, key, value)))
+    }
+    pub fn remove_property(&mut self, entity_id: &EntityId, key: &str) -> Result<(), TemplateError> {
+        try!(record_change(self.undo, self.document, entity_id, key));
+        Ok(try!(self.document.remove_property(entity_id, key)))
+    }
+}
 
-    template.apply(&HashMap::new(), &mut doc, &ent);
+/// Converts values written with a unit, like `width="5cm"`, before they're set on an entity.
+pub trait UnitConverter {
+    /// `None` leaves the value as it is, e.g. when the key has no canonical unit.
+    fn convert(&self, key: &str, value: &Pon) -> Result<Option<Pon>, TemplateError>;
+}
 
-    assert_eq!(doc.get_property(&ent, "x").unwrap().concretize(), Ok(Pon::Integer(7)));
+/// Units as factors of a common base, with the unit each property key is stored in:
+/// with `cm = 1`, `m = 100` and `width` in `m`, `'5cm'` becomes `0.05`.
+pub struct UnitTable {
+    pub factors: HashMap<String, f32>,
+    pub canonical: HashMap<String, String>
+}
+
+impl UnitTable {
+    pub fn new() -> UnitTable {
+        UnitTable { factors: HashMap::new(), canonical: HashMap::new() }
+    }
+}
+
+/// Splits `5cm` into `(5.0, "cm")`; `None` if it doesn't start with a number followed by a unit.
+pub fn split_quantity(string: &str) -> Option<(f32, &str)> {
+    let string = string.trim();
+    let split = match string.find(|c: char| c.is_alphabetic() || c == '%') {
+        Some(split) if split > 0 => split,
+        _ => return None
+    };
+    match string[..split].trim().parse::<f32>() {
+        Ok(number) => Some((number, &string[split..])),
+        Err(_) => None
+    }
+}
+
+impl UnitConverter for UnitTable {
+    fn convert(&self, key: &str, value: &Pon) -> Result<Option<Pon>, TemplateError> {
+        let canonical = match self.canonical.get(key) {
+            Some(canonical) => canonical,
+            None => return Ok(None)
+        };
+        let (number, unit) = match value {
+            &Pon::String(ref string) => match split_quantity(string) {
+                Some(quantity) => quantity,
+                None => return Ok(None)
+            },
+            _ => return Ok(None)
+        };
+        match (self.factors.get(unit), self.factors.get(canonical)) {
+            (Some(factor), Some(canonical_factor)) => Ok(Some(Pon::Float(number * factor / canonical_factor))),
+            (None, _) => Err(TemplateError::Unit(format!("Unknown unit {} in {:?} for {}", unit, value, key))),
+            (_, None) => Err(TemplateError::Unit(format!("Unknown canonical unit {} for {}", canonical, key)))
+        }
+    }
+}
+
+/// Everything applying a template consults besides the template and the document.
+pub struct ApplyContext<'a> {
+    pub templates: &'a TemplateSource,
+    /// Maps child template types to the document entity types that get spawned for them
+    pub type_mapper: Option<&'a Fn(&str) -> String>,
+    pub stats: TemplateStats,
+    /// Record children in `deferred` instead of spawning them, see `Template::spawn_children`
+    pub defer_children: bool,
+    pub deferred: Vec<(EntityId, Vec<Template>)>,
+    pub child_position: ChildPosition,
+    /// Only these keys may be set on entities; `None` allows all
+    pub allowed_keys: Option<&'a HashSet<String>>,
+    /// Active platform/feature flags; without any, children with a `when-flag` aren't spawned
+    pub flags: Option<&'a HashSet<String>>,
+    /// `(entity, key)` of every property skipped because it isn't allowed
+    pub rejected: Vec<(EntityId, String)>,
+    /// `(entity, key)` of every property set from a template rather than kept from the instance
+    pub assigned: Vec<(EntityId, String)>,
+    /// `(entity, key, value)` of lazy properties that weren't set
+    pub lazy: Vec<(EntityId, String, Pon)>,
+    /// Every entity spawned, at any depth, in creation order
+    pub spawned: Vec<EntityId>,
+    /// Spawning more entities than this fails the apply, against runaway `repeat`s and recursion
+    pub max_spawn: Option<usize>,
+    /// Name spawned entities after their parent and position, see `ApplyContext::spawn_name`
+    pub deterministic_names: bool,
+    /// Levels of recursive children below the entity the recursion started on, see `apply_chain`
+    pub depth: i64,
+    pub max_depth: Option<i64>,
+    pub units: Option<&'a UnitConverter>,
+    /// Sees every value about to be set and may rewrite it, or veto it by returning `None`
+    pub interceptor: Option<&'a mut FnMut(&str, Pon) -> Option<Pon>>,
+    /// Undo everything an apply did to the document if it fails part way, see `Template::apply_in`
+    pub transactional: bool,
+    /// Changes of the transaction in progress
+    pub undo: Option<Vec<DocumentChange>>
+}
+
+impl<'a> ApplyContext<'a> {
+    pub fn new(templates: &'a TemplateSource) -> ApplyContext<'a> {
+        ApplyContext {
+            templates: templates,
+            type_mapper: None,
+            stats: TemplateStats::default(),
+            defer_children: false,
+            deferred: vec![],
+            child_position: ChildPosition::Append,
+            allowed_keys: None,
+            flags: None,
+            rejected: vec![],
+            assigned: vec![],
+            lazy: vec![],
+            spawned: vec![],
+            max_spawn: None,
+            deterministic_names: false,
+            depth: 0,
+            max_depth: None,
+            units: None,
+            interceptor: None,
+            transactional: false,
+            undo: None
+        }
+    }
+    pub fn intercept(&mut self, key: &str, value: Pon) -> Option<Pon> {
+        match self.interceptor {
+            Some(ref mut interceptor) => (*interceptor)(key, value),
+            None => Some(value)
+        }
+    }
+    /// Sets a property, recording its previous value while a transaction is in progress.
+    pub fn set_property(&mut self, document: &mut Document, entity_id: &EntityId, key: &str, value: Pon) -> Result<(), TemplateError> {
+        try!(record_change(&mut self.undo, document, entity_id, key));
+        try!(document.set_property(entity_id, key, value));
+        Ok(())
+    }
+    /// Runs `apply`; with `transactional` set and no transaction open yet, a failure undoes
+    /// every change it made through the context, along with what the context recorded of them.
+    pub fn transaction(context: &mut ApplyContext, document: &mut Document, apply: &mut FnMut(&mut ApplyContext, &mut Document) -> Result<(), TemplateError>) -> Result<(), TemplateError> {
+        if !context.transactional || context.undo.is_some() {
+            return apply(context, document);
+        }
+        let deferred = context.deferred.len();
+        let assigned = context.assigned.len();
+        let lazy = context.lazy.len();
+        let spawned = context.spawned.len();
+        let rejected = context.rejected.len();
+        let stats = context.stats;
+        context.undo = Some(vec![]);
+        let result = apply(context, document);
+        let changes = context.undo.take().unwrap_or(vec![]);
+        if result.is_err() {
+            context.deferred.truncate(deferred);
+            context.assigned.truncate(assigned);
+            context.lazy.truncate(lazy);
+            context.spawned.truncate(spawned);
+            context.rejected.truncate(rejected);
+            context.stats = stats;
+            try!(Template::roll_back(changes, document));
+        }
+        result
+    }
+    /// Whether a `when-flag` is met; no flag always is.
+    pub fn has_flag(&self, flag: &Option<String>) -> bool {
+        match (flag, self.flags) {
+            (&None, _) => true,
+            (&Some(ref flag), Some(flags)) => flags.contains(flag),
+            (&Some(_), None) => false
+        }
+    }
+    /// The name for an entity about to be spawned on `parent_id`, with `deterministic_names`
+    /// set: `tank.Turret0` for the first child, a `Turret`, of an entity named `tank`. A parent
+    /// without a name is named by the path to it from its nearest named ancestor, or else the
+    /// document root, as type and position among its siblings: `world.Tank1.Turret0` for a
+    /// `Tank` that is the second child of `world`. So the same template applied to equivalent
+    /// documents always spawns the same names, in a fresh document or on another client alike,
+    /// and unnamed siblings don't share them.
+    pub fn spawn_name(&self, document: &Document, parent_id: &EntityId, type_name: &str) -> Result<Option<String>, TemplateError> {
+        if !self.deterministic_names {
+            return Ok(None);
+        }
+        let mut path = vec![];
+        let mut current = *parent_id;
+        let prefix;
+        loop {
+            if let Some(name) = try!(document.get_entity_name(&current)) {
+                prefix = name;
+                break;
+            }
+            let current_type = try!(document.get_entity_type_name(&current)).clone();
+            match try!(document.get_parent(&current)) {
+                Some(parent) => {
+                    let position = try!(document.get_children(&parent)).iter().position(|child| *child == current).unwrap_or(0);
+                    path.push(format!("{}{}", current_type, position));
+                    current = parent;
+                }
+                None => {
+                    prefix = current_type;
+                    break;
+                }
+            }
+        }
+        path.reverse();
+        let index = try!(document.get_children(parent_id)).len();
+        path.push(format!("{}{}", type_name, index));
+        Ok(Some(format!("{}.{}", prefix, path.join("."))))
+    }
+    /// Fails once `max_spawn` entities were spawned, before the next one is.
+    pub fn check_spawn_limit(&self) -> Result<(), TemplateError> {
+        match self.max_spawn {
+            Some(max_spawn) if self.spawned.len() >= max_spawn => Err(TemplateError::SpawnLimit(max_spawn)),
+            _ => Ok(())
+        }
+    }
+    pub fn record_spawn(&mut self, entity_id: EntityId) {
+        self.spawned.push(entity_id);
+        if let Some(ref mut undo) = self.undo {
+            undo.push(DocumentChange::Spawned(entity_id));
+        }
+    }
+    /// Whether the key may be set on the entity, recording it as rejected if not.
+    pub fn allows(&mut self, entity_id: &EntityId, key: &str) -> bool {
+        let allowed = match self.allowed_keys {
+            Some(allowed_keys) => allowed_keys.contains(key),
+            None => true
+        };
+        if !allowed {
+            self.rejected.push((*entity_id, key.to_string()));
+        }
+        allowed
+    }
+}
+
+/// A property as it comes out of the inheritance chain, with the template that provided it.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ResolvedProperty {
+    pub key: String,
+    pub value: Pon,
+    pub replace: bool,
+    pub source: String
+}
+
+/// What applying a template takes from its inheritance chain, worked out once ahead of
+/// time, see `Template::prepare`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct PreparedTemplate {
+    pub properties: Vec<ResolvedProperty>,
+    pub children: Vec<Template>
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Template {
+    pub type_name: String,
+    pub kind: TemplateKind,
+    /// Identifies a child template across the inheritance chain; it's not set on the entity.
+    pub name: Option<String>,
+    pub inherits: Option<String>,
+    /// Limits what comes from `inherits`, including everything the base itself inherits
+    pub inherit_mode: InheritMode,
+    /// From `tpml:extends-file="base.tpml"`: layered onto that file's template of the same type when
+    /// loading from a file, see `parse_tpml_file`. Once layered it's the path of the base file.
+    pub extends_file: Option<String>,
+    /// Other entity type names the template applies to, from `tpml:aliases="Stone, Boulder"`
+    pub aliases: Vec<String>,
+    /// Templates whose properties and children are mixed in after the bases, from `tpml:mixins="Glow, Shadow"`.
+    pub mixins: Vec<String>,
+    pub mixin_order: MixinOrder,
+    /// From `tpml:tags="damageable"`, for templates inheriting by tag
+    pub tags: Vec<String>,
+    /// From `tpml:inherits-tag="damageable"`: every template with the tag is mixed in, in type name order.
+    pub inherits_tag: Option<String>,
+    pub version: Option<u32>,
+    pub selector: Option<Selector>,
+    /// From `tpml:match-has="rigidbody, collider"`: applies to any entity with all of these properties,
+    /// whatever its type.
+    pub match_has: Vec<String>,
+    /// Authoritative templates overwrite whatever the instance already set.
+    pub replace: bool,
+    /// Object values are deep merged onto the inherited ones instead of being shadowed by them.
+    pub merge: bool,
+    /// Properties the entity must end up with, either from the instance or the templates.
+    pub required: Vec<String>,
+    /// From `tpml:abstract-property="mass, mesh"`: properties every entity template deriving from this
+    /// one has to provide itself, see `missing_overrides`.
+    pub abstract_properties: Vec<String>,
+    /// Spawn this many copies when used as a child.
+    pub repeat: Option<Repeat>,
+    /// From `tpml:inline="true"` on a child: instead of spawning an entity for it, it is applied to
+    /// the parent entity, and never overwrites what the parent already has.
+    pub inline: bool,
+    /// From `tpml:when-flag="mobile"` on a child: it is only spawned while the flag is active, see
+    /// `ApplyContext::flags`.
+    pub when_flag: Option<String>,
+    /// `(property, alias)` pairs from `tpml:property-alias="alias"`: an instance setting the alias provides the property.
+    pub property_aliases: Vec<(String, String)>,
+    /// `(property, condition)` pairs from `tpml:glow-when-depth=">0"` or `tpml:glow-when-root="true"`: the
+    /// property only applies to entities at a matching depth, the document root being depth 0.
+    pub depth_conditions: Vec<(String, String)>,
+    /// Properties from `tpml:x-lazy="true"`, held back on apply until they're asked for, see
+    /// `TemplateSubSystem::lazy_property`.
+    pub lazy: Vec<String>,
+    /// In declaration order. A namespaced attribute like `physics:mass` keeps its namespace in
+    /// the key, which is also the key it is set on entities with, so it can't collide with a
+    /// `mass` of another namespace or without one.
+    pub properties: Vec<(String, Pon)>,
+    /// Editor-only data from `meta:` attributes or a `<meta>` child, never set on entities.
+    pub metadata: HashMap<String, Pon>,
+    /// Properties resolved against the document on apply, e.g. `label="@name"`.
+    pub references: Vec<(String, Reference)>,
+    pub children: Vec<Template>,
+    /// Children chosen per entity while applying, see `Switch`
+    pub switches: Vec<Switch>,
+    /// From a `<parent>` child: properties contributed to the parent entity instead, see `contribute_to_parent`
+    pub parent: Option<Box<Template>>
+}
+
+impl Template {
+    pub fn new(type_name: String) -> Template {
+        Template {
+            type_name: type_name,
+            kind: TemplateKind::Entity,
+            name: None,
+            inherits: None,
+            inherit_mode: InheritMode::All,
+            extends_file: None,
+            aliases: vec![],
+            mixins: vec![],
+            mixin_order: MixinOrder::First,
+            tags: vec![],
+            inherits_tag: None,
+            version: None,
+            selector: None,
+            match_has: vec![],
+            replace: false,
+            merge: false,
+            required: vec![],
+            abstract_properties: vec![],
+            repeat: None,
+            inline: false,
+            when_flag: None,
+            property_aliases: vec![],
+            depth_conditions: vec![],
+            lazy: vec![],
+            properties: vec![],
+            metadata: HashMap::new(),
+            references: vec![],
+            children: vec![],
+            switches: vec![],
+            parent: None
+        }
+    }
+    /// Parses exactly one top level template; a second top level element is an error.
+    pub fn from_string(string: &str) -> Result<Template, TemplateError> {
+        Template::from_string_with_warnings(string, &ReaderConfig::default(), &mut vec![])
+    }
+    /// Like `from_string`, see `parse_tpml_with_warnings`.
+    pub fn from_string_with_warnings(string: &str, config: &ReaderConfig, warnings: &mut Vec<TemplateError>) -> Result<Template, TemplateError> {
+        if string.trim().is_empty() {
+            return Err(TemplateError::Empty);
+        }
+        Template::parse_single(string.as_bytes(), config, warnings)
+    }
+    /// Parses any number of top level templates.
+    pub fn from_string_multi(string: &str) -> Result<Vec<Template>, TemplateError> {
+        Template::from_string_multi_with_warnings(string, &ReaderConfig::default(), &mut vec![])
+    }
+    /// Like `from_string_multi`, see `parse_tpml_with_warnings`. The templates are read inside a
+    /// `<tpml:Tpml>` wrapper, after a leading byte order mark and xml declaration, which have to
+    /// come first. The source may itself be a whole `<Tpml>` file, but a `</Tpml>` it doesn't
+    /// open is an error rather than the end of the wrapper.
+    #[cfg(feature = "xml")]
+    pub fn from_string_multi_with_warnings(string: &str, config: &ReaderConfig, warnings: &mut Vec<TemplateError>) -> Result<Vec<Template>, TemplateError> {
+        let start = format!(r#"<tpml:Tpml xmlns:tpml="{}" xmlns:meta="{}">"#, DIRECTIVE_NAMESPACE, META_NAMESPACE);
+        let reader = start.as_bytes().chain(skip_prolog(string).as_bytes()).chain(&b"</tpml:Tpml>"[..]);
+        parse_tpml_with_warnings(reader, config, warnings)
+    }
+    /// Like `from_string_multi`, see `parse_tpml_minimal_with_warnings`, which reads any number
+    /// of top level templates without a wrapper.
+    #[cfg(not(feature = "xml"))]
+    pub fn from_string_multi_with_warnings(string: &str, config: &ReaderConfig, warnings: &mut Vec<TemplateError>) -> Result<Vec<Template>, TemplateError> {
+        ::minimal::parse_tpml_minimal_with_warnings(string, config, warnings)
+    }
+    /// Parses a template from raw bytes, skipping a leading UTF-8 byte order mark.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Template, TemplateError> {
+        Template::from_bytes_with_warnings(bytes, &ReaderConfig::default(), &mut vec![])
+    }
+    /// Like `from_bytes`, see `parse_tpml_with_warnings`.
+    pub fn from_bytes_with_warnings(bytes: &[u8], config: &ReaderConfig, warnings: &mut Vec<TemplateError>) -> Result<Template, TemplateError> {
+        let bytes = if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) { &bytes[3..] } else { bytes };
+        if is_blank(bytes) {
+            return Err(TemplateError::Empty);
+        }
+        Template::parse_single(bytes, config, warnings)
+    }
+    #[cfg(feature = "xml")]
+    fn parse_single(bytes: &[u8], config: &ReaderConfig, warnings: &mut Vec<TemplateError>) -> Result<Template, TemplateError> {
+        let mut parser = config.event_reader(bytes);
+        let mut pragmas = config.pragmas();
+        let mut events = parser.events();
+        let mut template_stack = vec![];
+        let mut parsed = None;
+        while let Some(e) = events.next() {
+            if parsed.is_some() {
+                if let XmlEvent::StartElement { .. } = e {
+                    return Err(TemplateError::Parse("More than one top level template".to_string()));
+                }
+            }
+            match try!(Template::parse_event_with(&mut template_stack, &mut pragmas, e, warnings)) {
+                Some(template) => parsed = Some(template),
+                None => {}
+            }
+        }
+        parsed.ok_or(TemplateError::Parse("No template parsed".to_string()))
+    }
+    #[cfg(not(feature = "xml"))]
+    fn parse_single(bytes: &[u8], config: &ReaderConfig, warnings: &mut Vec<TemplateError>) -> Result<Template, TemplateError> {
+        let mut templates = try!(::minimal::parse_tpml_minimal_with_warnings(try!(utf8_source(bytes)), config, warnings));
+        match templates.len() {
+            0 => Err(TemplateError::Parse("No template parsed".to_string())),
+            1 => Ok(templates.remove(0)),
+            _ => Err(TemplateError::Parse("More than one top level template".to_string()))
+        }
+    }
+    /// Builds a template from a PON typed object, e.g. `Rock { inherits: 'Base', x: 5, children: [Moss { y: 1 }] }`,
+    /// so templates can be authored inline without escaping xml. Directives other than
+    /// `inherits` go in a `tpml` object: `Moss { tpml: { name: 'moss' } }`.
+    pub fn from_pon(pon: &Pon) -> Result<Template, TemplateError> {
+        let (type_name, data) = try!(pon.as_typed(|p| Ok((p.type_name.clone(), p.data.clone()))));
+        let mut template = Template::new(type_name);
+        let map = match data {
+            Pon::Object(map) => map,
+            _ => return Err(TemplateError::Parse(format!("Expected an object for template {}", template.type_name)))
+        };
+        let mut keys: Vec<&String> = map.keys().collect();
+        keys.sort();
+        for key in keys {
+            let value = &map[key];
+            if key == "children" {
+                let children = try!(value.as_array(|children| Ok(children.clone())));
+                for child in &children {
+                    template.children.push(try!(Template::from_pon(child)));
+                }
+            } else if key == "meta" {
+                match value {
+                    &Pon::Object(ref metadata) => template.metadata.extend(metadata.clone().into_iter()),
+                    value => return Err(TemplateError::Parse(format!("Invalid value for meta: {:?}", value)))
+                }
+            } else if key == DIRECTIVE_PREFIX {
+                let directives = match value {
+                    &Pon::Object(ref directives) => directives,
+                    value => return Err(TemplateError::Parse(format!("Invalid value for {}: {:?}", DIRECTIVE_PREFIX, value)))
+                };
+                let mut keys: Vec<&String> = directives.keys().collect();
+                keys.sort();
+                for key in keys {
+                    try!(template.set_pon_directive(key, &directives[key]));
+                }
+            } else if key == "inherits" {
+                try!(template.set_pon_directive(key, value));
+            } else {
+                try!(template.set_property_value(key, value.clone(), false, &mut vec![]));
+            }
+        }
+        Ok(template)
+    }
+    fn set_pon_directive(&mut self, key: &str, value: &Pon) -> Result<(), TemplateError> {
+        let value = match value {
+            &Pon::String(ref value) => value.clone(),
+            &Pon::Integer(value) => value.to_string(),
+            &Pon::Boolean(value) => value.to_string(),
+            value => return Err(TemplateError::Parse(format!("Invalid value for {}: {:?}", key, value)))
+        };
+        self.set_directive(key, &value)
+    }
+    /// The PON form `from_pon` reads: the properties, `meta`, `children`, `inherits` and the
+    /// `name`, `mixins`, `tags` and `kind` directives in a `tpml` object. References, switches
+    /// and the other directives have no PON form and are left out.
+    pub fn to_pon(&self) -> Pon {
+        let mut map = HashMap::new();
+        for &(ref key, ref value) in &self.properties {
+            map.insert(key.clone(), value.clone());
+        }
+        if let Some(ref inherits) = self.inherits {
+            map.insert("inherits".to_string(), Pon::String(inherits.clone()));
+        }
+        let mut directives = HashMap::new();
+        if let Some(ref name) = self.name {
+            directives.insert("name".to_string(), Pon::String(name.clone()));
+        }
+        if !self.mixins.is_empty() {
+            directives.insert("mixins".to_string(), Pon::String(self.mixins.join(", ")));
+        }
+        if !self.tags.is_empty() {
+            directives.insert("tags".to_string(), Pon::String(self.tags.join(", ")));
+        }
+        if self.kind == TemplateKind::Fragment {
+            directives.insert("kind".to_string(), Pon::String("fragment".to_string()));
+        }
+        if !directives.is_empty() {
+            map.insert(DIRECTIVE_PREFIX.to_string(), Pon::Object(directives));
+        }
+        if !self.metadata.is_empty() {
+            map.insert("meta".to_string(), Pon::Object(self.metadata.clone()));
+        }
+        if !self.children.is_empty() {
+            map.insert("children".to_string(), Pon::Array(self.children.iter().map(|child| child.to_pon()).collect()));
+        }
+        Pon::TypedPon(Box::new(TypedPon { type_name: self.type_name.clone(), data: Pon::Object(map) }))
+    }
+    pub fn metadata(&self) -> &HashMap<String, Pon> {
+        &self.metadata
+    }
+    /// Whether the template applies to entities by what they are rather than by their type,
+    /// through a `selector` or `match-has`.
+    pub fn has_match_rules(&self) -> bool {
+        self.selector.is_some() || !self.match_has.is_empty()
+    }
+    /// Whether the entity passes the template's selector and has every `match-has` property.
+    pub fn matches_entity(&self, document: &Document, entity_id: &EntityId) -> bool {
+        let selected = match self.selector {
+            Some(ref selector) => selector.matches(document, entity_id),
+            None => true
+        };
+        self.has_match_rules() && selected && self.match_has.iter().all(|key| document.has_property(entity_id, key).unwrap_or(false))
+    }
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+    /// How many levels of children are nested below this template; 0 for a leaf.
+    pub fn depth(&self) -> usize {
+        self.children.iter().map(|child| child.depth() + 1).max().unwrap_or(0)
+    }
+    pub fn descendant_count(&self) -> usize {
+        self.children.iter().fold(0, |count, child| count + child.descendant_count() + 1)
+    }
+    /// Layers a later definition of the same type onto this one: its properties add to or
+    /// override ours, its children are added after ours and its directives win where it sets them.
+    pub fn layer(&mut self, other: Template) {
+        for (key, value) in other.properties {
+            match self.properties.iter().position(|p| p.0 == key) {
+                Some(i) => self.properties[i].1 = value,
+                None => self.properties.push((key, value))
+            }
+        }
+        for (key, reference) in other.references {
+            match self.references.iter().position(|r| r.0 == key) {
+                Some(i) => self.references[i].1 = reference,
+                None => self.references.push((key, reference))
+            }
+        }
+        self.metadata.extend(other.metadata.into_iter());
+        self.children.extend(other.children.into_iter());
+        self.switches.extend(other.switches.into_iter());
+        if other.parent.is_some() { self.parent = other.parent; }
+        for key in other.required {
+            if !self.required.contains(&key) {
+                self.required.push(key);
+            }
+        }
+        for key in other.abstract_properties {
+            if !self.abstract_properties.contains(&key) {
+                self.abstract_properties.push(key);
+            }
+        }
+        self.property_aliases.extend(other.property_aliases.into_iter());
+        self.depth_conditions.extend(other.depth_conditions.into_iter());
+        for key in other.lazy {
+            if !self.lazy.contains(&key) {
+                self.lazy.push(key);
+            }
+        }
+        if other.kind != TemplateKind::Entity { self.kind = other.kind; }
+        if other.name.is_some() { self.name = other.name; }
+        if other.inherits.is_some() { self.inherits = other.inherits; }
+        if other.inherit_mode != InheritMode::All { self.inherit_mode = other.inherit_mode; }
+        if other.mixin_order != MixinOrder::First { self.mixin_order = other.mixin_order; }
+        if other.extends_file.is_some() { self.extends_file = other.extends_file; }
+        for alias in other.aliases {
+            if !self.aliases.contains(&alias) {
+                self.aliases.push(alias);
+            }
+        }
+        for mixin in other.mixins {
+            if !self.mixins.contains(&mixin) {
+                self.mixins.push(mixin);
+            }
+        }
+        for tag in other.tags {
+            if !self.tags.contains(&tag) {
+                self.tags.push(tag);
+            }
+        }
+        if other.inherits_tag.is_some() { self.inherits_tag = other.inherits_tag; }
+        if other.version.is_some() { self.version = other.version; }
+        if other.selector.is_some() { self.selector = other.selector; }
+        for key in other.match_has {
+            if !self.match_has.contains(&key) {
+                self.match_has.push(key);
+            }
+        }
+        if other.repeat.is_some() { self.repeat = other.repeat; }
+        if other.when_flag.is_some() { self.when_flag = other.when_flag; }
+        self.replace = self.replace || other.replace;
+        self.merge = self.merge || other.merge;
+        self.inline = self.inline || other.inline;
+    }
+    /// Whether a name is one of the directives configuring the template itself, which are
+    /// given as `tpml:name="moss"` attributes, or in a `tpml` object in PON. `inherits` is the
+    /// one directive that can also go without the prefix.
+    pub fn is_directive(key: &str) -> bool {
+        match key {
+            "kind" | "name" | "inherits" | "inherit-mode" | "extends-file" | "aliases" | "mixins" | "mixin-order" | "tags" | "inherits-tag" | "version" | "selector" | "match-has" | "replace" | "merge" | "inline" | "when-flag" | "repeat" | "required" | "abstract-property" => true,
+            key => key.ends_with("-alias") || key.ends_with("-when-depth") || key.ends_with("-when-root") || key.ends_with("-lazy")
+        }
+    }
+    fn set_directive(&mut self, key: &str, value: &str) -> Result<(), TemplateError> {
+        match key {
+            "kind" => self.kind = match value {
+                "entity" => TemplateKind::Entity,
+                "fragment" => TemplateKind::Fragment,
+                kind => return Err(TemplateError::Parse(format!("Unknown template kind: {}", kind)))
+            },
+            "name" => self.name = Some(value.to_string()),
+            "inherits" => self.inherits = Some(value.to_string()),
+            "inherit-mode" => self.inherit_mode = match value {
+                "all" => InheritMode::All,
+                "properties" => InheritMode::Properties,
+                "children" => InheritMode::Children,
+                mode => return Err(TemplateError::Parse(format!("Unknown inherit mode: {}", mode)))
+            },
+            "extends-file" => self.extends_file = Some(value.to_string()),
+            "aliases" => self.aliases = value.split(',')
+                .map(|alias| alias.trim().to_string())
+                .filter(|alias| !alias.is_empty())
+                .collect(),
+            "mixin-order" => self.mixin_order = match value {
+                "first" => MixinOrder::First,
+                "last" => MixinOrder::Last,
+                order => return Err(TemplateError::Parse(format!("Unknown mixin order: {}", order)))
+            },
+            "mixins" => self.mixins = value.split(',')
+                .map(|mixin| mixin.trim().to_string())
+                .filter(|mixin| !mixin.is_empty())
+                .collect(),
+            "tags" => self.tags = value.split(',')
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect(),
+            "inherits-tag" => self.inherits_tag = Some(value.trim().to_string()),
+            "version" => self.version = Some(try!(value.trim().parse::<u32>()
+                .map_err(|_| TemplateError::Parse(format!("Invalid template version: {}", value))))),
+            "selector" => self.selector = Some(try!(Selector::from_string(value).map_err(|err| TemplateError::Parse(err)))),
+            "match-has" => self.match_has = value.split(',')
+                .map(|key| key.trim().to_string())
+                .filter(|key| !key.is_empty())
+                .collect(),
+            "replace" => self.replace = value == "true",
+            "merge" => self.merge = value == "true",
+            "inline" => self.inline = value == "true",
+            "when-flag" => self.when_flag = Some(value.trim().to_string()),
+            "repeat" => self.repeat = Some(try!(Repeat::from_string(value).map_err(|err| TemplateError::Parse(err)))),
+            "required" => self.required = value.split(',')
+                .map(|key| key.trim().to_string())
+                .filter(|key| !key.is_empty())
+                .collect(),
+            "abstract-property" => self.abstract_properties = value.split(',')
+                .map(|key| key.trim().to_string())
+                .filter(|key| !key.is_empty())
+                .collect(),
+            key if key.ends_with("-alias") => {
+                let property = key[..key.len() - "-alias".len()].to_string();
+                self.property_aliases.push((property, value.to_string()));
+            },
+            key if key.ends_with("-when-depth") => {
+                let property = key[..key.len() - "-when-depth".len()].to_string();
+                let condition = value.trim();
+                // A bare number means exactly that depth
+                let condition = if condition.starts_with(|c: char| c.is_digit(10)) { format!("=={}", condition) } else { condition.to_string() };
+                try!(Template::depth_matches(&condition, 0));
+                self.depth_conditions.push((property, condition));
+            },
+            key if key.ends_with("-when-root") => {
+                let property = key[..key.len() - "-when-root".len()].to_string();
+                let condition = match value {
+                    "true" => "==0",
+                    "false" => ">0",
+                    value => return Err(TemplateError::Parse(format!("{} must be true or false, found {}", key, value)))
+                };
+                self.depth_conditions.push((property, condition.to_string()));
+            },
+            key if key.ends_with("-lazy") => {
+                let property = key[..key.len() - "-lazy".len()].to_string();
+                if value == "true" && !self.lazy.contains(&property) {
+                    self.lazy.push(property);
+                }
+            },
+            key => return Err(TemplateError::Parse(format!("Unknown directive: {}", key)))
+        }
+        Ok(())
+    }
+    fn set_property_value(&mut self, key: &str, value: Pon, strict: bool, warnings: &mut Vec<TemplateError>) -> Result<(), TemplateError> {
+        match self.properties.iter().position(|p| p.0 == key) {
+            Some(_) if strict => return Err(TemplateError::Parse(format!("Duplicate attribute {} on {}", key, self.type_name))),
+            // Duplicated attributes are resolved last-wins
+            Some(i) => {
+                warnings.push(TemplateError::DuplicateAttribute(key.to_string(), self.type_name.clone()));
+                self.properties[i].1 = value;
+            }
+            None => self.properties.push((key.to_string(), value))
+        }
+        Ok(())
+    }
+    /// Feeds one xml event to the parser, returning a template once a top level element closes.
+    /// Malformed input of any kind is reported as an error, never as a panic.
+    #[cfg(feature = "xml")]
+    pub fn parse_event(template_stack: &mut Vec<Template>, event: XmlEvent) -> Result<Option<Template>, TemplateError> {
+        Template::parse_event_with(template_stack, &mut Pragmas::default(), event, &mut vec![])
+    }
+    /// Like `parse_event`, keeping track of the pragmas seen so far in the file and adding
+    /// what is wrong but doesn't stop the template from parsing to `warnings`.
+    #[cfg(feature = "xml")]
+    pub fn parse_event_with(template_stack: &mut Vec<Template>, pragmas: &mut Pragmas, event: XmlEvent, warnings: &mut Vec<TemplateError>) -> Result<Option<Template>, TemplateError> {
+        match event {
+            XmlEvent::StartElement { name: type_name, attributes, .. } => {
+                let attributes = attributes.into_iter().map(|a| (a.name.prefix, a.name.local_name, a.value)).collect();
+                try!(Template::start_element(template_stack, pragmas, type_name.to_string(), attributes, warnings));
+            }
+            XmlEvent::ProcessingInstruction { ref name, ref data } if name == "tpml-pragma" => {
+                pragmas.apply(data.as_ref().map(|data| data.as_str()).unwrap_or(""), warnings);
+            }
+            XmlEvent::EndElement { name } => {
+                return Template::end_element(template_stack, &name.to_string());
+            }
+            XmlEvent::Error(e) => {
+                return Err(TemplateError::Parse(format!("Xml error: {}", e)));
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+    /// Opens an element with `(prefix, name, value)` attributes; the half of `parse_event` that
+    /// doesn't depend on the xml parser.
+    pub fn start_element(template_stack: &mut Vec<Template>, pragmas: &Pragmas, type_name: String, attributes: Vec<(Option<String>, String, String)>, warnings: &mut Vec<TemplateError>) -> Result<(), TemplateError> {
+        let mut template = Template::new(type_name);
+        if Template::is_switch_element(&template.type_name) {
+            // Kept as raw strings until the element closes, see `into_switch`
+            for (_, key, value) in attributes {
+                template.properties.push((key, Pon::String(value)));
+            }
+            template_stack.push(template);
+            return Ok(());
+        }
+        for (prefix, key, value) in attributes {
+            // Namespace declarations are for the xml parser, not properties
+            if prefix.as_ref().map(|prefix| prefix.as_str()) == Some("xmlns") || (prefix.is_none() && key == "xmlns") {
+                continue;
+            }
+            let is_meta = prefix.as_ref().map(|prefix| prefix.as_str()) == Some("meta");
+            // Only `inherits` is a directive without the prefix, so a property can be named
+            // `kind` or `version` like any other
+            let is_directive = match prefix {
+                Some(ref prefix) => prefix == DIRECTIVE_PREFIX,
+                None => key == "inherits"
+            };
+            let key = match prefix {
+                Some(ref prefix) if !is_meta && !is_directive => format!("{}:{}", prefix, key),
+                _ => key
+            };
+            let key = key.as_str();
+            let value = if pragmas.trim_values { value.trim().to_string() } else { value };
+            if is_meta {
+                match Pon::from_string(&value) {
+                    Ok(node) => { template.metadata.insert(key.to_string(), node); }
+                    Err(err) => return Err(TemplateError::Parse(format!("Error parsing: {} error: {:?}", value, err)))
+                }
+            } else if is_directive {
+                if !Template::is_directive(key) {
+                    return Err(TemplateError::Parse(format!("Unknown directive: {}:{}", DIRECTIVE_PREFIX, key)));
+                }
+                try!(template.set_directive(key, &value));
+            } else if let Some(reference) = Reference::from_string(&value) {
+                template.references.push((key.to_string(), reference));
+            } else {
+                match Pon::from_string(&value) {
+                    Ok(node) => try!(template.set_property_value(key, node, pragmas.strict, warnings)),
+                    // `width="5cm"` isn't PON; it's kept for a unit converter to handle
+                    Err(_) if split_quantity(&value).is_some() => try!(template.set_property_value(key, Pon::String(value.clone()), pragmas.strict, warnings)),
+                    Err(err) => return Err(TemplateError::Parse(format!("Error parsing: {} error: {:?}", value, err)))
+                }
+            }
+        }
+        template_stack.push(template);
+        Ok(())
+    }
+    /// Closes the innermost element, returning the template if it was a top level one.
+    pub fn end_element(template_stack: &mut Vec<Template>, name: &str) -> Result<Option<Template>, TemplateError> {
+        match template_stack.pop() {
+            Some(ref template) if template.type_name != name => {
+                return Err(TemplateError::Parse(format!("Mismatched end tag: expected </{}> but found </{}>", template.type_name, name)));
+            }
+            Some(template) => {
+                let in_switch = template_stack.last().map(|parent| parent.type_name == "switch");
+                let is_case = template.type_name == "case" || template.type_name == "default";
+                if in_switch == Some(true) && !is_case {
+                    return Err(TemplateError::Parse(format!("Only case and default are allowed in a switch, found <{}>", template.type_name)));
+                }
+                if is_case && in_switch != Some(true) {
+                    return Err(TemplateError::Parse(format!("<{}> outside of a switch", template.type_name)));
+                }
+                if template.type_name == "parent" {
+                    match template_stack.last_mut() {
+                        Some(parent) => parent.parent = Some(Box::new(template)),
+                        None => return Err(TemplateError::Parse("<parent> outside of a template".to_string()))
+                    }
+                    return Ok(None);
+                }
+                if template.type_name == "meta" {
+                    match template_stack.last_mut() {
+                        Some(parent) => parent.metadata.extend(template.properties.into_iter()),
+                        None => return Err(TemplateError::Parse("<meta> outside of a template".to_string()))
+                    }
+                    return Ok(None);
+                }
+                if template.type_name == "switch" {
+                    let switch = try!(template.into_switch());
+                    match template_stack.last_mut() {
+                        Some(parent) => parent.switches.push(switch),
+                        None => return Err(TemplateError::Parse("<switch> outside of a template".to_string()))
+                    }
+                    return Ok(None);
+                }
+                match template_stack.last_mut() {
+                    Some(ref mut parent) => {
+                        parent.children.push(template);
+                    }
+                    None => return Ok(Some(template))
+                };
+            }
+            None => return Ok(None)
+        }
+        Ok(None)
+    }
+    fn is_switch_element(type_name: &str) -> bool {
+        type_name == "switch" || type_name == "case" || type_name == "default"
+    }
+    fn raw_attribute(&self, key: &str) -> Option<String> {
+        self.properties.iter().find(|p| p.0 == key).and_then(|p| match p.1 {
+            Pon::String(ref value) => Some(value.clone()),
+            _ => None
+        })
+    }
+    /// Turns a parsed `<switch>` element, with its `<case>` elements as children, into a `Switch`.
+    fn into_switch(self) -> Result<Switch, TemplateError> {
+        let on = match self.raw_attribute("on") {
+            Some(on) => on,
+            None => return Err(TemplateError::Parse("<switch> needs an on attribute".to_string()))
+        };
+        let cases = self.children.into_iter().map(|case| Case {
+            value: if case.type_name == "default" { None } else { case.raw_attribute("value") },
+            children: case.children
+        }).collect();
+        Ok(Switch { on: on, cases: cases })
+    }
+    /// The inheritance chain of this template, ordered from the root base down to self, with
+    /// each template's mixins in listed order, then the templates it inherits by tag, right
+    /// before it (or right after it with `tpml:mixin-order="last"`). This is also the precedence
+    /// order of properties, see `flatten`. Walking stops at a missing base or at the first
+    /// template that would repeat; a mixin's own bases and mixins aren't followed.
+    pub fn chain<'a>(&'a self, templates: &'a TemplateSource) -> Vec<&'a Template> {
+        self.chain_of(templates, None)
+    }
+    /// The chain without the bases, and their mixins, that an `inherit_mode` further down
+    /// keeps from contributing `part`.
+    fn chain_of<'a>(&'a self, templates: &'a TemplateSource, part: Option<InheritMode>) -> Vec<&'a Template> {
+        self.chain_within(templates, part, None)
+    }
+    /// `chain_of` leaving out the base named `stop_at` and everything above it.
+    fn chain_within<'a>(&'a self, templates: &'a TemplateSource, part: Option<InheritMode>, stop_at: Option<&str>) -> Vec<&'a Template> {
+        let bases = self.base_chain(templates);
+        let mut included = vec![true; bases.len()];
+        if let Some(stop_at) = stop_at {
+            if let Some(stop) = bases[..bases.len() - 1].iter().position(|t| t.type_name == stop_at) {
+                for included in &mut included[..stop + 1] {
+                    *included = false;
+                }
+            }
+        }
+        if let Some(part) = part {
+            let mut include = true;
+            for (i, template) in bases.iter().enumerate().rev() {
+                included[i] = included[i] && include;
+                include = include && template.inherit_mode.includes(part);
+            }
+        }
+        let mut chain: Vec<&'a Template> = vec![];
+        for (template, included) in bases.into_iter().zip(included.into_iter()) {
+            if !included {
+                continue;
+            }
+            let mut mixins: Vec<&'a Template> = template.mixins.iter().filter_map(|mixin| templates.get_template(mixin)).collect();
+            if let Some(ref tag) = template.inherits_tag {
+                mixins.extend(templates.tagged_templates(tag).into_iter());
+            }
+            if template.mixin_order == MixinOrder::Last {
+                chain.push(template);
+            }
+            for mixin in mixins {
+                if !chain.iter().any(|t| t.type_name == mixin.type_name) && mixin.type_name != template.type_name {
+                    chain.push(mixin);
+                }
+            }
+            if template.mixin_order == MixinOrder::First {
+                chain.push(template);
+            }
+        }
+        chain
+    }
+    /// Type names of the templates contributing properties, the one whose value wins first.
+    pub fn precedence(&self, templates: &TemplateSource) -> Vec<String> {
+        self.chain_of(templates, Some(InheritMode::Properties)).iter().map(|t| t.type_name.clone()).collect()
+    }
+    fn base_chain<'a>(&'a self, templates: &'a TemplateSource) -> Vec<&'a Template> {
+        let mut chain = vec![self];
+        let mut current = self;
+        loop {
+            let next = match current.inherits {
+                Some(ref inherits) => templates.get_template(inherits),
+                None => None
+            };
+            match next {
+                Some(template) if chain.iter().any(|t| t.type_name == template.type_name) => break,
+                Some(template) => {
+                    chain.push(template);
+                    current = template;
+                }
+                None => break
+            }
+        }
+        chain.reverse();
+        chain
+    }
+    /// Fails when two mixins anywhere in the chain set the same property to different values,
+    /// which would otherwise silently resolve to whichever comes first.
+    pub fn check_mixins(&self, templates: &TemplateSource) -> Result<(), TemplateError> {
+        let mut seen: Vec<(&String, &Pon, &String)> = vec![];
+        for template in self.base_chain(templates) {
+            for mixin in &template.mixins {
+                let mixin = match templates.get_template(mixin) {
+                    Some(mixin) => mixin,
+                    None => continue
+                };
+                for &(ref key, ref value) in &mixin.properties {
+                    match seen.iter().find(|s| s.0 == key) {
+                        Some(&(_, other_value, other_mixin)) if other_mixin != &mixin.type_name && other_value != value => {
+                            return Err(TemplateError::MixinConflict(key.clone(), other_mixin.clone(), mixin.type_name.clone()));
+                        }
+                        Some(_) => {}
+                        None => seen.push((key, value, &mixin.type_name))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Child types, at any depth and in any switch case, that have no template of their own, in
+    /// the order they're first found. Each type's children are only visited once.
+    pub fn missing_child_types(&self, templates: &TemplateSource) -> Vec<String> {
+        let mut missing = vec![];
+        let mut visited = vec![self.type_name.clone()];
+        self.collect_missing_child_types(templates, &mut visited, &mut missing);
+        missing
+    }
+    fn collect_missing_child_types(&self, templates: &TemplateSource, visited: &mut Vec<String>, missing: &mut Vec<String>) {
+        let chain = self.chain(templates);
+        let mut children = Template::resolve_children(&self.chain_of(templates, Some(InheritMode::Children)));
+        for template in &chain {
+            for switch in &template.switches {
+                for case in &switch.cases {
+                    children.extend(case.children.iter().cloned());
+                }
+            }
+        }
+        for child in &children {
+            // An inline child doesn't spawn an entity of its type
+            let registered = child.inline || templates.get_template(&child.type_name).is_some() || templates.aliased_template(&child.type_name).is_some();
+            if !registered && !missing.contains(&child.type_name) {
+                missing.push(child.type_name.clone());
+            }
+            if !visited.contains(&child.type_name) {
+                visited.push(child.type_name.clone());
+                child.collect_missing_child_types(templates, visited, missing);
+            }
+        }
+    }
+    /// Abstract properties of the bases and mixins that nothing in the chain below the
+    /// template declaring them provides, as `(property, declaring template)`. Fragments are
+    /// abstract themselves and have none, like a template for its own abstract properties.
+    pub fn missing_overrides(&self, templates: &TemplateSource) -> Vec<(String, String)> {
+        if self.kind == TemplateKind::Fragment {
+            return vec![];
+        }
+        let chain = self.chain_of(templates, Some(InheritMode::Properties));
+        let mut missing = vec![];
+        for (i, base) in chain.iter().enumerate() {
+            if base.type_name == self.type_name {
+                continue;
+            }
+            for key in &base.abstract_properties {
+                let overridden = chain[i + 1..].iter()
+                    .any(|t| t.properties.iter().any(|p| &p.0 == key) || t.references.iter().any(|r| &r.0 == key));
+                if !overridden {
+                    missing.push((key.clone(), base.type_name.clone()));
+                }
+            }
+        }
+        missing
+    }
+    /// Fails on the first child type without a template, see `missing_child_types`.
+    pub fn check_child_types(&self, templates: &TemplateSource) -> Result<(), TemplateError> {
+        match self.missing_child_types(templates).into_iter().next() {
+            Some(child) => Err(TemplateError::MissingChildTemplate(self.type_name.clone(), child)),
+            None => Ok(())
+        }
+    }
+    /// Resolves the properties of the whole inheritance chain. Bases take precedence, as they
+    /// are applied first, unless the deriving template is in replace mode, or in merge mode and
+    /// both values are objects, in which case they are deep merged with the deriving one winning.
+    pub fn flatten(&self, templates: &TemplateSource) -> Vec<ResolvedProperty> {
+        Template::flatten_chain(&self.chain_of(templates, Some(InheritMode::Properties)))
+    }
+    /// Properties the template declares that never take effect, as `(key, template whose value
+    /// wins)`: a base or mixin ahead of it in the chain sets them too, and the template is in
+    /// neither replace nor merge mode.
+    pub fn dead_properties(&self, templates: &TemplateSource) -> Vec<(String, String)> {
+        if self.replace || self.merge {
+            return vec![];
+        }
+        let resolved = self.flatten(templates);
+        let mut dead = vec![];
+        for &(ref key, _) in &self.properties {
+            match resolved.iter().find(|p| &p.key == key) {
+                Some(property) if property.source != self.type_name => dead.push((key.clone(), property.source.clone())),
+                _ => {}
+            }
+        }
+        dead
+    }
+    fn flatten_chain(chain: &Vec<&Template>) -> Vec<ResolvedProperty> {
+        let mut resolved: Vec<ResolvedProperty> = vec![];
+        for template in chain {
+            for &(ref k, ref v) in &template.properties {
+                let property = ResolvedProperty {
+                    key: k.clone(),
+                    value: clone_value(v),
+                    replace: template.replace,
+                    source: template.type_name.clone()
+                };
+                match resolved.iter().position(|p| &p.key == k) {
+                    Some(i) => {
+                        if template.replace {
+                            resolved[i] = property;
+                        } else if template.merge {
+                            let merged = merge_pon(&resolved[i].value, v);
+                            resolved[i].value = merged;
+                            resolved[i].source = template.type_name.clone();
+                        }
+                    }
+                    None => resolved.push(property)
+                }
+            }
+        }
+        resolved
+    }
+    /// A standalone copy of this template with the inheritance chain baked in and `inherits` cleared.
+    pub fn resolve(&self, templates: &TemplateSource) -> Template {
+        let chain = self.chain(templates);
+        let mut template = self.clone();
+        template.inherits = None;
+        template.inherit_mode = InheritMode::All;
+        template.mixins = vec![];
+        template.inherits_tag = None;
+        template.properties = Template::flatten_chain(&self.chain_of(templates, Some(InheritMode::Properties))).into_iter().map(|p| (p.key, p.value)).collect();
+        template.references = vec![];
+        for t in &chain {
+            for &(ref key, ref reference) in &t.references {
+                if !template.references.iter().any(|r| &r.0 == key) {
+                    template.references.push((key.clone(), reference.clone()));
+                }
+            }
+        }
+        template.depth_conditions = vec![];
+        for t in &chain {
+            for &(ref key, ref condition) in &t.depth_conditions {
+                match template.depth_conditions.iter().position(|c| &c.0 == key) {
+                    Some(i) => template.depth_conditions[i].1 = condition.clone(),
+                    None => template.depth_conditions.push((key.clone(), condition.clone()))
+                }
+            }
+        }
+        template.lazy = vec![];
+        for t in &chain {
+            for key in &t.lazy {
+                if !template.lazy.contains(key) {
+                    template.lazy.push(key.clone());
+                }
+            }
+        }
+        template.children = Template::resolve_children(&self.chain_of(templates, Some(InheritMode::Children)));
+        template.switches = chain.iter().flat_map(|t| t.switches.iter().cloned()).collect();
+        template
+    }
+    /// Children of the whole inheritance chain. A named child declared again further down the
+    /// chain isn't spawned twice; its properties override the inherited child's and its own
+    /// children are added to it, while the inherited child keeps everything else.
+    fn resolve_children(chain: &Vec<&Template>) -> Vec<Template> {
+        let mut children: Vec<Template> = vec![];
+        for template in chain {
+            for child in &template.children {
+                let existing = match child.name {
+                    Some(ref name) => children.iter().position(|c| c.name.as_ref() == Some(name)),
+                    None => None
+                };
+                match existing {
+                    Some(i) => {
+                        let inherited = &mut children[i];
+                        for &(ref k, ref v) in &child.properties {
+                            match inherited.properties.iter().position(|p| &p.0 == k) {
+                                Some(j) => inherited.properties[j].1 = v.clone(),
+                                None => inherited.properties.push((k.clone(), v.clone()))
+                            }
+                        }
+                        inherited.children.extend(child.children.iter().cloned());
+                    }
+                    None => children.push(child.clone())
+                }
+            }
+        }
+        children
+    }
+    /// Sets every property the entity doesn't already have and spawns the children.
+    /// Document errors abort the apply and are returned, a failing `has_property` included:
+    /// it only fails for an entity that isn't in the document (any more), which setting the
+    /// property would fail for just the same, so there is deliberately no lenient mode that
+    /// goes on to set it anyway.
+    pub fn apply(&self, templates: &TemplateSource, document: &mut Document, entity_id: &EntityId) -> Result<(), TemplateError> {
+        self.apply_in(&mut ApplyContext::new(templates), document, entity_id)
+    }
+    /// Like `apply`, returning the entities spawned for children at any depth, in creation order.
+    pub fn apply_with_spawned(&self, templates: &TemplateSource, document: &mut Document, entity_id: &EntityId) -> Result<Vec<EntityId>, TemplateError> {
+        let mut context = ApplyContext::new(templates);
+        try!(self.apply_in(&mut context, document, entity_id));
+        Ok(context.spawned)
+    }
+    /// Like `apply` without what `stop_at`, one of the bases, and the bases above it contribute,
+    /// to see what the levels below it add. Their mixins are left out along with them.
+    pub fn apply_until(&self, templates: &TemplateSource, document: &mut Document, entity_id: &EntityId, stop_at: &str) -> Result<(), TemplateError> {
+        let bases = self.base_chain(templates);
+        if !bases[..bases.len() - 1].iter().any(|t| t.type_name == stop_at) {
+            return Err(TemplateError::UnknownTemplate(format!("{} isn't a base of {}", stop_at, self.type_name)));
+        }
+        let chain = self.chain_within(templates, None, Some(stop_at));
+        let properties = Template::flatten_chain(&self.chain_within(templates, Some(InheritMode::Properties), Some(stop_at)));
+        let children = Template::resolve_children(&self.chain_within(templates, Some(InheritMode::Children), Some(stop_at)));
+        Template::apply_chain(&chain, &properties, &children, &mut ApplyContext::new(templates), document, entity_id)
+    }
+    /// Applies the template; with `context.transactional` set, a failure anywhere, children
+    /// included, undoes every property set and child spawned before it is returned, along with
+    /// what the context recorded of them, its `stats` included.
+    pub fn apply_in(&self, context: &mut ApplyContext, document: &mut Document, entity_id: &EntityId) -> Result<(), TemplateError> {
+        self.apply_with(None, context, document, entity_id)
+    }
+    /// Like `apply_in` with the flattened properties and children taken from `prepared`
+    /// instead of the inheritance chain.
+    pub fn apply_prepared(&self, prepared: &PreparedTemplate, context: &mut ApplyContext, document: &mut Document, entity_id: &EntityId) -> Result<(), TemplateError> {
+        self.apply_with(Some(prepared), context, document, entity_id)
+    }
+    fn apply_with(&self, prepared: Option<&PreparedTemplate>, context: &mut ApplyContext, document: &mut Document, entity_id: &EntityId) -> Result<(), TemplateError> {
+        ApplyContext::transaction(context, document, &mut |context: &mut ApplyContext, document: &mut Document| {
+            let templates = context.templates;
+            let chain = self.chain(templates);
+            match prepared {
+                Some(prepared) => Template::apply_chain(&chain, &prepared.properties, &prepared.children, context, document, entity_id),
+                None => {
+                    let prepared = self.prepare(templates);
+                    Template::apply_chain(&chain, &prepared.properties, &prepared.children, context, document, entity_id)
+                }
+            }
+        })
+    }
+    /// Flattens the properties and children of the inheritance chain, the part of applying
+    /// that doesn't depend on the entity.
+    pub fn prepare(&self, templates: &TemplateSource) -> PreparedTemplate {
+        PreparedTemplate {
+            properties: Template::flatten_chain(&self.chain_of(templates, Some(InheritMode::Properties))),
+            children: Template::resolve_children(&self.chain_of(templates, Some(InheritMode::Children)))
+        }
+    }
+    /// Fails on a base or mixin that isn't in `templates`, or on types inheriting from each
+    /// other in a loop, either of which `chain` would otherwise quietly cut short.
+    pub fn check_bases(&self, templates: &TemplateSource) -> Result<(), TemplateError> {
+        let mut visited = vec![self.type_name.clone()];
+        let mut current = self;
+        loop {
+            for mixin in &current.mixins {
+                if templates.get_template(mixin).is_none() {
+                    return Err(TemplateError::UnknownTemplate(format!("{} mixes in {}", current.type_name, mixin)));
+                }
+            }
+            let inherits = match current.inherits {
+                Some(ref inherits) => inherits,
+                None => return Ok(())
+            };
+            if let Some(i) = visited.iter().position(|t| t == inherits) {
+                let mut cycle = visited[i..].to_vec();
+                cycle.push(inherits.clone());
+                return Err(TemplateError::InheritanceCycle(cycle));
+            }
+            current = match templates.get_template(inherits) {
+                Some(base) => base,
+                None => return Err(TemplateError::UnknownTemplate(format!("{} inherits {}", current.type_name, inherits)))
+            };
+            visited.push(inherits.clone());
+        }
+    }
+    fn roll_back(changes: Vec<DocumentChange>, document: &mut Document) -> Result<(), TemplateError> {
+        for change in changes.into_iter().rev() {
+            match change {
+                DocumentChange::Property(entity_id, key, Some(value)) => try!(document.set_property(&entity_id, &key, value)),
+                DocumentChange::Property(entity_id, key, None) => try!(document.remove_property(&entity_id, &key)),
+                DocumentChange::Spawned(entity_id) => try!(document.remove_entity(&entity_id))
+            }
+        }
+        Ok(())
+    }
+    /// Applies this template to many entities, resolving the inheritance chain only once. Each
+    /// value is cloned once per entity it's set on, where `apply` clones it twice, once more to
+    /// flatten the chain for every entity, see `test_apply_to_entities_clones`.
+    ///
+    /// The remaining clone can't go without changes to pyramid: `Document::set_property` takes
+    /// the `Pon` by value and stores it, and `Pon` has no shared or interned form, so neither a
+    /// `&Pon` nor a handle could be passed through to the document.
+    pub fn apply_to_entities(&self, templates: &TemplateSource, document: &mut Document, entity_ids: &[EntityId]) -> Result<(), TemplateError> {
+        let chain = self.chain(templates);
+        let prepared = self.prepare(templates);
+        let mut context = ApplyContext::new(templates);
+        for entity_id in entity_ids {
+            try!(Template::apply_chain(&chain, &prepared.properties, &prepared.children, &mut context, document, entity_id));
+        }
+        Ok(())
+    }
+    /// Number of ancestors of the entity, or None if walking up the hierarchy fails.
+    fn entity_depth(document: &Document, entity_id: &EntityId) -> Option<i64> {
+        let mut depth = 0;
+        let mut current = *entity_id;
+        loop {
+            match document.get_parent(&current) {
+                Ok(Some(parent)) => {
+                    depth += 1;
+                    current = parent;
+                }
+                Ok(None) => return Some(depth),
+                Err(_) => return None
+            }
+        }
+    }
+    fn depth_matches(condition: &str, depth: i64) -> Result<bool, TemplateError> {
+        let lookup = |key: &str| if key == "depth" { Some(Pon::Integer(depth)) } else { None };
+        evaluate_condition(&Pon::String(format!("depth {}", condition)), &lookup)
+    }
+    fn apply_chain(chain: &Vec<&Template>, properties: &Vec<ResolvedProperty>, children: &Vec<Template>, context: &mut ApplyContext, document: &mut Document, entity_id: &EntityId) -> Result<(), TemplateError> {
+        context.stats.applies += 1;
+        for template in chain {
+            for &(ref property, ref alias) in &template.property_aliases {
+                if !try!(document.has_property(entity_id, property)) && try!(document.has_property(entity_id, alias)) && context.allows(entity_id, property) {
+                    let value = try!(document.get_property(entity_id, alias)).clone();
+                    if let Some(value) = context.intercept(property, value) {
+                        try!(context.set_property(document, entity_id, property, value));
+                    }
+                }
+            }
+        }
+        let mut depth = None;
+        for property in properties {
+            if !context.allows(entity_id, &property.key) {
+                continue;
+            }
+            // The most derived template's condition wins
+            let condition = chain.iter().rev()
+                .filter_map(|t| t.depth_conditions.iter().find(|c| c.0 == property.key))
+                .next();
+            if let Some(&(_, ref condition)) = condition {
+                if depth.is_none() {
+                    depth = Some(Template::entity_depth(document, entity_id));
+                }
+                // An entity whose depth can't be determined gets none of the conditional properties
+                let matches = match depth {
+                    Some(Some(depth)) => try!(Template::depth_matches(condition, depth)),
+                    _ => false
+                };
+                if !matches {
+                    context.stats.properties_skipped += 1;
+                    continue;
+                }
+            }
+            if property.replace || !try!(document.has_property(entity_id, &property.key.as_str())) {
+                let value = match context.units {
+                    Some(units) => match try!(units.convert(&property.key, &property.value)) {
+                        Some(converted) => converted,
+                        None => clone_value(&property.value)
+                    },
+                    None => clone_value(&property.value)
+                };
+                let lazy = chain.iter().any(|t| t.lazy.contains(&property.key));
+                match context.intercept(&property.key, value) {
+                    Some(value) => if lazy {
+                        context.lazy.push((*entity_id, property.key.clone(), value));
+                    } else {
+                        try!(context.set_property(document, entity_id, &property.key, value));
+                        context.stats.properties_set += 1;
+                        context.assigned.push((*entity_id, property.key.clone()));
+                    },
+                    None => context.stats.properties_skipped += 1
+                }
+            } else {
+                // The instance's own value may be written with a unit as well
+                if let Some(units) = context.units {
+                    let converted = try!(units.convert(&property.key, try!(document.get_property(entity_id, &property.key))));
+                    if let Some(converted) = converted.and_then(|converted| context.intercept(&property.key, converted)) {
+                        try!(context.set_property(document, entity_id, &property.key, converted));
+                    }
+                }
+                context.stats.properties_skipped += 1;
+            }
+        }
+        for template in chain {
+            for &(ref key, ref reference) in &template.references {
+                if !context.allows(entity_id, key) {
+                    continue;
+                }
+                if template.replace || !try!(document.has_property(entity_id, key)) {
+                    if let Some(value) = try!(reference.resolve(document, entity_id)).and_then(|value| context.intercept(key, value)) {
+                        try!(context.set_property(document, entity_id, key, value));
+                        context.stats.properties_set += 1;
+                        context.assigned.push((*entity_id, key.clone()));
+                    }
+                }
+            }
+        }
+        let mut missing = vec![];
+        for template in chain {
+            for key in &template.required {
+                if !try!(document.has_property(entity_id, key)) && !missing.contains(key) {
+                    missing.push(key.clone());
+                }
+            }
+        }
+        if missing.len() > 0 {
+            let type_name = chain.last().map(|t| t.type_name.clone()).unwrap_or(String::new());
+            return Err(TemplateError::MissingProperties(type_name, missing));
+        }
+        if let Some(parent_id) = try!(document.get_parent(entity_id)) {
+            for template in chain {
+                if let Some(ref parent) = template.parent {
+                    for &(ref key, ref value) in &parent.properties {
+                        if !context.allows(&parent_id, key) {
+                            continue;
+                        }
+                        if let Some(value) = context.intercept(key, value.clone()) {
+                            try!(Template::contribute_to_parent(context, document, &parent_id, key, value));
+                        }
+                    }
+                    for &(ref key, ref reference) in &parent.references {
+                        if !context.allows(&parent_id, key) {
+                            continue;
+                        }
+                        if let Some(value) = try!(reference.resolve(document, entity_id)).and_then(|value| context.intercept(key, value)) {
+                            try!(Template::contribute_to_parent(context, document, &parent_id, key, value));
+                        }
+                    }
+                }
+            }
+        }
+        // A child of the template's own type spawns the whole template again, as long as the
+        // instance's `max_depth` (counting the instance itself as the first level) allows it.
+        // Without one it's an error, rather than quietly never recursing.
+        let self_type = chain.last().map(|t| t.type_name.clone()).unwrap_or(String::new());
+        let recursive: Vec<&Template> = children.iter().filter(|child| child.type_name == self_type).collect();
+        let max_depth = match context.max_depth {
+            Some(max_depth) => max_depth,
+            None if recursive.is_empty() => 0,
+            None => match document.get_property(entity_id, "max_depth").map(|p| p.concretize()) {
+                Ok(Ok(Pon::Integer(max_depth))) => max_depth,
+                _ => return Err(TemplateError::MissingMaxDepth(self_type))
+            }
+        };
+        let non_recursive: Vec<Template>;
+        let spawned = if recursive.is_empty() {
+            children
+        } else {
+            non_recursive = children.iter().filter(|child| child.type_name != self_type).cloned().collect();
+            &non_recursive
+        };
+        let mut switched = vec![];
+        for template in chain {
+            for switch in &template.switches {
+                if let Some(case) = switch.select(document, entity_id) {
+                    switched.extend(case.children.iter().cloned());
+                }
+            }
+        }
+        if context.defer_children {
+            let mut pending = spawned.clone();
+            pending.extend(switched.into_iter());
+            if pending.len() > 0 {
+                context.deferred.push((*entity_id, pending));
+            }
+        } else {
+            try!(Template::spawn_children(spawned, context, document, entity_id));
+            try!(Template::spawn_children(&switched, context, document, entity_id));
+        }
+        if recursive.len() > 0 {
+            if context.depth + 1 < max_depth {
+                let outer_max_depth = context.max_depth;
+                context.max_depth = Some(max_depth);
+                for child in recursive {
+                    if !context.has_flag(&child.when_flag) {
+                        continue;
+                    }
+                    let count = match child.repeat {
+                        Some(ref repeat) => repeat.count(document, entity_id),
+                        None => 1
+                    };
+                    for _ in 0..count {
+                        try!(context.check_spawn_limit());
+                        let name = try!(context.spawn_name(document, entity_id, &self_type));
+                        let e = try!(document.append_entity(Some(*entity_id), &self_type, name));
+                        context.record_spawn(e);
+                        context.stats.children_spawned += 1;
+                        context.depth += 1;
+                        let result = Template::apply_chain(chain, properties, children, context, document, &e);
+                        context.depth -= 1;
+                        try!(result);
+                    }
+                }
+                context.max_depth = outer_max_depth;
+            }
+        }
+        Ok(())
+    }
+    /// Adds a value from a `<parent>` directive to the parent entity. Values accumulate on an
+    /// array the parent already has, arrays being concatenated; a missing property is set to the
+    /// value, and any other value the parent already has is left alone.
+    fn contribute_to_parent(context: &mut ApplyContext, document: &mut Document, parent_id: &EntityId, key: &str, value: Pon) -> Result<(), TemplateError> {
+        let existing = match try!(document.has_property(parent_id, key)) {
+            true => Some(try!(document.get_property(parent_id, key)).clone()),
+            false => None
+        };
+        match (existing, value) {
+            (Some(Pon::Array(mut items)), Pon::Array(values)) => {
+                items.extend(values.into_iter());
+                try!(context.set_property(document, parent_id, key, Pon::Array(items)));
+            }
+            (Some(Pon::Array(mut items)), value) => {
+                items.push(value);
+                try!(context.set_property(document, parent_id, key, Pon::Array(items)));
+            }
+            (None, value) => try!(context.set_property(document, parent_id, key, value)),
+            (Some(_), _) => {}
+        }
+        Ok(())
+    }
+    /// Applies an inline child to the entity it would have been spawned on. Properties the
+    /// entity already has, from the instance or from the parent's templates, are kept even if
+    /// the child is in replace mode; the child's own children are spawned on the entity.
+    fn apply_inline(&self, context: &mut ApplyContext, document: &mut Document, entity_id: &EntityId) -> Result<(), TemplateError> {
+        let mut child = self.clone();
+        child.inline = false;
+        child.replace = false;
+        child.apply_in(context, document, entity_id)
+    }
+    /// Spawns resolved children on the entity and applies them, e.g. to flush children
+    /// recorded while `defer_children` was set.
+    pub fn spawn_children(children: &Vec<Template>, context: &mut ApplyContext, document: &mut Document, entity_id: &EntityId) -> Result<(), TemplateError> {
+        let mut spawned = 0;
+        for child in children {
+            if !context.has_flag(&child.when_flag) {
+                continue;
+            }
+            if child.inline {
+                try!(child.apply_inline(context, document, entity_id));
+                continue;
+            }
+            let count = match child.repeat {
+                Some(ref repeat) => repeat.count(document, entity_id),
+                None => 1
+            };
+            let type_name = match context.type_mapper {
+                Some(type_mapper) => type_mapper(&child.type_name),
+                None => child.type_name.clone()
+            };
+            for _ in 0..count {
+                try!(context.check_spawn_limit());
+                let name = try!(context.spawn_name(document, entity_id, &type_name));
+                let e = match context.child_position {
+                    ChildPosition::Append => try!(document.append_entity(Some(*entity_id), &type_name, name)),
+                    ChildPosition::Prepend => try!(document.insert_entity(Some(*entity_id), spawned, &type_name, name))
+                };
+                context.record_spawn(e);
+                spawned += 1;
+                context.stats.children_spawned += 1;
+                try!(child.apply_in(context, document, &e));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Range of `<Tpml version="...">` formats that can be read; files without a version are 1.
+pub const TPML_VERSIONS: (u32, u32) = (1, 2);
+
+/// Checks the `version` attribute of a `<Tpml>` wrapper against `TPML_VERSIONS`.
+pub fn check_tpml_version(version: Option<&str>) -> Result<(), TemplateError> {
+    let version = match version {
+        Some(version) => version,
+        None => return Ok(())
+    };
+    match version.trim().parse::<u32>() {
+        Ok(v) if v >= TPML_VERSIONS.0 && v <= TPML_VERSIONS.1 => Ok(()),
+        _ => Err(TemplateError::UnsupportedVersion(version.to_string()))
+    }
+}
+
+/// The source without a leading byte order mark and xml declaration.
+#[cfg(feature = "xml")]
+fn skip_prolog(source: &str) -> &str {
+    let source = source.trim_left_matches('\u{feff}');
+    let declaration = source.starts_with("<?xml") && source[5..].chars().next().map(|c| c.is_whitespace()) == Some(true);
+    match source.find("?>") {
+        Some(end) if declaration => &source[end + 2..],
+        _ => source
+    }
+}
+
+#[cfg(feature = "xml")]
+fn tpml_version(attributes: &Vec<OwnedAttribute>) -> Option<&str> {
+    attributes.iter().find(|a| a.name.local_name == "version").map(|a| a.value.as_str())
+}
+
+/// Parses a `<Tpml>` document into the templates it contains.
+#[cfg(feature = "xml")]
+pub fn parse_tpml<R: Read>(reader: R) -> Result<Vec<Template>, TemplateError> {
+    parse_tpml_with_warnings(reader, &ReaderConfig::default(), &mut vec![])
+}
+
+/// Like `parse_tpml`, reading with `config` and adding what is wrong but doesn't stop the
+/// templates from loading, e.g. a `TemplateError::DuplicateAttribute`, to `warnings` for the
+/// caller to report. Every parse function has a `_with_warnings` form taking the same two.
+#[cfg(feature = "xml")]
+pub fn parse_tpml_with_warnings<R: Read>(reader: R, config: &ReaderConfig, warnings: &mut Vec<TemplateError>) -> Result<Vec<Template>, TemplateError> {
+    parse_tpml_with_progress(reader, config, &mut |_| {}, warnings)
+}
+
+/// Like `parse_tpml_with_warnings`, calling `progress` with the number of templates parsed so far
+/// each time a top level template is complete, e.g. to update a loading screen.
+#[cfg(feature = "xml")]
+pub fn parse_tpml_with_progress<R: Read>(reader: R, config: &ReaderConfig, progress: &mut FnMut(usize), warnings: &mut Vec<TemplateError>) -> Result<Vec<Template>, TemplateError> {
+    let mut event_reader = config.event_reader(reader);
+    let mut events = event_reader.events();
+    let mut template_stack = vec![];
+    let mut pragmas = config.pragmas();
+    let mut templates = vec![];
+    // Prefixes of the open wrappers, so a `</Tpml>` can't close a `<tpml:Tpml>`
+    let mut wrappers = vec![];
+    while let Some(e) = events.next() {
+        match e.clone() {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                if name.local_name.as_str() == "Tpml" {
+                    try!(check_tpml_version(tpml_version(&attributes)));
+                    wrappers.push(name.prefix);
+                    continue;
+                }
+            }
+            XmlEvent::EndElement { name, .. } => {
+                if name.local_name.as_str() == "Tpml" {
+                    if wrappers.pop() != Some(name.prefix.clone()) {
+                        return Err(TemplateError::Parse(format!("Unexpected closing tag: {}", name)));
+                    }
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        match try!(Template::parse_event_with(&mut template_stack, &mut pragmas, e, warnings)) {
+            Some(template) => {
+                templates.push(template);
+                progress(templates.len());
+            }
+            None => {}
+        }
+    }
+    Ok(templates)
+}
+
+/// Parses as much of a Tpml file as possible: every template that parses is returned, along
+/// with the errors of the others keyed by the index of their top level element. A template
+/// with an error is skipped as a whole. Malformed xml can't be recovered from and ends parsing.
+#[cfg(feature = "xml")]
+pub fn parse_tpml_collect(path: &Path) -> (Vec<Template>, Vec<(usize, TemplateError)>) {
+    parse_tpml_collect_with_warnings(path, &ReaderConfig::default(), &mut vec![])
+}
+
+/// Like `parse_tpml_collect`, see `parse_tpml_with_warnings`.
+#[cfg(feature = "xml")]
+pub fn parse_tpml_collect_with_warnings(path: &Path, config: &ReaderConfig, warnings: &mut Vec<TemplateError>) -> (Vec<Template>, Vec<(usize, TemplateError)>) {
+    match File::open(path) {
+        Ok(file) => parse_tpml_collect_reader_with_warnings(BufReader::new(file), config, warnings),
+        Err(err) => (vec![], vec![(0, TemplateError::Io(format!("{}", err)))])
+    }
+}
+
+#[cfg(feature = "xml")]
+pub fn parse_tpml_collect_reader<R: Read>(reader: R) -> (Vec<Template>, Vec<(usize, TemplateError)>) {
+    parse_tpml_collect_reader_with_warnings(reader, &ReaderConfig::default(), &mut vec![])
+}
+
+/// Like `parse_tpml_collect_reader`, see `parse_tpml_with_warnings`.
+#[cfg(feature = "xml")]
+pub fn parse_tpml_collect_reader_with_warnings<R: Read>(reader: R, config: &ReaderConfig, warnings: &mut Vec<TemplateError>) -> (Vec<Template>, Vec<(usize, TemplateError)>) {
+    let mut event_reader = config.event_reader(reader);
+    let mut events = event_reader.events();
+    let mut template_stack = vec![];
+    let mut pragmas = config.pragmas();
+    let mut templates = vec![];
+    let mut errors = vec![];
+    let mut index = 0;
+    let mut depth = 0;
+    let mut skipping = false;
+    while let Some(e) = events.next() {
+        let is_error = match e {
+            XmlEvent::StartElement { ref name, ref attributes, .. } => {
+                if name.local_name.as_str() == "Tpml" {
+                    if let Err(err) = check_tpml_version(tpml_version(attributes)) {
+                        return (vec![], vec![(0, err)]);
+                    }
+                    continue;
+                }
+                depth += 1;
+                false
+            }
+            XmlEvent::EndElement { ref name, .. } => {
+                if name.local_name.as_str() == "Tpml" { continue; }
+                depth -= 1;
+                false
+            }
+            XmlEvent::Error(_) => true,
+            _ => false
+        };
+        if skipping && !is_error {
+            if depth == 0 {
+                skipping = false;
+                index += 1;
+            }
+            continue;
+        }
+        match Template::parse_event_with(&mut template_stack, &mut pragmas, e, warnings) {
+            Ok(Some(template)) => {
+                templates.push(template);
+                index += 1;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                errors.push((index, err));
+                if is_error {
+                    break;
+                }
+                template_stack.clear();
+                if depth == 0 {
+                    index += 1;
+                } else {
+                    skipping = true;
+                }
+            }
+        }
+    }
+    (templates, errors)
+}
+
+/// Parses a Tpml file into the templates it contains, without needing a subsystem or a document.
+/// An empty or whitespace-only file has no templates, which isn't an error, only a
+/// `TemplateError::NoTemplates` warning. A template with `extends-file` is layered onto the
+/// same type from that file, relative to this one, so it keeps everything of the base it
+/// doesn't set itself.
+pub fn parse_tpml_file(path: &Path) -> Result<Vec<Template>, TemplateError> {
+    parse_tpml_file_with_warnings(path, &ReaderConfig::default(), &mut vec![])
+}
+
+/// Like `parse_tpml_file`, see `parse_tpml_with_warnings`. The file may also be an entry of a
+/// zip archive named by a path through it, e.g. `assets.zip/rocks.tpml`. An `extends-file` is
+/// resolved relative to the file, so entries can extend other entries of the same archive.
+pub fn parse_tpml_file_with_warnings(path: &Path, config: &ReaderConfig, warnings: &mut Vec<TemplateError>) -> Result<Vec<Template>, TemplateError> {
+    parse_tpml_source(path, config, &read_source, warnings)
+}
+
+/// Like `parse_tpml_file_with_warnings`, reading the file and any `extends-file` it names
+/// through `read` instead, e.g. out of an archive already in memory.
+pub fn parse_tpml_source(path: &Path, config: &ReaderConfig, read: &Fn(&Path) -> Result<Vec<u8>, TemplateError>, warnings: &mut Vec<TemplateError>) -> Result<Vec<Template>, TemplateError> {
+    parse_tpml_file_extending(path, config, read, &mut vec![], warnings)
+}
+
+fn parse_tpml_file_extending(path: &Path, config: &ReaderConfig, read: &Fn(&Path) -> Result<Vec<u8>, TemplateError>, visiting: &mut Vec<PathBuf>, warnings: &mut Vec<TemplateError>) -> Result<Vec<Template>, TemplateError> {
+    let templates = try!(parse_tpml_file_only(path, config, read, warnings));
+    let mut extended = vec![];
+    for mut template in templates {
+        let file = match template.extends_file.clone() {
+            Some(file) => file,
+            None => {
+                extended.push(template);
+                continue;
+            }
+        };
+        let base_path = path.parent().unwrap_or(Path::new("")).join(&file);
+        if base_path.as_path() == path || visiting.iter().any(|p| p.as_path() == base_path.as_path()) {
+            return Err(TemplateError::Parse(format!("extends-file cycle through {}", base_path.display())));
+        }
+        visiting.push(path.to_path_buf());
+        let bases = parse_tpml_file_extending(&base_path, config, read, visiting, warnings);
+        visiting.pop();
+        let mut base = match try!(bases).into_iter().find(|t| t.type_name == template.type_name) {
+            Some(base) => base,
+            None => return Err(TemplateError::UnknownTemplate(format!("{} in {}", template.type_name, file)))
+        };
+        base.layer(template);
+        base.extends_file = Some(base_path.display().to_string());
+        extended.push(base);
+    }
+    Ok(extended)
+}
+
+fn parse_tpml_file_only(path: &Path, config: &ReaderConfig, read: &Fn(&Path) -> Result<Vec<u8>, TemplateError>, warnings: &mut Vec<TemplateError>) -> Result<Vec<Template>, TemplateError> {
+    let bytes = try!(read(path));
+    let content = if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) { &bytes[3..] } else { &bytes[..] };
+    if is_blank(content) {
+        warnings.push(TemplateError::NoTemplates(path.display().to_string()));
+        return Ok(vec![]);
+    }
+    parse_tpml_content(content, config, warnings)
+}
+
+#[cfg(feature = "xml")]
+fn parse_tpml_content(content: &[u8], config: &ReaderConfig, warnings: &mut Vec<TemplateError>) -> Result<Vec<Template>, TemplateError> {
+    parse_tpml_with_warnings(content, config, warnings)
+}
+
+#[cfg(not(feature = "xml"))]
+fn parse_tpml_content(content: &[u8], config: &ReaderConfig, warnings: &mut Vec<TemplateError>) -> Result<Vec<Template>, TemplateError> {
+    ::minimal::parse_tpml_minimal_with_warnings(try!(utf8_source(content)), config, warnings)
+}
+
+/// The minimal parser reads `str`s, so builds without xml-rs only read UTF-8 sources.
+#[cfg(not(feature = "xml"))]
+fn utf8_source(bytes: &[u8]) -> Result<&str, TemplateError> {
+    ::std::str::from_utf8(bytes).map_err(|_| TemplateError::Parse("Template source isn't valid UTF-8".to_string()))
+}
+
+/// The contents of a file, or of the archive entry a path through a zip archive names.
+fn read_source(path: &Path) -> Result<Vec<u8>, TemplateError> {
+    let mut bytes = vec![];
+    match split_archive_path(path) {
+        Some((archive, entry)) => {
+            let file = try!(File::open(&archive).map_err(|err| TemplateError::Io(format!("{}", err))));
+            let mut zip = try!(ZipArchive::new(BufReader::new(file)).map_err(|err| TemplateError::Archive(format!("{:?}", err))));
+            let mut entry = try!(zip.by_name(&entry).map_err(|err| TemplateError::Archive(format!("{:?}", err))));
+            try!(entry.read_to_end(&mut bytes).map_err(|err| TemplateError::Io(format!("{}", err))));
+        }
+        None => {
+            let file = try!(File::open(path).map_err(|err| TemplateError::Io(format!("{}", err))));
+            try!(BufReader::new(file).read_to_end(&mut bytes).map_err(|err| TemplateError::Io(format!("{}", err))));
+        }
+    }
+    Ok(bytes)
+}
+
+/// Splits a path through a zip archive, e.g. `assets.zip/trees/oak.tpml`, into the archive
+/// and the name of the entry. None if no `.zip` file along the path exists.
+pub fn split_archive_path(path: &Path) -> Option<(PathBuf, String)> {
+    let mut archive = PathBuf::new();
+    let mut components = path.components();
+    while let Some(component) = components.next() {
+        archive.push(component.as_os_str());
+        if archive.extension().map_or(false, |ext| ext == "zip") && archive.is_file() {
+            return Some((archive, archive_entry_name(components.as_path())));
+        }
+    }
+    None
+}
+
+/// The `/` separated name a zip archive knows an entry by, with `.` and `..` resolved.
+pub fn archive_entry_name(path: &Path) -> String {
+    let mut parts = vec![];
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => { parts.pop(); }
+            component => parts.push(component.as_os_str().to_string_lossy().into_owned())
+        }
+    }
+    parts.join("/")
+}
+
+/// Clones a template's property value for an entity, the one copy per set that
+/// `Document::set_property` taking ownership of the value makes necessary.
+fn clone_value(value: &Pon) -> Pon {
+    count_value_clone();
+    value.clone()
+}
+
+#[cfg(test)]
+thread_local!(static VALUE_CLONES: ::std::cell::Cell<usize> = ::std::cell::Cell::new(0));
+
+#[cfg(test)]
+fn count_value_clone() {
+    VALUE_CLONES.with(|clones| clones.set(clones.get() + 1));
+}
+
+#[cfg(not(test))]
+fn count_value_clone() {}
+
+/// The number of `clone_value`s on this thread since the last call.
+#[cfg(test)]
+fn take_value_clones() -> usize {
+    VALUE_CLONES.with(|clones| {
+        let count = clones.get();
+        clones.set(0);
+        count
+    })
+}
+
+fn is_blank(bytes: &[u8]) -> bool {
+    bytes.iter().all(|&b| b == b' ' || b == b'\t' || b == b'\n' || b == b'\r')
+}
+
+/// Deep merges two objects, `overlay` winning on conflicting leaves. Anything that
+/// isn't an object (or two typed objects of the same type) is replaced by `overlay`.
+pub fn merge_pon(base: &Pon, overlay: &Pon) -> Pon {
+    match (base, overlay) {
+        (&Pon::Object(ref base), &Pon::Object(ref overlay)) => {
+            let mut merged = base.clone();
+            for (k, v) in overlay {
+                let value = match base.get(k) {
+                    Some(b) => merge_pon(b, v),
+                    None => v.clone()
+                };
+                merged.insert(k.clone(), value);
+            }
+            Pon::Object(merged)
+        }
+        (&Pon::TypedPon(ref base), &Pon::TypedPon(ref overlay)) if base.type_name == overlay.type_name => {
+            Pon::TypedPon(Box::new(TypedPon {
+                type_name: overlay.type_name.clone(),
+                data: merge_pon(&base.data, &overlay.data)
+            }))
+        }
+        _ => overlay.clone()
+    }
+}
+
+/// A directory of its own for a test's files, named after the test and the process so runs
+/// side by side don't share it. The test removes it when it's done.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("pyramid_template_test_{}_{}", name, ::std::process::id()));
+    ::std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Xorshift, so the fuzz test sees the same inputs on every run.
+#[cfg(test)]
+fn next_random(state: &mut u32) -> u32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state
+}
+
+#[cfg(all(test, feature = "xml"))]
+fn parse_xml(bytes: &[u8]) {
+    let _ = parse_tpml(bytes);
+}
+
+#[cfg(all(test, not(feature = "xml")))]
+fn parse_xml(_: &[u8]) {}
+
+/// `parse_tpml_minimal` takes a `str`, so invalid UTF-8 only reaches it replaced.
+#[cfg(all(test, any(feature = "minimal-parser", not(feature = "xml"))))]
+fn parse_minimal_lossy(bytes: &[u8]) {
+    let _ = ::minimal::parse_tpml_minimal(&String::from_utf8_lossy(bytes));
+}
+
+#[cfg(all(test, feature = "xml", not(feature = "minimal-parser")))]
+fn parse_minimal_lossy(_: &[u8]) {}
+
+#[test]
+fn test_template_from_string() {
+    let str = r#"<Stone x="5"><Candle /></Stone>"#;
+    let template = Template::from_string(str).unwrap();
+    assert_eq!(template, Template {
+        type_name: "Stone".to_string(),
+        kind: TemplateKind::Entity,
+        name: None,
+        inherits: None,
+        inherit_mode: InheritMode::All,
+        extends_file: None,
+        aliases: vec![],
+        mixins: vec![],
+        mixin_order: MixinOrder::First,
+        tags: vec![],
+        inherits_tag: None,
+        version: None,
+        selector: None,
+        match_has: vec![],
+        replace: false,
+        merge: false,
+        required: vec![],
+        abstract_properties: vec![],
+        repeat: None,
+        inline: false,
+        when_flag: None,
+        property_aliases: vec![],
+        depth_conditions: vec![],
+        lazy: vec![],
+        properties: vec![("x".to_string(), Pon::Integer(5))],
+        metadata: HashMap::new(),
+        references: vec![],
+        switches: vec![],
+        parent: None,
+        children: vec![
+            Template {
+                type_name: "Candle".to_string(),
+                kind: TemplateKind::Entity,
+                name: None,
+                inherits: None,
+                inherit_mode: InheritMode::All,
+                extends_file: None,
+                aliases: vec![],
+                mixins: vec![],
+                mixin_order: MixinOrder::First,
+                tags: vec![],
+                inherits_tag: None,
+                version: None,
+                selector: None,
+                match_has: vec![],
+                replace: false,
+                merge: false,
+                required: vec![],
+                abstract_properties: vec![],
+                repeat: None,
+                inline: false,
+                when_flag: None,
+                property_aliases: vec![],
+                depth_conditions: vec![],
+                lazy: vec![],
+                properties: vec![],
+                metadata: HashMap::new(),
+                references: vec![],
+                children: vec![],
+                switches: vec![],
+                parent: None
+            }
+        ]
+    })
+}
+
+#[test]
+fn test_template_from_string_single() {
+    assert!(Template::from_string(r#"<Rock x="5" />"#).is_ok());
+    assert!(Template::from_string(r#"<Rock x="5" /><Stone />"#).is_err());
+}
+
+#[test]
+fn test_template_from_string_multi() {
+    let templates = Template::from_string_multi(r#"<Rock x="5" /><Stone><Moss /></Stone>"#).unwrap();
+    assert_eq!(templates.len(), 2);
+    assert_eq!(templates[0].type_name, "Rock".to_string());
+    assert_eq!(templates[1].type_name, "Stone".to_string());
+    assert_eq!(templates[1].children.len(), 1);
+}
+
+#[test]
+fn test_template_from_string_multi_prolog() {
+    let source = "\u{feff}<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<Rock x=\"5\" /><Stone />";
+    let templates = Template::from_string_multi(source).unwrap();
+    assert_eq!(templates.iter().map(|t| t.type_name.clone()).collect::<Vec<String>>(), vec!["Rock".to_string(), "Stone".to_string()]);
+    assert_eq!(templates[0].properties, vec![("x".to_string(), Pon::Integer(5))]);
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn test_template_from_string_multi_closing_tpml() {
+    assert!(Template::from_string_multi(r#"<Rock /></Tpml><Stone />"#).is_err());
+    let templates = Template::from_string_multi(r#"<Tpml><Rock /></Tpml><Stone />"#).unwrap();
+    assert_eq!(templates.iter().map(|t| t.type_name.clone()).collect::<Vec<String>>(), vec!["Rock".to_string(), "Stone".to_string()]);
+}
+
+#[test]
+fn test_template_from_pon() {
+    let pon = Pon::from_string("Granit { inherits: 'Rock', y: 2, children: [Moss { z: 1 }] }").unwrap();
+    let template = Template::from_pon(&pon).unwrap();
+    assert_eq!(template.type_name, "Granit".to_string());
+    assert_eq!(template.inherits, Some("Rock".to_string()));
+    assert_eq!(template.properties, vec![("y".to_string(), Pon::Integer(2))]);
+    assert_eq!(template.children.len(), 1);
+    assert_eq!(template.children[0].properties, vec![("z".to_string(), Pon::Integer(1))]);
+}
+
+#[test]
+fn test_template_pon_round_trip() {
+    let template = Template::from_string(r#"<Granit inherits="Rock" tpml:mixins="Mossy, Wet" a="1" b="[1, 2.5]" meta:category="'props'"><Moss tpml:name="moss" y="'green'"><Lichen tpml:kind="fragment" /></Moss></Granit>"#).unwrap();
+    let pon = template.to_pon();
+    assert_eq!(Template::from_pon(&pon), Ok(template));
+}
+
+#[test]
+fn test_template_metadata() {
+    let template = Template::from_string(r#"<Rock x="5" meta:category="'props'"><meta icon="'rock.png'" /></Rock>"#).unwrap();
+    assert_eq!(template.properties, vec![("x".to_string(), Pon::Integer(5))]);
+    assert_eq!(template.metadata().get("category"), Some(&Pon::String("props".to_string())));
+    assert_eq!(template.metadata().get("icon"), Some(&Pon::String("rock.png".to_string())));
+    assert!(template.is_leaf());
+
+    let mut doc = Document::from_string(r#"<Rock name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent).unwrap();
+    assert!(!doc.has_property(&ent, "category").unwrap());
+    assert!(!doc.has_property(&ent, "icon").unwrap());
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn test_template_namespace_declarations() {
+    let undeclared = r#"<Tpml><Rock x="5" tpml:kind="fragment" meta:category="'props'" /><Granit inherits="Rock" tpml:tags="hard" /></Tpml>"#;
+    let declared = format!(r#"<Tpml xmlns:tpml="{}" xmlns:meta="{}"><Rock x="5" tpml:kind="fragment" meta:category="'props'" /><Granit inherits="Rock" tpml:tags="hard" /></Tpml>"#,
+        DIRECTIVE_NAMESPACE, META_NAMESPACE);
+    let templates = parse_tpml(undeclared.as_bytes()).unwrap();
+    assert_eq!(templates[0].kind, TemplateKind::Fragment);
+    assert_eq!(templates[0].metadata().get("category"), Some(&Pon::String("props".to_string())));
+    assert_eq!(parse_tpml(declared.as_bytes()), Ok(templates));
+
+    let on_element = format!(r#"<Rock xmlns:tpml="{}" x="5" tpml:kind="fragment" />"#, DIRECTIVE_NAMESPACE);
+    assert_eq!(Template::from_string(&on_element), Template::from_string(r#"<Rock x="5" tpml:kind="fragment" />"#));
+    assert_eq!(Template::from_string(&on_element).unwrap().properties, vec![("x".to_string(), Pon::Integer(5))]);
+}
+
+#[test]
+fn test_template_tree_helpers() {
+    let template = Template::from_string(r#"<House><Room><Chair /><Table><Lamp /></Table></Room><Garden /></House>"#).unwrap();
+    assert!(!template.is_leaf());
+    assert!(template.children[1].is_leaf());
+    assert_eq!(template.depth(), 3);
+    assert_eq!(template.children[1].depth(), 0);
+    assert_eq!(template.descendant_count(), 5);
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn test_template_mismatched_end_tag() {
+    let mut template_stack = vec![Template::new("Rock".to_string())];
+    let event = XmlEvent::EndElement { name: ::xml::name::OwnedName::local("Stone") };
+    assert_eq!(Template::parse_event(&mut template_stack, event),
+        Err(TemplateError::Parse("Mismatched end tag: expected </Rock> but found </Stone>".to_string())));
+    assert!(Template::from_string(r#"<Rock></Stone>"#).is_err());
+}
+
+#[test]
+fn test_parse_tpml_file() {
+    use std::io::Write;
+
+    let dir = test_dir("parse_tpml_file");
+    let path = dir.join("templates.tpml");
+    File::create(&path).unwrap().write_all(br#"<Tpml><Rock x="5" /><Granit inherits="Rock"><Moss /></Granit></Tpml>"#).unwrap();
+
+    let templates = parse_tpml_file(&path).unwrap();
+
+    assert_eq!(templates.len(), 2);
+    assert_eq!(templates[0].type_name, "Rock".to_string());
+    assert_eq!(templates[0].properties, vec![("x".to_string(), Pon::Integer(5))]);
+    assert_eq!(templates[1].inherits, Some("Rock".to_string()));
+    assert_eq!(templates[1].children.len(), 1);
+    assert!(parse_tpml_file(Path::new("does_not_exist.tpml")).is_err());
+    ::std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn test_parse_tpml_collect() {
+    use std::io::Write;
+
+    let dir = test_dir("parse_tpml_collect");
+    let path = dir.join("templates.tpml");
+    File::create(&path).unwrap().write_all(br#"<Tpml><Rock x="5" /><Boulder tpml:kind="huge"><Moss /></Boulder><Tree y="{ a: " /><Bush><Leaf /></Bush></Tpml>"#).unwrap();
+
+    let (templates, errors) = parse_tpml_collect(&path);
+
+    assert_eq!(templates.iter().map(|t| t.type_name.clone()).collect::<Vec<String>>(), vec!["Rock".to_string(), "Bush".to_string()]);
+    assert_eq!(templates[1].children.len(), 1);
+    assert_eq!(errors.iter().map(|e| e.0).collect::<Vec<usize>>(), vec![1, 2]);
+    assert_eq!(errors[0].1, TemplateError::Parse("Unknown template kind: huge".to_string()));
+    ::std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_template_from_bytes_with_bom() {
+    let mut bytes = vec![0xEF, 0xBB, 0xBF];
+    bytes.extend(br#"<Rock x="5" />"#.iter().cloned());
+    let template = Template::from_bytes(&bytes).unwrap();
+    assert_eq!(template.type_name, "Rock".to_string());
+    assert_eq!(template.properties, vec![("x".to_string(), Pon::Integer(5))]);
+}
+
+#[test]
+fn test_template_parse_never_panics() {
+    use std::io::Write;
+
+    // Markup fragments mixed in with the random bytes, so inputs get past the first tag
+    let tokens: [&[u8]; 12] = [b"<Tpml>", b"</Tpml>", b"<Rock", b"</Rock>", b"/>", b">", b" x=\"", b"\"",
+        b" tpml:kind=\"fragment\"", b" inherits=\"Rock\"", b"[1, {", b"\xEF\xBB\xBF"];
+    let dir = test_dir("parse_never_panics");
+    let path = dir.join("fuzz.tpml");
+    let mut state: u32 = 2463534242;
+    for _ in 0..2000 {
+        let mut bytes = vec![];
+        for _ in 0..(next_random(&mut state) % 32) {
+            let r = next_random(&mut state);
+            match r % 3 {
+                0 => bytes.push((r >> 8) as u8),
+                _ => bytes.extend(tokens[(r >> 8) as usize % tokens.len()].iter().cloned())
+            }
+        }
+        // Only that they return matters, not what
+        parse_xml(&bytes);
+        let _ = Template::from_bytes(&bytes);
+        parse_minimal_lossy(&bytes);
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+        let _ = parse_tpml_file(&path);
+    }
+    ::std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_template_unknown_kind_is_error() {
+    assert!(Template::from_string(r#"<Rock tpml:kind="boulder" />"#).is_err());
+}
+
+#[test]
+fn test_template_directive_names_as_properties() {
+    let template = Template::from_string(r#"<Rock kind="'huge'" version="3" tags="['mineral']" health-alias="'hp'" mesh-lazy="true" />"#).unwrap();
+    assert_eq!(template.kind, TemplateKind::Entity);
+    assert_eq!(template.version, None);
+    assert!(template.tags.is_empty() && template.property_aliases.is_empty() && template.lazy.is_empty());
+
+    let mut doc = Document::from_string(r#"<Rock name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent).unwrap();
+    assert_eq!(doc.get_property(&ent, "kind").unwrap().concretize(), Ok(Pon::String("huge".to_string())));
+    assert_eq!(doc.get_property(&ent, "version").unwrap().concretize(), Ok(Pon::Integer(3)));
+    assert_eq!(doc.get_property(&ent, "tags").unwrap().concretize(), Ok(Pon::Array(vec![Pon::String("mineral".to_string())])));
+    assert_eq!(doc.get_property(&ent, "health-alias").unwrap().concretize(), Ok(Pon::String("hp".to_string())));
+    assert_eq!(doc.get_property(&ent, "mesh-lazy").unwrap().concretize(), Ok(Pon::Boolean(true)));
+
+    assert_eq!(Template::from_string(r#"<Rock tpml:colour="red" />"#).err(), Some(TemplateError::Parse("Unknown directive: tpml:colour".to_string())));
+}
+
+#[test]
+fn test_template_invalid_version_is_error() {
+    assert_eq!(Template::from_string(r#"<Creature tpml:version="two" />"#).err(),
+        Some(TemplateError::Parse("Invalid template version: two".to_string())));
+    assert_eq!(Template::from_string(r#"<Creature tpml:version=" 3 " />"#).unwrap().version, Some(3));
+}
+
+#[test]
+fn test_template_duplicate_attribute() {
+    let str = r#"<Rock x="5" x="7" />"#;
+    let template = Template::from_string(str).unwrap();
+    assert_eq!(template.properties, vec![("x".to_string(), Pon::Integer(7))]);
+
+    let mut warnings = vec![];
+    let templates = Template::from_string_multi_with_warnings(str, &ReaderConfig::default(), &mut warnings).unwrap();
+    assert_eq!(templates[0].properties, vec![("x".to_string(), Pon::Integer(7))]);
+    assert_eq!(warnings, vec![TemplateError::DuplicateAttribute("x".to_string(), "Rock".to_string())]);
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn test_template_strict_pragma() {
+    let strict = r#"<?tpml-pragma strict?><Tpml><Rock x="5" x="7" /></Tpml>"#;
+    assert_eq!(parse_tpml(strict.as_bytes()), Err(TemplateError::Parse("Duplicate attribute x on Rock".to_string())));
+    // Only the file with the pragma is strict
+    let lenient = r#"<Tpml><Rock x="5" x="7" /></Tpml>"#;
+    assert_eq!(parse_tpml(lenient.as_bytes()).unwrap()[0].properties, vec![("x".to_string(), Pon::Integer(7))]);
+    let unknown = r#"<?tpml-pragma sloppy?><Tpml><Rock x="5" x="7" /></Tpml>"#;
+    let mut warnings = vec![];
+    assert!(parse_tpml_with_warnings(unknown.as_bytes(), &ReaderConfig::default(), &mut warnings).is_ok());
+    assert!(warnings.contains(&TemplateError::UnknownPragma("sloppy".to_string())));
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn test_parse_tpml_with_progress() {
+    let source = r#"<Tpml><Rock x="5"><Moss /></Rock><Granit inherits="Rock" /><Marble /></Tpml>"#;
+    let mut counts = vec![];
+    let templates = parse_tpml_with_progress(source.as_bytes(), &ReaderConfig::default(), &mut |count| counts.push(count), &mut vec![]).unwrap();
+    assert_eq!(templates.len(), 3);
+    assert_eq!(counts, vec![1, 2, 3]);
+}
+
+#[cfg(feature = "xml")]
+#[test]
+fn test_template_reader_config() {
+    let source = r#"<Granit inherits=" Rock " x="5" />"#;
+    let template = Template::from_string(source).unwrap();
+    assert_eq!(template.inherits, Some(" Rock ".to_string()));
+    let config = ReaderConfig { trim_values: true, ..ReaderConfig::default() };
+    let template = Template::from_string_with_warnings(source, &config, &mut vec![]).unwrap();
+    assert_eq!(template.inherits, Some("Rock".to_string()));
+    assert_eq!(template.properties, vec![("x".to_string(), Pon::Integer(5))]);
+    assert_eq!(Template::from_bytes_with_warnings(source.as_bytes(), &config, &mut vec![]).unwrap().inherits, Some("Rock".to_string()));
+    let (templates, errors) = parse_tpml_collect_reader_with_warnings(format!("<Tpml>{}</Tpml>", source).as_bytes(), &config, &mut vec![]);
+    assert_eq!(templates[0].inherits, Some("Rock".to_string()));
+    assert!(errors.is_empty());
+    let pragma = r#"<?tpml-pragma trim-values?><Tpml><Granit inherits=" Rock " /></Tpml>"#;
+    assert_eq!(parse_tpml(pragma.as_bytes()).unwrap()[0].inherits, Some("Rock".to_string()));
+}
+
+#[test]
+fn test_template_apply() {
+    let str = r#"<Stone x="5"><Candle /></Stone>"#;
+    let template = Template::from_string(str).unwrap();
+    let mut doc = Document::from_string(r#"<Stone name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent).unwrap();
+
+    assert_eq!(doc.get_property(&ent, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(doc.get_children(&ent).unwrap().len(), 1);
+}
+
+#[test]
+fn test_template_apply_dont_overwrite() {
+    let str = r#"<Stone x="5" />"#;
+    let template = Template::from_string(str).unwrap();
+    let mut doc = Document::from_string(r#"<Stone x="7" name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent).unwrap();
+
+    assert_eq!(doc.get_property(&ent, "x").unwrap().concretize(), Ok(Pon::Integer(7)));
+}
+
+#[test]
+fn test_template_apply_replace() {
+    let str = r#"<Stone tpml:replace="true" x="5" />"#;
+    let template = Template::from_string(str).unwrap();
+    let mut doc = Document::from_string(r#"<Stone x="7" name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent).unwrap();
+
+    assert_eq!(doc.get_property(&ent, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+}
+
+#[test]
+fn test_template_merge_inherited_objects() {
+    let mut templates = HashMap::new();
+    templates.insert("Node".to_string(), Template::from_string(r#"<Node transform="{ translate: 1, rotate: 2 }"/>"#).unwrap());
+    let template = Template::from_string(r#"<Mesh inherits="Node" tpml:merge="true" transform="{ rotate: 3, scale: 4 }"/>"#).unwrap();
+    let mut doc = Document::from_string(r#"<Mesh name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    template.apply(&templates, &mut doc, &ent).unwrap();
+
+    let mut expected = HashMap::new();
+    expected.insert("translate".to_string(), Pon::Integer(1));
+    expected.insert("rotate".to_string(), Pon::Integer(3));
+    expected.insert("scale".to_string(), Pon::Integer(4));
+    assert_eq!(doc.get_property(&ent, "transform").unwrap().concretize(), Ok(Pon::Object(expected)));
+}
+
+#[test]
+fn test_template_required_properties() {
+    let template = Template::from_string(r#"<Model tpml:required="mesh, material" mesh="'box'" />"#).unwrap();
+    let mut doc = Document::from_string(r#"<Model name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    assert_eq!(template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent),
+        Err(TemplateError::MissingProperties("Model".to_string(), vec!["material".to_string()])));
+}
+
+#[test]
+fn test_template_override_named_child() {
+    let mut templates = HashMap::new();
+    templates.insert("Lamp".to_string(), Template::from_string(r#"<Lamp><Light tpml:name="main" intensity="1" color="2" /></Lamp>"#).unwrap());
+    let template = Template::from_string(r#"<BrightLamp inherits="Lamp"><Light tpml:name="main" intensity="5" /></BrightLamp>"#).unwrap();
+    let mut doc = Document::from_string(r#"<BrightLamp name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    template.apply(&templates, &mut doc, &ent).unwrap();
+
+    let children = doc.get_children(&ent).unwrap().clone();
+    assert_eq!(children.len(), 1);
+    assert_eq!(doc.get_property(&children[0], "intensity").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(doc.get_property(&children[0], "color").unwrap().concretize(), Ok(Pon::Integer(2)));
+}
+
+#[test]
+fn test_template_repeat_child() {
+    let template = Template::from_string(r#"<Fence post_count="1"><Post tpml:repeat="@post_count" /><Gate tpml:repeat="-2" /></Fence>"#).unwrap();
+    let mut doc = Document::from_string(r#"<Fence name="tmp" post_count="3" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent).unwrap();
+
+    assert_eq!(doc.get_children(&ent).unwrap().len(), 3);
+}
+
+#[test]
+fn test_template_property_alias() {
+    let template = Template::from_string(r#"<Creature health="10" tpml:health-alias="hp" />"#).unwrap();
+    let mut doc = Document::from_string(r#"<Root><Creature name="a" hp="3" /><Creature name="b" /></Root>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &a).unwrap();
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &b).unwrap();
+
+    assert_eq!(doc.get_property(&a, "health").unwrap().concretize(), Ok(Pon::Integer(3)));
+    assert_eq!(doc.get_property(&b, "health").unwrap().concretize(), Ok(Pon::Integer(10)));
+}
+
+#[test]
+fn test_template_mixins() {
+    let mut templates = HashMap::new();
+    templates.insert("Glow".to_string(), Template::from_string(r#"<Glow glow="1"><Light /></Glow>"#).unwrap());
+    templates.insert("Shadow".to_string(), Template::from_string(r#"<Shadow shadow="2" />"#).unwrap());
+    let template = Template::from_string(r#"<Lamp tpml:mixins="Glow, Shadow" shadow="3" />"#).unwrap();
+    let mut doc = Document::from_string(r#"<Lamp name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    template.apply(&templates, &mut doc, &ent).unwrap();
+
+    assert_eq!(doc.get_property(&ent, "glow").unwrap().concretize(), Ok(Pon::Integer(1)));
+    assert_eq!(doc.get_property(&ent, "shadow").unwrap().concretize(), Ok(Pon::Integer(2)));
+    assert_eq!(doc.get_children(&ent).unwrap().len(), 1);
+}
+
+#[test]
+fn test_template_precedence() {
+    let mut templates = HashMap::new();
+    templates.insert("Base".to_string(), Template::from_string(r#"<Base a="1" />"#).unwrap());
+    templates.insert("Glow".to_string(), Template::from_string(r#"<Glow a="2" b="2" />"#).unwrap());
+    templates.insert("Shadow".to_string(), Template::from_string(r#"<Shadow b="3" c="3" />"#).unwrap());
+    let value = |template: &Template, key: &str| template.flatten(&templates).into_iter().find(|p| p.key == key).map(|p| p.value);
+
+    let lamp = Template::from_string(r#"<Lamp inherits="Base" tpml:mixins="Glow, Shadow" a="4" b="4" c="4" d="4" />"#).unwrap();
+    assert_eq!(lamp.precedence(&templates), vec!["Base", "Glow", "Shadow", "Lamp"]);
+    assert_eq!(value(&lamp, "a"), Some(Pon::Integer(1)));
+    assert_eq!(value(&lamp, "b"), Some(Pon::Integer(2)));
+    assert_eq!(value(&lamp, "c"), Some(Pon::Integer(3)));
+    assert_eq!(value(&lamp, "d"), Some(Pon::Integer(4)));
+
+    let lamp = Template::from_string(r#"<Lamp inherits="Base" tpml:mixins="Glow, Shadow" tpml:mixin-order="last" a="4" b="4" />"#).unwrap();
+    assert_eq!(lamp.precedence(&templates), vec!["Base", "Lamp", "Glow", "Shadow"]);
+    assert_eq!(value(&lamp, "a"), Some(Pon::Integer(1)));
+    assert_eq!(value(&lamp, "b"), Some(Pon::Integer(4)));
+    assert_eq!(value(&lamp, "c"), Some(Pon::Integer(3)));
+}
+
+#[test]
+fn test_template_mixin_conflict() {
+    let mut templates = HashMap::new();
+    templates.insert("Glow".to_string(), Template::from_string(r#"<Glow intensity="1" />"#).unwrap());
+    templates.insert("Flicker".to_string(), Template::from_string(r#"<Flicker intensity="2" />"#).unwrap());
+    templates.insert("Steady".to_string(), Template::from_string(r#"<Steady intensity="1" />"#).unwrap());
+
+    let conflicting = Template::from_string(r#"<Lamp tpml:mixins="Glow, Flicker" />"#).unwrap();
+    assert_eq!(conflicting.check_mixins(&templates),
+        Err(TemplateError::MixinConflict("intensity".to_string(), "Glow".to_string(), "Flicker".to_string())));
+    let agreeing = Template::from_string(r#"<Lamp tpml:mixins="Glow, Steady" />"#).unwrap();
+    assert_eq!(agreeing.check_mixins(&templates), Ok(()));
+}
+
+#[test]
+fn test_template_inherits_tag() {
+    let mut templates = HashMap::new();
+    templates.insert("Health".to_string(), Template::from_string(r#"<Health tpml:tags="damageable" health="100" armor="1" />"#).unwrap());
+    templates.insert("Armor".to_string(), Template::from_string(r#"<Armor tpml:tags="damageable, heavy" armor="5" />"#).unwrap());
+    templates.insert("Paint".to_string(), Template::from_string(r#"<Paint color="1" />"#).unwrap());
+    let template = Template::from_string(r#"<Crate tpml:inherits-tag="damageable" />"#).unwrap();
+    let mut doc = Document::from_string(r#"<Crate name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    template.apply(&templates, &mut doc, &ent).unwrap();
+
+    assert_eq!(doc.get_property(&ent, "health").unwrap().concretize(), Ok(Pon::Integer(100)));
+    // Armor comes before Health by type name, so its value wins
+    assert_eq!(doc.get_property(&ent, "armor").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert!(!doc.has_property(&ent, "color").unwrap());
+}
+
+#[test]
+fn test_template_name_reference() {
+    let template = Template::from_string(r#"<Button label="@name"><Icon label="@name" /></Button>"#).unwrap();
+    let mut doc = Document::from_string(r#"<Button name="ok" />"#).unwrap();
+    let ent = doc.get_entity_by_name("ok").unwrap();
+
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent).unwrap();
+
+    assert_eq!(doc.get_property(&ent, "label").unwrap().concretize(), Ok(Pon::String("ok".to_string())));
+    // The spawned child is anonymous, so it gets no label
+    let children = doc.get_children(&ent).unwrap().clone();
+    assert!(!doc.has_property(&children[0], "label").unwrap());
+}
+
+#[test]
+fn test_template_defer_children() {
+    let template = Template::from_string(r#"<Forest><Tree><Leaf /></Tree></Forest>"#).unwrap();
+    let mut doc = Document::from_string(r#"<Forest name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let templates = HashMap::<String, Template>::new();
+    let mut context = ApplyContext::new(&templates);
+    context.defer_children = true;
+
+    template.apply_in(&mut context, &mut doc, &ent).unwrap();
+    assert_eq!(doc.get_children(&ent).unwrap().len(), 0);
+
+    let deferred = context.deferred.clone();
+    context.defer_children = false;
+    for &(ref entity_id, ref children) in &deferred {
+        Template::spawn_children(children, &mut context, &mut doc, entity_id).unwrap();
+    }
+    let children = doc.get_children(&ent).unwrap().clone();
+    assert_eq!(children.len(), 1);
+    assert_eq!(doc.get_children(&children[0]).unwrap().len(), 1);
+}
+
+#[test]
+fn test_template_switch() {
+    let template = Template::from_string(r#"<Model><switch on="detail"><case value="low"><LowDetailMesh /></case><case value="high"><HighDetailMesh /></case><default><MediumDetailMesh /></default></switch></Model>"#).unwrap();
+    assert_eq!(template.switches[0].cases.len(), 3);
+    let mut doc = Document::from_string(r#"<Root><Model name="low" detail="'low'" /><Model name="high" detail="'high'" /><Model name="other" detail="'ultra'" /></Root>"#).unwrap();
+    let templates = HashMap::<String, Template>::new();
+
+    for &(name, expected) in &[("low", "LowDetailMesh"), ("high", "HighDetailMesh"), ("other", "MediumDetailMesh")] {
+        let ent = doc.get_entity_by_name(name).unwrap();
+        template.apply(&templates, &mut doc, &ent).unwrap();
+        let children = doc.get_children(&ent).unwrap().clone();
+        assert_eq!(children.len(), 1);
+        assert_eq!(doc.get_entity_type_name(&children[0]).unwrap().clone(), expected.to_string());
+    }
+}
+
+#[test]
+fn test_template_switch_without_default() {
+    let template = Template::from_string(r#"<Model><switch on="detail"><case value="low"><LowDetailMesh /></case></switch></Model>"#).unwrap();
+    let mut doc = Document::from_string(r#"<Model name="tmp" detail="'high'" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent).unwrap();
+
+    assert_eq!(doc.get_children(&ent).unwrap().len(), 0);
+    assert!(Template::from_string(r#"<Model><case value="low" /></Model>"#).is_err());
+}
+
+#[test]
+fn test_template_contribute_to_parent() {
+    let template = Template::from_string(r#"<Lamp><parent lights="@name" lit="true" /></Lamp>"#).unwrap();
+    let mut doc = Document::from_string(r#"<Room name="room" lights="[]"><Lamp name="lamp1" /><Lamp name="lamp2" /></Room>"#).unwrap();
+    let room = doc.get_entity_by_name("room").unwrap();
+    let templates = HashMap::<String, Template>::new();
+
+    for name in &["lamp1", "lamp2"] {
+        let ent = doc.get_entity_by_name(name).unwrap();
+        template.apply(&templates, &mut doc, &ent).unwrap();
+        assert!(!doc.has_property(&ent, "lights").unwrap());
+    }
+
+    assert_eq!(doc.get_property(&room, "lights").unwrap().clone(),
+        Pon::Array(vec![Pon::String("lamp1".to_string()), Pon::String("lamp2".to_string())]));
+    assert_eq!(doc.get_property(&room, "lit").unwrap().concretize(), Ok(Pon::Boolean(true)));
+}
+
+#[test]
+fn test_template_depth_conditions() {
+    let template = Template::from_string(r#"<Node camera="true" tpml:camera-when-root="true" indent="1" tpml:indent-when-depth=">0" />"#).unwrap();
+    let mut doc = Document::from_string(r#"<Node name="root"><Node name="inner" /></Node>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let inner = doc.get_entity_by_name("inner").unwrap();
+    let templates = HashMap::<String, Template>::new();
+
+    template.apply(&templates, &mut doc, &root).unwrap();
+    template.apply(&templates, &mut doc, &inner).unwrap();
+
+    assert_eq!(doc.get_property(&root, "camera").unwrap().concretize(), Ok(Pon::Boolean(true)));
+    assert!(!doc.has_property(&root, "indent").unwrap());
+    assert!(!doc.has_property(&inner, "camera").unwrap());
+    assert_eq!(doc.get_property(&inner, "indent").unwrap().concretize(), Ok(Pon::Integer(1)));
+    assert!(Template::from_string(r#"<Node x="1" tpml:x-when-depth="> &amp;&amp;" />"#).is_err());
+    assert!(Template::from_string(r#"<Node x="1" tpml:x-when-root="maybe" />"#).is_err());
+}
+
+#[test]
+fn test_template_transactional_apply() {
+    let template = Template::from_string(r#"<Lamp lit="true"><Bulb /><Socket tpml:required="voltage" /></Lamp>"#).unwrap();
+    let templates = HashMap::<String, Template>::new();
+    let mut doc = Document::from_string(r#"<Lamp name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let mut context = ApplyContext::new(&templates);
+    context.transactional = true;
+
+    assert!(template.apply_in(&mut context, &mut doc, &ent).is_err());
+
+    // lit was set and the Bulb spawned before the Socket failed; both are undone
+    assert!(!doc.has_property(&ent, "lit").unwrap());
+    assert_eq!(doc.get_children(&ent).unwrap().len(), 0);
+    assert!(context.undo.is_none());
+    // So is what the context recorded of them
+    assert_eq!(context.stats, TemplateStats::default());
+    assert!(context.spawned.is_empty());
+}
+
+#[test]
+fn test_template_inherit_mode() {
+    let mut templates = HashMap::new();
+    templates.insert("Base".to_string(), Template::from_string(r#"<Base x="1"><Wheel /></Base>"#).unwrap());
+    templates.insert("Middle".to_string(), Template::from_string(r#"<Middle inherits="Base" y="2"><Door /></Middle>"#).unwrap());
+    for &(mode, properties, children) in &[
+        ("all", vec!["x", "y", "z"], vec!["Wheel", "Door", "Seat"]),
+        ("properties", vec!["x", "y", "z"], vec!["Seat"]),
+        ("children", vec!["z"], vec!["Wheel", "Door", "Seat"])
+    ] {
+        let template = Template::from_string(&format!(r#"<Car inherits="Middle" tpml:inherit-mode="{}" z="3"><Seat /></Car>"#, mode)).unwrap();
+        let resolved = template.resolve(&templates);
+        assert_eq!(resolved.properties.iter().map(|p| p.0.as_str()).collect::<Vec<_>>(), properties);
+        assert_eq!(resolved.children.iter().map(|c| c.type_name.as_str()).collect::<Vec<_>>(), children);
+    }
+    assert!(Template::from_string(r#"<Car tpml:inherit-mode="some" />"#).is_err());
+}
+
+#[test]
+fn test_template_empty_input() {
+    use std::io::Write;
+
+    assert_eq!(Template::from_string(""), Err(TemplateError::Empty));
+    assert_eq!(Template::from_string(" \n\t"), Err(TemplateError::Empty));
+    assert_eq!(Template::from_bytes(&[0xEF, 0xBB, 0xBF, b'\n']), Err(TemplateError::Empty));
+    assert_eq!(Template::from_string_multi("  "), Ok(vec![]));
+
+    let dir = test_dir("template_empty_input");
+    let path = dir.join("templates.tpml");
+    File::create(&path).unwrap().write_all(b" \r\n ").unwrap();
+    let mut warnings = vec![];
+    assert_eq!(parse_tpml_file_with_warnings(&path, &ReaderConfig::default(), &mut warnings), Ok(vec![]));
+    assert_eq!(warnings, vec![TemplateError::NoTemplates(path.display().to_string())]);
+    ::std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_parse_tpml_file_extends_file() {
+    use std::io::Write;
+
+    let dir = test_dir("extends_file");
+    File::create(dir.join("base.tpml")).unwrap()
+        .write_all(br#"<Tpml><Rock x="5" y="1"><Moss /></Rock><Tree /></Tpml>"#).unwrap();
+    let path = dir.join("mod.tpml");
+    File::create(&path).unwrap()
+        .write_all(br#"<Tpml><Rock tpml:extends-file="base.tpml" y="2" z="3" /></Tpml>"#).unwrap();
+
+    let templates = parse_tpml_file(&path).unwrap();
+
+    assert_eq!(templates.len(), 1);
+    let rock = &templates[0];
+    assert_eq!(rock.extends_file, Some(dir.join("base.tpml").display().to_string()));
+    // The extending file wins where both set a property
+    assert_eq!(rock.properties, vec![("x".to_string(), Pon::Integer(5)), ("y".to_string(), Pon::Integer(2)), ("z".to_string(), Pon::Integer(3))]);
+    assert_eq!(rock.children.iter().map(|c| c.type_name.as_str()).collect::<Vec<_>>(), vec!["Moss"]);
+
+    File::create(&path).unwrap()
+        .write_all(br#"<Tpml><Bush tpml:extends-file="base.tpml" /></Tpml>"#).unwrap();
+    assert!(parse_tpml_file(&path).is_err());
+    ::std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_archive_entry_name() {
+    assert_eq!(archive_entry_name(Path::new("trees/oak.tpml")), "trees/oak.tpml");
+    assert_eq!(archive_entry_name(Path::new("trees/../rocks.tpml")), "rocks.tpml");
+    assert_eq!(archive_entry_name(Path::new("./trees/./oak.tpml")), "trees/oak.tpml");
+    assert_eq!(split_archive_path(Path::new("no_such_dir/assets.zip/rocks.tpml")), None);
+}
+
+#[test]
+fn test_template_namespaced_properties() {
+    let mut templates = HashMap::new();
+    for template in Template::from_string_multi(r#"<Physics physics:mass="2" /><Render render:mass="0.5" /><Crate tpml:mixins="Physics, Render" mass="1" />"#).unwrap() {
+        templates.insert(template.type_name.clone(), template);
+    }
+    assert_eq!(templates["Physics"].properties, vec![("physics:mass".to_string(), Pon::Integer(2))]);
+    assert_eq!(templates["Crate"].check_mixins(&templates), Ok(()));
+    let mut doc = Document::from_string(r#"<Crate name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    templates["Crate"].apply(&templates, &mut doc, &ent).unwrap();
+
+    assert_eq!(doc.get_property(&ent, "physics:mass").unwrap().concretize(), Ok(Pon::Integer(2)));
+    assert_eq!(doc.get_property(&ent, "render:mass").unwrap().concretize(), Ok(Pon::Float(0.5)));
+    assert_eq!(doc.get_property(&ent, "mass").unwrap().concretize(), Ok(Pon::Integer(1)));
+}
+
+#[test]
+fn test_template_inline_children() {
+    let template = Template::from_string(r#"<Crate mass="1"><Physics tpml:inline="true" mass="5" friction="0.5"><Shape /></Physics><Label /></Crate>"#).unwrap();
+    let mut doc = Document::from_string(r#"<Crate name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent).unwrap();
+
+    assert_eq!(doc.get_property(&ent, "friction").unwrap().concretize(), Ok(Pon::Float(0.5)));
+    // The parent's own value wins over the inline child's
+    assert_eq!(doc.get_property(&ent, "mass").unwrap().concretize(), Ok(Pon::Integer(1)));
+    let children: Vec<String> = doc.get_children(&ent).unwrap().iter().map(|c| doc.get_entity_type_name(c).unwrap().clone()).collect();
+    assert_eq!(children, vec!["Shape".to_string(), "Label".to_string()]);
+}
+
+#[test]
+fn test_template_parent_reference() {
+    let template = Template::from_string(r#"<Car color="'red'"><Door color="@parent.color" /><Wheel size="@parent.wheel_size" /></Car>"#).unwrap();
+    assert_eq!(template.children[0].references, vec![("color".to_string(), Reference::Parent("color".to_string()))]);
+    let mut doc = Document::from_string(r#"<Car name="tmp" wheel_size="17" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent).unwrap();
+
+    let children = doc.get_children(&ent).unwrap().clone();
+    assert_eq!(doc.get_property(&children[0], "color").unwrap().concretize(), Ok(Pon::String("red".to_string())));
+    assert_eq!(doc.get_property(&children[1], "size").unwrap().concretize(), Ok(Pon::Integer(17)));
+    // The car is the root, so there is no parent to read from and the property is left unset
+    let tinted = Template::from_string(r#"<Car tint="@parent.color" />"#).unwrap();
+    tinted.apply(&HashMap::<String, Template>::new(), &mut doc, &ent).unwrap();
+    assert_eq!(doc.has_property(&ent, "tint"), Ok(false));
+}
+
+#[test]
+fn test_template_apply_until() {
+    let mut templates = HashMap::new();
+    for template in Template::from_string_multi(r#"<Stone hard="true" /><Rock inherits="Stone" x="1" y="1"><Moss /></Rock><Wet damp="true" /><Granit inherits="Rock" tpml:mixins="Wet" y="2" />"#).unwrap() {
+        templates.insert(template.type_name.clone(), template);
+    }
+    let mut doc = Document::from_string(r#"<Granit name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    templates["Granit"].apply_until(&templates, &mut doc, &ent, "Rock").unwrap();
+
+    assert_eq!(doc.get_property(&ent, "y").unwrap().concretize(), Ok(Pon::Integer(2)));
+    assert_eq!(doc.get_property(&ent, "damp").unwrap().concretize(), Ok(Pon::Boolean(true)));
+    assert_eq!(doc.has_property(&ent, "x"), Ok(false));
+    assert_eq!(doc.has_property(&ent, "hard"), Ok(false));
+    assert_eq!(doc.get_children(&ent).unwrap().len(), 0);
+    assert!(templates["Granit"].apply_until(&templates, &mut doc, &ent, "Granit").is_err());
+}
+
+#[test]
+fn test_template_apply_with_spawned() {
+    let template = Template::from_string(r#"<Car><Wheel tpml:repeat="2"><Bolt /></Wheel><Seat /></Car>"#).unwrap();
+    let mut doc = Document::from_string(r#"<Car name="tmp"><Radio /></Car>"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    let spawned = template.apply_with_spawned(&HashMap::<String, Template>::new(), &mut doc, &ent).unwrap();
+
+    let type_names: Vec<String> = spawned.iter().map(|e| doc.get_entity_type_name(e).unwrap().clone()).collect();
+    assert_eq!(type_names, vec!["Wheel", "Bolt", "Wheel", "Bolt", "Seat"]);
+    let children = doc.get_children(&ent).unwrap().clone();
+    assert_eq!(&children[1..], &[spawned[0], spawned[2], spawned[4]]);
+    assert_eq!(doc.get_children(&spawned[0]).unwrap(), &vec![spawned[1]]);
+}
+
+#[test]
+fn test_template_child_position() {
+    let template = Template::from_string(r#"<Shelf><Vase /><Clock /></Shelf>"#).unwrap();
+    let templates = HashMap::<String, Template>::new();
+    for &(position, expected) in &[(ChildPosition::Append, ["Book", "Vase", "Clock"]), (ChildPosition::Prepend, ["Vase", "Clock", "Book"])] {
+        let mut doc = Document::from_string(r#"<Shelf name="tmp"><Book /></Shelf>"#).unwrap();
+        let ent = doc.get_entity_by_name("tmp").unwrap();
+        let mut context = ApplyContext::new(&templates);
+        context.child_position = position;
+
+        template.apply_in(&mut context, &mut doc, &ent).unwrap();
+
+        let children = doc.get_children(&ent).unwrap().clone();
+        let types: Vec<String> = children.iter().map(|c| doc.get_entity_type_name(c).unwrap().clone()).collect();
+        assert_eq!(types, expected.iter().map(|t| t.to_string()).collect::<Vec<String>>());
+    }
+}
+
+#[test]
+fn test_template_allowed_keys() {
+    let template = Template::from_string(r#"<User name_color="1" admin="true" />"#).unwrap();
+    let mut doc = Document::from_string(r#"<User name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let templates = HashMap::<String, Template>::new();
+    let mut allowed_keys = HashSet::new();
+    allowed_keys.insert("name_color".to_string());
+    let mut context = ApplyContext::new(&templates);
+    context.allowed_keys = Some(&allowed_keys);
+
+    template.apply_in(&mut context, &mut doc, &ent).unwrap();
+
+    assert_eq!(doc.get_property(&ent, "name_color").unwrap().concretize(), Ok(Pon::Integer(1)));
+    assert!(!doc.has_property(&ent, "admin").unwrap());
+    assert_eq!(context.rejected, vec![(ent, "admin".to_string())]);
+}
+
+#[test]
+fn test_template_recursive() {
+    let template = Template::from_string(r#"<Branch><Leaf /><Branch /></Branch>"#).unwrap();
+    let mut doc = Document::from_string(r#"<Branch name="tmp" max_depth="3" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent).unwrap();
+
+    let mut levels = 0;
+    let mut current = Some(ent);
+    while let Some(branch) = current {
+        levels += 1;
+        let children = doc.get_children(&branch).unwrap().clone();
+        assert_eq!(doc.get_entity_type_name(&children[0]).unwrap().clone(), "Leaf".to_string());
+        current = children.iter().cloned().find(|c| doc.get_entity_type_name(c).unwrap().as_str() == "Branch");
+    }
+    assert_eq!(levels, 3);
+
+    let mut doc = Document::from_string(r#"<Branch name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    assert_eq!(template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent), Err(TemplateError::MissingMaxDepth("Branch".to_string())));
+    assert_eq!(doc.get_children(&ent).unwrap().len(), 0);
+}
+
+#[test]
+fn test_template_units() {
+    let template = Template::from_string(r#"<Box width="5cm" depth="2m" height="3" />"#).unwrap();
+    let mut doc = Document::from_string(r#"<Root><Box name="a" depth="'50cm'" /><Box name="b" depth="'1ft'" /></Root>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+    let templates = HashMap::<String, Template>::new();
+    let mut units = UnitTable::new();
+    units.factors.insert("cm".to_string(), 1.0);
+    units.factors.insert("m".to_string(), 100.0);
+    units.canonical.insert("width".to_string(), "m".to_string());
+    units.canonical.insert("depth".to_string(), "m".to_string());
+    let mut context = ApplyContext::new(&templates);
+    context.units = Some(&units);
+
+    template.apply_in(&mut context, &mut doc, &a).unwrap();
+
+    assert_eq!(doc.get_property(&a, "width").unwrap().concretize(), Ok(Pon::Float(0.05)));
+    assert_eq!(doc.get_property(&a, "depth").unwrap().concretize(), Ok(Pon::Float(0.5)));
+    assert_eq!(doc.get_property(&a, "height").unwrap().concretize(), Ok(Pon::Integer(3)));
+    match template.apply_in(&mut context, &mut doc, &b) {
+        Err(TemplateError::Unit(_)) => {}
+        result => panic!("Expected a unit error, got {:?}", result)
+    }
+}
+
+#[test]
+fn test_template_interceptor() {
+    let template = Template::from_string(r#"<Player speed="5" secret="1" color="2" />"#).unwrap();
+    let mut doc = Document::from_string(r#"<Player name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let templates = HashMap::<String, Template>::new();
+    let mut seen = vec![];
+    {
+        let mut interceptor = |key: &str, value: Pon| -> Option<Pon> {
+            seen.push(key.to_string());
+            match key {
+                "speed" => Some(Pon::Integer(10)),
+                "secret" => None,
+                _ => Some(value)
+            }
+        };
+        let mut context = ApplyContext::new(&templates);
+        context.interceptor = Some(&mut interceptor);
+
+        template.apply_in(&mut context, &mut doc, &ent).unwrap();
+    }
+
+    assert_eq!(seen.len(), 3);
+    assert_eq!(doc.get_property(&ent, "speed").unwrap().concretize(), Ok(Pon::Integer(10)));
+    assert!(!doc.has_property(&ent, "secret").unwrap());
+    assert_eq!(doc.get_property(&ent, "color").unwrap().concretize(), Ok(Pon::Integer(2)));
+}
+
+#[test]
+fn test_template_entity_reference() {
+    let template = Template::from_string(r#"<Light target="@entity:camera.position" />"#).unwrap();
+    let mut doc = Document::from_string(r#"<Root><Camera name="camera" position="[1, 2, 3]" /><Light name="light" /></Root>"#).unwrap();
+    let light = doc.get_entity_by_name("light").unwrap();
+
+    template.apply(&HashMap::<String, Template>::new(), &mut doc, &light).unwrap();
+
+    assert_eq!(doc.get_property(&light, "target").unwrap().concretize(),
+        Ok(Pon::Array(vec![Pon::Integer(1), Pon::Integer(2), Pon::Integer(3)])));
+    let missing = Template::from_string(r#"<Light target="@entity:tripod.position" />"#).unwrap();
+    assert_eq!(missing.apply(&HashMap::<String, Template>::new(), &mut doc, &light),
+        Err(TemplateError::UnresolvedReference("No entity named tripod".to_string())));
+}
+
+#[test]
+fn test_template_type_mapper() {
+    let template = Template::from_string(r#"<Car><Wheel /></Car>"#).unwrap();
+    let mut doc = Document::from_string(r#"<Car name="tmp" />"#).unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    let templates = HashMap::<String, Template>::new();
+    let type_mapper = |type_name: &str| if type_name == "Wheel" { "PhysicsWheel".to_string() } else { type_name.to_string() };
+    let mut context = ApplyContext::new(&templates);
+    context.type_mapper = Some(&type_mapper);
+
+    template.apply_in(&mut context, &mut doc, &ent).unwrap();
+
+    let children = doc.get_children(&ent).unwrap().clone();
+    assert_eq!(doc.get_entity_type_name(&children[0]).unwrap().clone(), "PhysicsWheel".to_string());
+}
+
+#[test]
+fn test_template_apply_to_entities() {
+    let mut templates = HashMap::new();
+    templates.insert("Rock".to_string(), Template::from_string(r#"<Rock x="5"/>"#).unwrap());
+    let template = Template::from_string(r#"<Granit inherits="Rock" y="2"/>"#).unwrap();
+    let mut doc = Document::from_string(r#"<Root><Granit name="a" /><Granit name="b" y="3" /></Root>"#).unwrap();
+    let a = doc.get_entity_by_name("a").unwrap();
+    let b = doc.get_entity_by_name("b").unwrap();
+
+    template.apply_to_entities(&templates, &mut doc, &[a, b]).unwrap();
+
+    assert_eq!(doc.get_property(&a, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(doc.get_property(&a, "y").unwrap().concretize(), Ok(Pon::Integer(2)));
+    assert_eq!(doc.get_property(&b, "x").unwrap().concretize(), Ok(Pon::Integer(5)));
+    assert_eq!(doc.get_property(&b, "y").unwrap().concretize(), Ok(Pon::Integer(3)));
+}
+
+#[test]
+fn test_template_apply_has_property_error() {
+    let template = Template::from_string(r#"<Stone x="5"><Moss /></Stone>"#).unwrap();
+    let mut doc = Document::from_string(r#"<Root name="root"><Stone name="tmp" /></Root>"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let ent = doc.get_entity_by_name("tmp").unwrap();
+    // A handle kept after its entity was removed, what `has_property` fails for
+    doc.remove_entity(&ent).unwrap();
+
+    match template.apply(&HashMap::<String, Template>::new(), &mut doc, &ent) {
+        Err(TemplateError::Document(_)) => {}
+        result => panic!("Expected a document error, got {:?}", result)
+    }
+    // The apply stopped at the first property, before any child was spawned
+    assert!(doc.get_children(&root).unwrap().is_empty());
+}
+
+#[cfg(test)]
+fn bench_fixture() -> (HashMap<String, Template>, Template) {
+    let mut templates = HashMap::new();
+    // Authoritative, so applying again to the same entities sets every value again
+    templates.insert("Rock".to_string(), Template::from_string(r#"<Rock tpml:replace="true" x="5" y="[1, 2, 3]"/>"#).unwrap());
+    let template = Template::from_string(r#"<Granit inherits="Rock" tpml:replace="true" z="2"/>"#).unwrap();
+    (templates, template)
+}
+
+#[cfg(test)]
+fn bench_document(count: usize) -> (Document, Vec<EntityId>) {
+    let mut doc = Document::from_string(r#"<Root name="root" />"#).unwrap();
+    let root = doc.get_entity_by_name("root").unwrap();
+    let entities = (0..count).map(|_| doc.append_entity(Some(root), "Granit", None).unwrap()).collect();
+    (doc, entities)
+}
+
+#[test]
+fn test_apply_to_entities_clones() {
+    let (templates, template) = bench_fixture();
+    let (mut doc, entities) = bench_document(10);
+    take_value_clones();
+
+    for entity_id in &entities[..5] {
+        template.apply(&templates, &mut doc, entity_id).unwrap();
+    }
+    // The three values are cloned to flatten the chain and again to be set, for every entity
+    assert_eq!(take_value_clones(), 5 * 3 * 2);
+
+    template.apply_to_entities(&templates, &mut doc, &entities[5..]).unwrap();
+    // Flattened once, then cloned once per set
+    assert_eq!(take_value_clones(), 3 + 5 * 3);
+    assert_eq!(doc.get_property(&entities[9], "y").unwrap().concretize(), doc.get_property(&entities[0], "y").unwrap().concretize());
+}
+
+#[bench]
+fn bench_apply_each(b: &mut ::test::Bencher) {
+    let (templates, template) = bench_fixture();
+    let (mut doc, entities) = bench_document(100);
+    b.iter(|| {
+        for entity_id in &entities {
+            template.apply(&templates, &mut doc, entity_id).unwrap();
+        }
+    });
+}
+
+#[bench]
+fn bench_apply_prepared(b: &mut ::test::Bencher) {
+    let (templates, template) = bench_fixture();
+    let prepared = template.prepare(&templates);
+    let (mut doc, entities) = bench_document(100);
+    b.iter(|| {
+        let mut context = ApplyContext::new(&templates);
+        for entity_id in &entities {
+            template.apply_prepared(&prepared, &mut context, &mut doc, entity_id).unwrap();
+        }
+    });
+}
+
+#[bench]
+fn bench_apply_to_entities(b: &mut ::test::Bencher) {
+    let (templates, template) = bench_fixture();
+    let (mut doc, entities) = bench_document(100);
+    b.iter(|| {
+        template.apply_to_entities(&templates, &mut doc, &entities).unwrap();
+    });
 }